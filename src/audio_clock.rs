@@ -0,0 +1,77 @@
+use bevy::prelude::*;
+use std::sync::atomic::{AtomicBool, AtomicU32, AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+/// オーディオのスレッドが読み出したサンプル数から求める再生位置
+///
+/// フレームの経過時間を積み上げる代わりにこの位置へ合わせることで、長い曲でも映像と音がずれない。
+/// オーディオのスレッドはバッファ単位でまとめて読み出すので、位置はバッファの長さ程度ずつ進む。
+#[derive(Resource, Clone)]
+pub struct AudioClock {
+    start_position: Duration, // 読み出しを始めた位置
+    shared: Arc<AudioClockShared>,
+}
+
+#[derive(Default)]
+struct AudioClockShared {
+    samples: AtomicU64, // 全チャンネル合計のサンプル数
+    sample_rate: AtomicU32,
+    channels: AtomicU32,
+    is_finished: AtomicBool, // オーディオの終わりまで読み出した
+}
+
+/// オーディオのスレッド側で、読み出したサンプルを数える
+pub struct AudioClockCounter {
+    shared: Arc<AudioClockShared>,
+}
+
+impl AudioClock {
+    pub fn new(start_position: Duration) -> Self {
+        Self {
+            start_position,
+            shared: Arc::new(AudioClockShared::default()),
+        }
+    }
+
+    pub fn counter(&self) -> AudioClockCounter {
+        AudioClockCounter {
+            shared: self.shared.clone(),
+        }
+    }
+
+    /// 現在の再生位置。まだ1サンプルも読み出していない場合と、読み出し終わった場合はNone
+    pub fn position(&self) -> Option<Duration> {
+        if self.shared.is_finished.load(Ordering::Relaxed) {
+            return None;
+        }
+        let samples = self.shared.samples.load(Ordering::Relaxed);
+        let sample_rate = self.shared.sample_rate.load(Ordering::Relaxed);
+        let channels = self.shared.channels.load(Ordering::Relaxed);
+        if samples == 0 || sample_rate == 0 || channels == 0 {
+            return None;
+        }
+        Some(
+            self.start_position
+                + Duration::from_secs_f64(samples as f64 / (sample_rate * channels) as f64),
+        )
+    }
+}
+
+impl AudioClockCounter {
+    /// 開始位置より後のサンプルを1つ読み出すたびに呼ぶ
+    pub fn count(&self, sample_rate: u32, channels: u16) {
+        self.shared
+            .sample_rate
+            .store(sample_rate, Ordering::Relaxed);
+        self.shared
+            .channels
+            .store(channels as u32, Ordering::Relaxed);
+        self.shared.samples.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// オーディオの終わりに達したときに呼ぶ。以降は経過時間をフレームから進める
+    pub fn finish(&self) {
+        self.shared.is_finished.store(true, Ordering::Relaxed);
+    }
+}
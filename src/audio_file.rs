@@ -0,0 +1,86 @@
+use bevy::audio::AudioSource;
+use std::fmt;
+use std::fs;
+use std::io::{self, Cursor};
+use std::sync::Arc;
+
+/// 再生できるオーディオファイルの形式
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AudioFormat {
+    Wav,
+    Flac,
+    Mp3,
+    Ogg,
+}
+
+impl fmt::Display for AudioFormat {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            AudioFormat::Wav => "WAV",
+            AudioFormat::Flac => "FLAC",
+            AudioFormat::Mp3 => "MP3",
+            AudioFormat::Ogg => "OGG",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+#[derive(Debug)]
+pub enum AudioLoadError {
+    FileNotFound(String),
+    Io(String, io::Error),
+    UnknownFormat(String),
+    Decode(String, AudioFormat, rodio::decoder::DecoderError),
+}
+
+impl fmt::Display for AudioLoadError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            AudioLoadError::FileNotFound(path) => write!(f, "audio file not found: {}", path),
+            AudioLoadError::Io(path, e) => write!(f, "failed to read audio file {}: {}", path, e),
+            AudioLoadError::UnknownFormat(path) => write!(
+                f,
+                "unsupported audio format: {} (supported: WAV, FLAC, MP3, OGG)",
+                path
+            ),
+            AudioLoadError::Decode(path, format, e) => {
+                write!(f, "failed to decode {} file {}: {}", format, path, e)
+            }
+        }
+    }
+}
+
+impl std::error::Error for AudioLoadError {}
+
+/// ファイルの先頭のバイト列から形式を判定する。拡張子は見ない
+pub fn detect_audio_format(bytes: &[u8]) -> Option<AudioFormat> {
+    match bytes {
+        [b'R', b'I', b'F', b'F', _, _, _, _, b'W', b'A', b'V', b'E', ..] => Some(AudioFormat::Wav),
+        [b'f', b'L', b'a', b'C', ..] => Some(AudioFormat::Flac),
+        [b'O', b'g', b'g', b'S', ..] => Some(AudioFormat::Ogg),
+        // ID3タグ付き、またはフレーム同期から始まるMP3
+        [b'I', b'D', b'3', ..] => Some(AudioFormat::Mp3),
+        [0xFF, b1, ..] if b1 & 0xE0 == 0xE0 => Some(AudioFormat::Mp3),
+        _ => None,
+    }
+}
+
+/// オーディオファイルを読み込み、デコードできることを確かめる
+///
+/// AssetServerに任せると、読めない形式でもエラーにならず無音のまま再生が進むので、
+/// 起動時に自前で判定してエラーを表示できるようにする。
+pub fn load_audio_file(wave_file_path: &str) -> Result<(AudioSource, AudioFormat), AudioLoadError> {
+    let bytes = fs::read(wave_file_path).map_err(|e| match e.kind() {
+        io::ErrorKind::NotFound => AudioLoadError::FileNotFound(wave_file_path.to_string()),
+        _ => AudioLoadError::Io(wave_file_path.to_string(), e),
+    })?;
+    let format = detect_audio_format(&bytes)
+        .ok_or_else(|| AudioLoadError::UnknownFormat(wave_file_path.to_string()))?;
+
+    let audio_source = AudioSource {
+        bytes: Arc::from(bytes),
+    };
+    rodio::Decoder::new(Cursor::new(audio_source.clone()))
+        .map_err(|e| AudioLoadError::Decode(wave_file_path.to_string(), format, e))?;
+    Ok((audio_source, format))
+}
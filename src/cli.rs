@@ -0,0 +1,219 @@
+use crate::global_vars::Config;
+
+pub const USAGE: &str = "\
+Usage: bevy-tutorial [OPTIONS]
+
+Options:
+  -c, --config <PATH>     config file (default: config.toml)
+  -m, --midi <PATH>       override main_config.midi_file_path
+  -a, --audio <PATH>      override main_config.wave_file_path
+      --width <PIXELS>    override main_config.window_width
+      --height <PIXELS>   override main_config.window_height
+  -s, --start <SECONDS>   start playback or offline rendering from this time
+      --mode <MODE>       interactive | render | analyze
+  -h, --help              print this help";
+
+/// 起動モード
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RunMode {
+    Interactive, // ウィンドウを開いて再生する
+    Render,      // オフラインレンダリングで書き出す
+    Analyze,     // MIDIを読み込んだ結果を標準出力に書き出して終了する
+}
+
+/// コマンドライン引数。指定されなかった項目はconfig.tomlの値を使う
+#[derive(Debug, Clone)]
+pub struct CliArgs {
+    pub config_path: String,
+    pub midi_file_path: Option<String>,
+    pub wave_file_path: Option<String>,
+    pub window_width: Option<u32>,
+    pub window_height: Option<u32>,
+    pub start_seconds: Option<f32>,
+    pub mode: Option<RunMode>,
+    pub help: bool,
+}
+
+impl Default for CliArgs {
+    fn default() -> Self {
+        Self {
+            config_path: "config.toml".to_string(),
+            midi_file_path: None,
+            wave_file_path: None,
+            window_width: None,
+            window_height: None,
+            start_seconds: None,
+            mode: None,
+            help: false,
+        }
+    }
+}
+
+/// プログラム名を除いた引数を解析する。`--name value`と`--name=value`のどちらでも書ける
+pub fn parse_args(args: impl IntoIterator<Item = String>) -> Result<CliArgs, String> {
+    let mut cli_args = CliArgs::default();
+    let mut args = args.into_iter();
+
+    while let Some(arg) = args.next() {
+        let (name, inline_value) = match arg.split_once('=') {
+            Some((name, value)) if name.starts_with("--") => {
+                (name.to_string(), Some(value.to_string()))
+            }
+            _ => (arg, None),
+        };
+        let mut value = || {
+            inline_value
+                .clone()
+                .or_else(|| args.next())
+                .ok_or(format!("missing value for {}", name))
+        };
+
+        match name.as_str() {
+            "-c" | "--config" => cli_args.config_path = value()?,
+            "-m" | "--midi" => cli_args.midi_file_path = Some(value()?),
+            "-a" | "--audio" => cli_args.wave_file_path = Some(value()?),
+            "--width" => cli_args.window_width = Some(parse_number(&name, &value()?)?),
+            "--height" => cli_args.window_height = Some(parse_number(&name, &value()?)?),
+            "-s" | "--start" => cli_args.start_seconds = Some(parse_number(&name, &value()?)?),
+            "--mode" => {
+                cli_args.mode = Some(match value()?.as_str() {
+                    "interactive" => RunMode::Interactive,
+                    "render" => RunMode::Render,
+                    "analyze" => RunMode::Analyze,
+                    mode => return Err(format!("unknown mode: {}", mode)),
+                })
+            }
+            "-h" | "--help" => cli_args.help = true,
+            _ => return Err(format!("unknown option: {}", name)),
+        }
+    }
+
+    Ok(cli_args)
+}
+
+fn parse_number<T: std::str::FromStr>(name: &str, value: &str) -> Result<T, String> {
+    value
+        .parse()
+        .map_err(|_| format!("invalid value for {}: {}", name, value))
+}
+
+impl CliArgs {
+    /// 指定された項目で設定を上書きする
+    pub fn apply(&self, config: &mut Config) {
+        if let Some(midi_file_path) = &self.midi_file_path {
+            config.main_config.midi_file_path = midi_file_path.clone();
+        }
+        if let Some(wave_file_path) = &self.wave_file_path {
+            config.main_config.wave_file_path = wave_file_path.clone();
+        }
+        if let Some(window_width) = self.window_width {
+            config.main_config.window_width = window_width;
+        }
+        if let Some(window_height) = self.window_height {
+            config.main_config.window_height = window_height;
+        }
+        if let Some(start_seconds) = self.start_seconds {
+            config.main_config.start_seconds = start_seconds;
+            config.offline_render.start_seconds = start_seconds;
+        }
+        match self.mode {
+            Some(RunMode::Interactive) => config.offline_render.enabled = false,
+            Some(RunMode::Render) => config.offline_render.enabled = true,
+            Some(RunMode::Analyze) | None => {}
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(args: &[&str]) -> Result<CliArgs, String> {
+        parse_args(args.iter().map(|x| x.to_string()))
+    }
+
+    #[test]
+    fn value_after_equals_or_space() {
+        for args in [
+            vec!["--midi=a.mid", "--width=1280", "--start=1.5"],
+            vec!["--midi", "a.mid", "--width", "1280", "--start", "1.5"],
+            vec!["-m", "a.mid", "--width", "1280", "-s", "1.5"],
+        ] {
+            let cli_args = parse(&args).unwrap();
+            assert_eq!(
+                cli_args.midi_file_path.as_deref(),
+                Some("a.mid"),
+                "{args:?}"
+            );
+            assert_eq!(cli_args.window_width, Some(1280), "{args:?}");
+            assert_eq!(cli_args.start_seconds, Some(1.5), "{args:?}");
+            assert_eq!(cli_args.config_path, "config.toml", "{args:?}");
+        }
+    }
+
+    #[test]
+    fn value_containing_equals() {
+        // 値の中の"="は区切りとして扱わない
+        let cli_args = parse(&["--audio=a=b.ogg", "-c", "x=y.toml"]).unwrap();
+        assert_eq!(cli_args.wave_file_path.as_deref(), Some("a=b.ogg"));
+        assert_eq!(cli_args.config_path, "x=y.toml");
+    }
+
+    #[test]
+    fn missing_value() {
+        assert_eq!(parse(&["--midi"]).unwrap_err(), "missing value for --midi");
+        assert_eq!(parse(&["-s"]).unwrap_err(), "missing value for -s");
+    }
+
+    #[test]
+    fn invalid_value() {
+        assert_eq!(
+            parse(&["--height=tall"]).unwrap_err(),
+            "invalid value for --height: tall"
+        );
+    }
+
+    #[test]
+    fn unknown_option() {
+        assert_eq!(
+            parse(&["--fullscreen"]).unwrap_err(),
+            "unknown option: --fullscreen"
+        );
+        assert_eq!(
+            parse(&["--fullscreen=1"]).unwrap_err(),
+            "unknown option: --fullscreen"
+        );
+        assert_eq!(
+            parse(&["song.mid"]).unwrap_err(),
+            "unknown option: song.mid"
+        );
+    }
+
+    #[test]
+    fn mode() {
+        assert_eq!(parse(&[]).unwrap().mode, None);
+        assert_eq!(
+            parse(&["--mode", "interactive"]).unwrap().mode,
+            Some(RunMode::Interactive)
+        );
+        assert_eq!(
+            parse(&["--mode=render"]).unwrap().mode,
+            Some(RunMode::Render)
+        );
+        assert_eq!(
+            parse(&["--mode", "analyze"]).unwrap().mode,
+            Some(RunMode::Analyze)
+        );
+        assert_eq!(
+            parse(&["--mode", "play"]).unwrap_err(),
+            "unknown mode: play"
+        );
+    }
+
+    #[test]
+    fn help() {
+        assert!(parse(&["-h"]).unwrap().help);
+        assert!(parse(&["--help"]).unwrap().help);
+        assert!(!parse(&[]).unwrap().help);
+    }
+}
@@ -1,29 +1,545 @@
-use crate::global_vars::Config;
+use crate::global_vars::{
+    Config, FeatureLayoutChild, NoteGroupBy, OfflineRenderOutput, RectangleFromFeatureLayoutChild,
+};
+use crate::util_color;
+use std::fmt;
 use std::fs;
 use std::io::{self};
 use std::path::Path;
 
-pub fn load_config() -> io::Result<Config> {
-    let config_path = Path::new("config.toml");
+#[derive(Debug)]
+pub enum ConfigError {
+    Io(String, io::Error),
+    Parse(String, toml::de::Error),
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ConfigError::Io(path, e) => write!(f, "failed to access config file {}: {}", path, e),
+            ConfigError::Parse(path, e) => write!(f, "failed to parse config file {}: {}", path, e),
+        }
+    }
+}
+
+impl std::error::Error for ConfigError {}
+
+pub fn load_config(config_path: &Path) -> Result<Config, ConfigError> {
+    let path_string = config_path.display().to_string();
 
     if config_path.exists() {
         // config.toml が存在する場合は読み込む
-        let content = fs::read_to_string(config_path)?;
+        let content =
+            fs::read_to_string(config_path).map_err(|e| ConfigError::Io(path_string.clone(), e))?;
         let config: Config =
-            toml::from_str(&content).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+            toml::from_str(&content).map_err(|e| ConfigError::Parse(path_string, e))?;
         Ok(config)
     } else {
         // config.toml が存在しない場合はデフォルト値でファイルを作成する
         let default_config = Config::default();
-        save_config(&default_config)?;
+        save_config(config_path, &default_config).map_err(|e| ConfigError::Io(path_string, e))?;
         Ok(default_config)
     }
 }
 
-pub fn save_config(config: &Config) -> io::Result<()> {
-    let config_path = Path::new("config.toml");
-    let toml_content =
-        toml::to_string(config).map_err(|e| io::Error::new(io::ErrorKind::Other, e))?;
+pub fn save_config(config_path: &Path, config: &Config) -> io::Result<()> {
+    let toml_content = toml::to_string(config).map_err(io::Error::other)?;
     fs::write(config_path, toml_content)?;
     Ok(())
 }
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ConfigSeverity {
+    Error,   // 起動できない
+    Warning, // 起動はできるが、意図しない表示になる可能性がある
+}
+
+/// 設定の問題点。key_pathはTOML上のキー (例: theme[0].accent_base_hex)
+#[derive(Debug, Clone)]
+pub struct ConfigProblem {
+    pub severity: ConfigSeverity,
+    pub key_path: String,
+    pub message: String,
+}
+
+impl fmt::Display for ConfigProblem {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}: {}", self.key_path, self.message)
+    }
+}
+
+#[derive(Default)]
+struct ConfigValidator {
+    problems: Vec<ConfigProblem>,
+}
+
+impl ConfigValidator {
+    fn error(&mut self, key_path: impl Into<String>, message: impl Into<String>) {
+        self.problems.push(ConfigProblem {
+            severity: ConfigSeverity::Error,
+            key_path: key_path.into(),
+            message: message.into(),
+        });
+    }
+
+    fn warning(&mut self, key_path: impl Into<String>, message: impl Into<String>) {
+        self.problems.push(ConfigProblem {
+            severity: ConfigSeverity::Warning,
+            key_path: key_path.into(),
+            message: message.into(),
+        });
+    }
+
+    fn check_positive(&mut self, key_path: &str, value: f32) {
+        if !(value.is_finite() && value > 0.0) {
+            self.error(key_path, format!("must be greater than 0, got {}", value));
+        }
+    }
+
+    fn check_non_negative(&mut self, key_path: &str, value: f32) {
+        if !(value.is_finite() && value >= 0.0) {
+            self.error(key_path, format!("must be 0 or greater, got {}", value));
+        }
+    }
+
+    fn check_range(&mut self, key_path: &str, value: f32, min: f32, max: f32) {
+        if !(min..=max).contains(&value) {
+            self.error(
+                key_path,
+                format!("must be between {} and {}, got {}", min, max, value),
+            );
+        }
+    }
+
+    fn check_hex_color(&mut self, key_path: &str, value: &str) {
+        if let Err(e) = util_color::hex_to_srgb(value) {
+            self.error(key_path, e.to_string());
+        }
+    }
+
+    fn check_channels(&mut self, key_path: &str, channels: &[u32]) {
+        for (i, channel) in channels.iter().enumerate() {
+            if !(1..=16).contains(channel) {
+                self.error(
+                    format!("{}[{}]", key_path, i),
+                    format!("channel must be between 1 and 16, got {}", channel),
+                );
+            }
+        }
+    }
+}
+
+/// 設定の問題点を全て集める。Bevyのシステムが動く前に呼び出す
+pub fn validate_config(config: &Config) -> Vec<ConfigProblem> {
+    let mut validator = ConfigValidator::default();
+
+    // main_config
+    let main_config = &config.main_config;
+    for (key, value) in [
+        ("window_width", main_config.window_width),
+        ("window_height", main_config.window_height),
+    ] {
+        if value == 0 {
+            validator.error(
+                format!("main_config.{}", key),
+                "must be greater than 0, got 0",
+            );
+        }
+    }
+    validator.check_non_negative("main_config.start_seconds", main_config.start_seconds);
+    validator.check_range(
+        "main_config.audio_offset_ms",
+        main_config.audio_offset_ms,
+        -10000.0,
+        10000.0,
+    );
+
+    // midi_loader
+    let midi_loader = &config.midi_loader;
+    validator.check_positive(
+        "midi_loader.timecode_grid_tempo",
+        midi_loader.timecode_grid_tempo,
+    );
+    if midi_loader.default_time_signature_numerator == 0 {
+        validator.error(
+            "midi_loader.default_time_signature_numerator",
+            "must be greater than 0, got 0",
+        );
+    }
+    let denominator = midi_loader.default_time_signature_denominator;
+    if !denominator.is_power_of_two() || denominator > 64 {
+        validator.error(
+            "midi_loader.default_time_signature_denominator",
+            format!("must be one of 1, 2, 4, 8, 16, 32, 64, got {}", denominator),
+        );
+    }
+    validator.check_positive(
+        "midi_loader.orphaned_note_max_length_beats",
+        midi_loader.orphaned_note_max_length_beats,
+    );
+
+    // note_group
+    match config.note_group.group_by {
+        NoteGroupBy::Channel => validator.check_channels(
+            "note_group.visible_groups",
+            &config.note_group.visible_groups,
+        ),
+        NoteGroupBy::Track => {
+            for (i, group) in config.note_group.visible_groups.iter().enumerate() {
+                if *group == 0 {
+                    validator.error(
+                        format!("note_group.visible_groups[{}]", i),
+                        "track numbers start at 1, got 0",
+                    );
+                }
+            }
+        }
+    }
+
+    // piano_roll
+    validator.check_range(
+        "piano_roll.sustain_tail_brightness",
+        config.piano_roll.sustain_tail_brightness,
+        0.0,
+        1.0,
+    );
+    if !(0.0..100.0).contains(&config.piano_roll.playhead_percent) {
+        validator.error(
+            "piano_roll.playhead_percent",
+            format!(
+                "must be 0 or greater and less than 100, got {}",
+                config.piano_roll.playhead_percent
+            ),
+        );
+    }
+    validator.check_positive("piano_roll.look_ahead", config.piano_roll.look_ahead);
+    if !(config.piano_roll.keyboard_height_percent > 0.0
+        && config.piano_roll.keyboard_height_percent < 100.0)
+    {
+        validator.error(
+            "piano_roll.keyboard_height_percent",
+            format!(
+                "must be greater than 0 and less than 100, got {}",
+                config.piano_roll.keyboard_height_percent
+            ),
+        );
+    }
+    if config.piano_roll.measures_per_page == 0 {
+        validator.error(
+            "piano_roll.measures_per_page",
+            "must be greater than 0, got 0",
+        );
+    }
+    if config.piano_roll.auto_measures_per_page && config.piano_roll.max_notes_per_page == 0 {
+        validator.error(
+            "piano_roll.max_notes_per_page",
+            "must be greater than 0, got 0",
+        );
+    }
+    if config.piano_roll.max_key > 127 {
+        validator.error(
+            "piano_roll.max_key",
+            format!("must be 127 or less, got {}", config.piano_roll.max_key),
+        );
+    }
+    if config.piano_roll.min_key > config.piano_roll.max_key {
+        validator.error(
+            "piano_roll.min_key",
+            format!(
+                "must be max_key ({}) or less, got {}",
+                config.piano_roll.max_key, config.piano_roll.min_key
+            ),
+        );
+    }
+    validator.check_non_negative(
+        "piano_roll.key_range_transition_seconds",
+        config.piano_roll.key_range_transition_seconds,
+    );
+
+    // piano_roll_grid
+    let piano_roll_grid = &config.piano_roll_grid;
+    if piano_roll_grid.subdivisions == 0 {
+        validator.error(
+            "piano_roll_grid.subdivisions",
+            "must be greater than 0, got 0",
+        );
+    }
+    for (key, value) in [
+        (
+            "measure_line_strength",
+            piano_roll_grid.measure_line_strength,
+        ),
+        ("beat_line_strength", piano_roll_grid.beat_line_strength),
+        (
+            "subdivision_line_strength",
+            piano_roll_grid.subdivision_line_strength,
+        ),
+        ("key_lane_strength", piano_roll_grid.key_lane_strength),
+    ] {
+        validator.check_range(&format!("piano_roll_grid.{}", key), value, 0.0, 1.0);
+    }
+
+    // automation_lane
+    validator.check_channels("automation_lane.channels", &config.automation_lane.channels);
+
+    // offline_render
+    let offline_render = &config.offline_render;
+    if offline_render.fps == 0 {
+        validator.error("offline_render.fps", "must be greater than 0, got 0");
+    }
+    validator.check_non_negative("offline_render.start_seconds", offline_render.start_seconds);
+    if let Some(end_seconds) = offline_render.end_seconds {
+        if end_seconds.is_nan() || end_seconds <= offline_render.start_seconds {
+            validator.error(
+                "offline_render.end_seconds",
+                format!(
+                    "must be greater than start_seconds ({}), got {}",
+                    offline_render.start_seconds, end_seconds
+                ),
+            );
+        }
+    }
+    if offline_render.output == OfflineRenderOutput::Encoder
+        && offline_render.encoder_command.is_empty()
+    {
+        validator.error(
+            "offline_render.encoder_command",
+            "must not be empty when output = \"encoder\"",
+        );
+    }
+
+    // synth
+    let synth = &config.synth;
+    validator.check_range("synth.volume", synth.volume, 0.0, 1.0);
+    if !(8000..=192000).contains(&synth.sample_rate) {
+        validator.error(
+            "synth.sample_rate",
+            format!("must be between 8000 and 192000, got {}", synth.sample_rate),
+        );
+    }
+    validator.check_non_negative("synth.attack_seconds", synth.attack_seconds);
+    validator.check_non_negative("synth.release_seconds", synth.release_seconds);
+    if !synth.enabled && config.main_config.wave_file_path.is_empty() {
+        validator.warning(
+            "main_config.wave_file_path",
+            "is empty, no audio will be played (set synth.enabled = true to use the built-in synthesizer)",
+        );
+    }
+
+    // feature_and_layout
+    validate_feature_and_layout(&mut validator, config);
+
+    // theme
+    if config.theme.is_empty() {
+        validator.error("theme", "at least one [[theme]] is required");
+    }
+    for (i, theme) in config.theme.iter().enumerate() {
+        for (key, value) in [
+            ("background_hex", &theme.background_hex),
+            ("note_channel_base_hex", &theme.note_channel_base_hex),
+            ("note_channel_target_hex", &theme.note_channel_target_hex),
+            ("main_base_hex", &theme.main_base_hex),
+            ("accent_base_hex", &theme.accent_base_hex),
+        ] {
+            validator.check_hex_color(&format!("theme[{}].{}", i, key), value);
+        }
+    }
+
+    // theme_switch
+    let theme_switch = &config.theme_switch;
+    validator.check_non_negative(
+        "theme_switch.crossfade_seconds",
+        theme_switch.crossfade_seconds,
+    );
+    if theme_switch.enabled && config.theme.len() < 2 {
+        validator.warning(
+            "theme_switch.enabled",
+            "only one [[theme]] is defined, channel 16 notes will not change the theme",
+        );
+    }
+
+    validator.problems
+}
+
+fn validate_feature_and_layout(validator: &mut ConfigValidator, config: &Config) {
+    let window_width = config.main_config.window_width;
+    let window_height = config.main_config.window_height;
+    let feature_and_layout = &config.feature_and_layout;
+    let layouts: [(&str, &FeatureLayoutChild); 5] = [
+        ("piano_roll", &feature_and_layout.piano_roll),
+        ("transport_panel", &feature_and_layout.transport_panel),
+        ("note_list", &feature_and_layout.note_list),
+        ("automation_lane", &feature_and_layout.automation_lane),
+        ("lyrics", &feature_and_layout.lyrics),
+    ];
+
+    // 重なりを調べるための、有効な領域の矩形
+    let mut rects: Vec<(&str, RectangleFromFeatureLayoutChild)> = Vec::new();
+
+    for (name, layout) in layouts {
+        let key_path = format!("feature_and_layout.{}", name);
+        if !layout.enabled {
+            continue;
+        }
+
+        let mut is_in_range = true;
+        for (key, value) in [
+            ("left_percent", layout.left_percent),
+            ("top_percent", layout.top_percent),
+            ("right_percent", layout.right_percent),
+            ("bottom_percent", layout.bottom_percent),
+        ] {
+            if !(0.0..=100.0).contains(&value) {
+                validator.error(
+                    format!("{}.{}", key_path, key),
+                    format!("must be between 0 and 100, got {}", value),
+                );
+                is_in_range = false;
+            }
+        }
+        if !is_in_range || window_width == 0 || window_height == 0 {
+            continue;
+        }
+
+        let Some(rect) = layout.calculate_rect(window_width, window_height) else {
+            continue;
+        };
+        if rect.width_pixel <= 0.0 || rect.height_pixel <= 0.0 {
+            validator.error(
+                key_path,
+                format!(
+                    "extends past the window ({}x{} pixels at {}x{})",
+                    rect.width_pixel, rect.height_pixel, window_width, window_height
+                ),
+            );
+            continue;
+        }
+        rects.push((name, rect));
+    }
+
+    // 重なっていても描画はできるので、警告にとどめる
+    for (i, (name_a, a)) in rects.iter().enumerate() {
+        for (name_b, b) in &rects[i + 1..] {
+            let (a_left, a_bottom) = a.left_bottom_abs_pixel;
+            let (a_right, a_top) = a.right_top_abs_pixel;
+            let (b_left, b_bottom) = b.left_bottom_abs_pixel;
+            let (b_right, b_top) = b.right_top_abs_pixel;
+            if a_left < b_right && b_left < a_right && a_bottom < b_top && b_bottom < a_top {
+                validator.warning(
+                    format!("feature_and_layout.{}", name_b),
+                    format!("overlaps feature_and_layout.{}", name_a),
+                );
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn problems(config: &Config) -> Vec<(ConfigSeverity, String)> {
+        validate_config(config)
+            .iter()
+            .map(|x| (x.severity, x.to_string()))
+            .collect()
+    }
+
+    #[test]
+    fn default_config() {
+        let problems = problems(&Config::default());
+        assert!(
+            problems.iter().all(|x| x.0 != ConfigSeverity::Error),
+            "{problems:?}"
+        );
+    }
+
+    #[test]
+    fn invalid_hex_color() {
+        let mut config = Config::default();
+        config.theme[0].accent_base_hex = "#81a1c".to_string();
+        config.theme[0].background_hex = "#2e344g".to_string();
+
+        let problems = problems(&config);
+        assert!(problems.contains(&(
+            ConfigSeverity::Error,
+            "theme[0].accent_base_hex: invalid length 5".to_string()
+        )));
+        assert!(problems.contains(&(
+            ConfigSeverity::Error,
+            "theme[0].background_hex: invalid hex digit 'g'".to_string()
+        )));
+    }
+
+    #[test]
+    fn report_every_problem() {
+        let mut config = Config::default();
+        config.main_config.window_width = 0;
+        config.offline_render.fps = 0;
+        config.midi_loader.default_time_signature_denominator = 3;
+        config.theme.clear();
+
+        let problems = problems(&config);
+        for expected in [
+            "main_config.window_width: must be greater than 0, got 0",
+            "offline_render.fps: must be greater than 0, got 0",
+            "midi_loader.default_time_signature_denominator: must be one of 1, 2, 4, 8, 16, 32, 64, got 3",
+            "theme: at least one [[theme]] is required",
+        ] {
+            assert!(
+                problems.contains(&(ConfigSeverity::Error, expected.to_string())),
+                "{expected}: {problems:?}"
+            );
+        }
+    }
+
+    #[test]
+    fn disabled_features() {
+        // 無効な要素は、領域の値が範囲外でも検証しない
+        let mut config = Config::default();
+        let feature_and_layout = &mut config.feature_and_layout;
+        feature_and_layout.piano_roll.enabled = false;
+        feature_and_layout.transport_panel.enabled = false;
+        feature_and_layout.transport_panel.left_percent = 120.0;
+
+        let problems = problems(&config);
+        assert!(
+            problems.iter().all(|x| x.0 != ConfigSeverity::Error),
+            "{problems:?}"
+        );
+    }
+
+    #[test]
+    fn layout_out_of_range() {
+        let mut config = Config::default();
+        config.feature_and_layout.piano_roll.left_percent = 120.0;
+
+        assert!(problems(&config).contains(&(
+            ConfigSeverity::Error,
+            "feature_and_layout.piano_roll.left_percent: must be between 0 and 100, got 120"
+                .to_string()
+        )));
+    }
+
+    #[test]
+    fn overlapping_layouts() {
+        let mut config = Config::default();
+        config.feature_and_layout.lyrics = FeatureLayoutChild {
+            enabled: true,
+            left_percent: 5.0,
+            top_percent: 5.0,
+            right_percent: 5.0,
+            bottom_percent: 80.0,
+        };
+        assert!(problems(&config).contains(&(
+            ConfigSeverity::Warning,
+            "feature_and_layout.lyrics: overlaps feature_and_layout.piano_roll".to_string()
+        )));
+
+        // READMEの例のように、ピアノロールの上端を下げれば重ならない
+        config.feature_and_layout.piano_roll.top_percent = 25.0;
+        assert!(!problems(&config)
+            .iter()
+            .any(|x| x.1.starts_with("feature_and_layout.lyrics")));
+    }
+}
@@ -65,7 +65,7 @@ impl CubicBezier {
 
             t -= delta / derivative;
 
-            if t < 0.0 || t > 1.0 {
+            if !(0.0..=1.0).contains(&t) {
                 return None;
             }
         }
@@ -1,3 +1,5 @@
+use crate::tempo_map::TempoMap;
+use crate::util_color;
 use bevy::prelude::*;
 use bevy::time::Stopwatch;
 use serde::{Deserialize, Serialize};
@@ -6,6 +8,22 @@ use serde::{Deserialize, Serialize};
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct Config {
     pub main_config: MainConfig,
+    #[serde(default)]
+    pub midi_loader: MidiLoaderConfig,
+    #[serde(default)]
+    pub note_group: NoteGroupConfig,
+    #[serde(default)]
+    pub piano_roll: PianoRollConfig,
+    #[serde(default)]
+    pub piano_roll_grid: PianoRollGridConfig,
+    #[serde(default)]
+    pub automation_lane: AutomationLaneConfig,
+    #[serde(default)]
+    pub offline_render: OfflineRenderConfig,
+    #[serde(default)]
+    pub synth: SynthConfig,
+    #[serde(default)]
+    pub theme_switch: ThemeSwitchConfig,
     pub feature_and_layout: FeatureLayoutRoot,
     pub theme: Vec<Theme>,
 }
@@ -13,9 +31,417 @@ pub struct Config {
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct MainConfig {
     pub midi_file_path: String,
+    // synth.enabledがtrueの場合は使わないので、省略できる
+    #[serde(default)]
     pub wave_file_path: String,
     pub window_height: u32,
     pub window_width: u32,
+    // 再生を開始する位置(秒)
+    #[serde(default)]
+    pub start_seconds: f32,
+    // オーディオが映像より遅れて聞こえる時間(ms)。出力の遅延の分だけ映像を遅らせる
+    #[serde(default)]
+    pub audio_offset_ms: f32,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(default)]
+pub struct MidiLoaderConfig {
+    // タイムコード(SMPTE)形式のMIDIで、小節・拍のグリッドを作るための名目上のテンポ
+    pub timecode_grid_tempo: f32,
+    // 拍子イベントが無いときに使う拍子
+    pub default_time_signature_numerator: u8,
+    pub default_time_signature_denominator: u8,
+    // NoteOffが無いノートの扱い
+    pub orphaned_note_policy: OrphanedNotePolicy,
+    // orphaned_note_policy = "max_length" のときのノートの長さ(四分音符単位)
+    pub orphaned_note_max_length_beats: f32,
+    // 同じキーのノートが重なったときの、NoteOffとの対応の付け方
+    pub overlapping_note_policy: OverlappingNotePolicy,
+    // サステインペダル(CC64)による余韻を計算する
+    pub sustain_pedal_enabled: bool,
+    // ソステヌートペダル(CC66)による余韻を計算する
+    pub sostenuto_pedal_enabled: bool,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum OrphanedNotePolicy {
+    EndOfTrack, // トラックの終端で閉じる
+    NextNoteOn, // 同じキーの次のNoteOnで閉じる
+    MaxLength,  // 一定の長さで閉じる
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum OverlappingNotePolicy {
+    Fifo,      // 最も古いノートを閉じる
+    Lifo,      // 最も新しいノートを閉じる
+    Retrigger, // 再度のNoteOnで前のノートを閉じる
+}
+
+impl Default for MidiLoaderConfig {
+    fn default() -> Self {
+        Self {
+            timecode_grid_tempo: 120.0,
+            default_time_signature_numerator: 4,
+            default_time_signature_denominator: 4,
+            orphaned_note_policy: OrphanedNotePolicy::EndOfTrack,
+            orphaned_note_max_length_beats: 4.0,
+            overlapping_note_policy: OverlappingNotePolicy::Lifo,
+            sustain_pedal_enabled: true,
+            sostenuto_pedal_enabled: false,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+#[serde(default)]
+pub struct NoteGroupConfig {
+    // ノートの色分け・表示の単位
+    pub group_by: NoteGroupBy,
+    // 表示するグループの番号(1始まり)。空の場合は全て表示する
+    pub visible_groups: Vec<u32>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum NoteGroupBy {
+    #[default]
+    Channel,
+    Track,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(default)]
+pub struct PianoRollConfig {
+    // ペダルによる余韻を、ノートの後ろに暗い色で描画する
+    pub sustain_tail_enabled: bool,
+    // 余韻の明るさ。0.0で背景色、1.0でノートと同じ色
+    pub sustain_tail_brightness: f32,
+    pub view_mode: PianoRollViewMode,
+    // view_mode = "scroll" のときの、左端からの再生ヘッドの位置(%)
+    pub playhead_percent: f32,
+    // view_mode = "scroll"・"falling" のときに、再生ヘッドより先に表示する長さ
+    pub look_ahead: f32,
+    pub look_ahead_unit: LookAheadUnit,
+    // view_mode = "falling" のときの、ピアノロールの高さに対する鍵盤の高さ(%)
+    pub keyboard_height_percent: f32,
+    // view_mode = "page" のときに、1ページに表示する小節の数。自動で決める場合はその上限
+    pub measures_per_page: u32,
+    // ノートの密度に合わせて、1ページの小節の数を自動で決める
+    pub auto_measures_per_page: bool,
+    // 自動で決める場合の、1ページに収めるノートの数の目安
+    pub max_notes_per_page: u32,
+    pub key_range_mode: KeyRangeMode,
+    // key_range_mode = "fixed" のときに表示する、最低音と最高音のkey
+    pub min_key: u32,
+    pub max_key: u32,
+    // key_range_mode = "auto"・"auto_per_page" のときに、ノートの上下に空けるkeyの数
+    pub key_range_padding: u32,
+    // key_range_mode = "auto_per_page" のときに、表示する範囲が移り変わる秒数
+    pub key_range_transition_seconds: f32,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum PianoRollViewMode {
+    Page,    // 現在のページの小節を横幅いっぱいに表示し、ページが変わると切り替える
+    Scroll,  // 固定した再生ヘッドの前を、ノートが右から左へ流れる
+    Falling, // 下端の鍵盤に向かって、ノートが上から落ちてくる
+}
+
+/// ピアノロールに表示する最低音と最高音。88鍵のピアノと同じ範囲
+pub const PIANO_MIN_KEY: u32 = 21;
+pub const PIANO_MAX_KEY: u32 = 108;
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum KeyRangeMode {
+    Piano,       // 88鍵のピアノと同じ範囲に固定する
+    Fixed,       // min_key から max_key までに固定する
+    Auto,        // 曲全体のノートが収まる範囲に合わせる
+    AutoPerPage, // view_mode = "page" のときに、ページごとのノートに合わせる。それ以外ではautoと同じ
+}
+
+/// ピアノロールの縦方向(落下表示では横方向)に表示する、keyの範囲。両端を含む
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct KeyRange {
+    pub min_key: u32,
+    pub max_key: u32,
+}
+
+impl KeyRange {
+    pub const PIANO: KeyRange = KeyRange {
+        min_key: PIANO_MIN_KEY,
+        max_key: PIANO_MAX_KEY,
+    };
+
+    /// ノートが収まる範囲に、上下の余白を足す。ノートが無い場合はNone
+    pub fn fit<'a>(midi_notes: impl Iterator<Item = &'a MidiNote>, padding: u32) -> Option<Self> {
+        let (min_key, max_key) = midi_notes.fold(None, |acc: Option<(u32, u32)>, midi_note| {
+            Some(match acc {
+                Some((min_key, max_key)) => {
+                    (min_key.min(midi_note.key), max_key.max(midi_note.key))
+                }
+                None => (midi_note.key, midi_note.key),
+            })
+        })?;
+        Some(Self {
+            min_key: min_key.saturating_sub(padding),
+            max_key: (max_key + padding).min(127),
+        })
+    }
+
+    pub fn key_count(&self) -> u32 {
+        self.max_key - self.min_key + 1
+    }
+
+    pub fn contains(&self, key: u32) -> bool {
+        (self.min_key..=self.max_key).contains(&key)
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum LookAheadUnit {
+    Beats,   // 四分音符単位。tickに沿って流れるので、テンポが変わると流れる速さも変わる
+    Seconds, // 秒単位。流れる速さは一定
+}
+
+impl Default for PianoRollConfig {
+    fn default() -> Self {
+        Self {
+            sustain_tail_enabled: true,
+            sustain_tail_brightness: 0.35,
+            view_mode: PianoRollViewMode::Page,
+            playhead_percent: 25.0,
+            look_ahead: 8.0,
+            look_ahead_unit: LookAheadUnit::Beats,
+            keyboard_height_percent: 15.0,
+            measures_per_page: 1,
+            auto_measures_per_page: false,
+            max_notes_per_page: 64,
+            key_range_mode: KeyRangeMode::Piano,
+            min_key: PIANO_MIN_KEY,
+            max_key: PIANO_MAX_KEY,
+            key_range_padding: 2,
+            key_range_transition_seconds: 0.3,
+        }
+    }
+}
+
+/// ノートの奥に描画する、拍や小節の区切り線とkeyのレーン
+///
+/// 色はテーマのaccent_base_hexをbackground_hexに混ぜて作り、strengthが大きいほどaccentに近づく。
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(default)]
+pub struct PianoRollGridConfig {
+    pub measure_lines: bool,
+    pub beat_lines: bool,
+    // 1拍を分割する数。2以上の場合は、beat_linesと一緒に分割した位置にも線を描画する
+    pub subdivisions: u32,
+    pub key_lanes: KeyLaneMode,
+    pub measure_line_strength: f32,
+    pub beat_line_strength: f32,
+    pub subdivision_line_strength: f32,
+    pub key_lane_strength: f32,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum KeyLaneMode {
+    None,
+    BlackKeys, // 黒鍵のレーンに色を付ける
+    Octaves,   // 各オクターブのCのレーンに色を付ける
+}
+
+impl Default for PianoRollGridConfig {
+    fn default() -> Self {
+        Self {
+            measure_lines: true,
+            beat_lines: false,
+            subdivisions: 1,
+            key_lanes: KeyLaneMode::None,
+            measure_line_strength: 0.5,
+            beat_line_strength: 0.25,
+            subdivision_line_strength: 0.12,
+            key_lane_strength: 0.08,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(default)]
+pub struct AutomationLaneConfig {
+    // 描画するレーン。上から順に並ぶ
+    pub lanes: Vec<AutomationLaneKind>,
+    // 描画するチャンネル(1始まり)。同じレーンに重ねて描画する
+    pub channels: Vec<u32>,
+}
+
+impl Default for AutomationLaneConfig {
+    fn default() -> Self {
+        Self {
+            lanes: vec![
+                AutomationLaneKind::ControlChange(1),
+                AutomationLaneKind::ControlChange(11),
+            ],
+            channels: vec![1],
+        }
+    }
+}
+
+/// config.tomlでは "cc1", "pitch_bend", "channel_aftertouch", "program_change" のように書く
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(try_from = "String", into = "String")]
+pub enum AutomationLaneKind {
+    ControlChange(u8),
+    PitchBend,
+    ChannelAftertouch,
+    ProgramChange,
+}
+
+impl TryFrom<String> for AutomationLaneKind {
+    type Error = String;
+
+    fn try_from(value: String) -> Result<Self, Self::Error> {
+        match value.as_str() {
+            "pitch_bend" => Ok(Self::PitchBend),
+            "channel_aftertouch" => Ok(Self::ChannelAftertouch),
+            "program_change" => Ok(Self::ProgramChange),
+            _ => value
+                .strip_prefix("cc")
+                .and_then(|x| x.parse::<u8>().ok())
+                .filter(|x| *x < 128)
+                .map(Self::ControlChange)
+                .ok_or(format!("unknown automation lane: {}", value)),
+        }
+    }
+}
+
+impl From<AutomationLaneKind> for String {
+    fn from(value: AutomationLaneKind) -> Self {
+        match value {
+            AutomationLaneKind::ControlChange(controller) => format!("cc{}", controller),
+            AutomationLaneKind::PitchBend => "pitch_bend".to_string(),
+            AutomationLaneKind::ChannelAftertouch => "channel_aftertouch".to_string(),
+            AutomationLaneKind::ProgramChange => "program_change".to_string(),
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(default)]
+pub struct OfflineRenderConfig {
+    // trueの場合、ウィンドウを開かずに1フレームずつ描画して書き出す
+    pub enabled: bool,
+    // 書き出すフレームレート。1フレームごとに1/fps秒ずつ時間を進める
+    pub fps: u32,
+    // 書き出す範囲(秒)。end_secondsを省略した場合は曲の終わりまで
+    pub start_seconds: f32,
+    pub end_seconds: Option<f32>,
+    // 書き出し先
+    pub output: OfflineRenderOutput,
+    // output = "png_sequence" のときの書き出し先のフォルダ
+    pub output_directory: String,
+    // output = "encoder" のときに起動するコマンド。標準入力にRGBAの生データを渡す
+    // {width}, {height}, {fps} は実際の値に置き換えられる
+    pub encoder_command: Vec<String>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum OfflineRenderOutput {
+    PngSequence, // 連番のPNGファイル
+    Encoder,     // 外部のエンコーダーにパイプで渡す
+}
+
+impl Default for OfflineRenderConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            fps: 60,
+            start_seconds: 0.0,
+            end_seconds: None,
+            output: OfflineRenderOutput::PngSequence,
+            output_directory: "render".to_string(),
+            encoder_command: [
+                "ffmpeg",
+                "-y",
+                "-f",
+                "rawvideo",
+                "-pix_fmt",
+                "rgba",
+                "-s",
+                "{width}x{height}",
+                "-r",
+                "{fps}",
+                "-i",
+                "-",
+                "-pix_fmt",
+                "yuv420p",
+                "render.mp4",
+            ]
+            .iter()
+            .map(|x| x.to_string())
+            .collect(),
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(default)]
+pub struct SynthConfig {
+    // trueの場合、wave_file_pathの代わりに読み込んだMIDIを内蔵のシンセサイザーで鳴らす
+    pub enabled: bool,
+    pub waveform: SynthWaveform,
+    // 全体の音量。0.0～1.0
+    pub volume: f32,
+    pub sample_rate: u32,
+    // ノートの立ち上がりと、離してから消えるまでの時間(秒)
+    pub attack_seconds: f32,
+    pub release_seconds: f32,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum SynthWaveform {
+    Sine,
+    Triangle,
+    Square,
+    Sawtooth,
+}
+
+impl Default for SynthConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            waveform: SynthWaveform::Triangle,
+            volume: 0.3,
+            sample_rate: 44100,
+            attack_seconds: 0.005,
+            release_seconds: 0.15,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(default)]
+pub struct ThemeSwitchConfig {
+    // trueの場合、Ch.16のノートをテーマの切り替えに使い、ピアノロールなどには表示しない
+    pub enabled: bool,
+    // 前のテーマの色から次のテーマの色へ移り変わる時間(秒)。0.0の場合はすぐに切り替える
+    pub crossfade_seconds: f32,
+}
+
+impl Default for ThemeSwitchConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            crossfade_seconds: 0.0,
+        }
+    }
 }
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
@@ -23,6 +449,10 @@ pub struct FeatureLayoutRoot {
     pub piano_roll: FeatureLayoutChild,
     pub transport_panel: FeatureLayoutChild,
     pub note_list: FeatureLayoutChild,
+    #[serde(default = "FeatureLayoutChild::disabled")]
+    pub automation_lane: FeatureLayoutChild,
+    #[serde(default = "FeatureLayoutChild::disabled")]
+    pub lyrics: FeatureLayoutChild,
 }
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
@@ -36,15 +466,24 @@ pub struct FeatureLayoutChild {
 
 #[derive(Debug, Clone, Component)]
 pub struct RectangleFromFeatureLayoutChild {
-    pub left_top_abs_pixel: (f32, f32),
     pub right_top_abs_pixel: (f32, f32),
-    pub right_bottom_abs_pixel: (f32, f32),
     pub left_bottom_abs_pixel: (f32, f32),
     pub width_pixel: f32,
     pub height_pixel: f32,
 }
 
 impl FeatureLayoutChild {
+    /// 設定ファイルに書かれていない機能のデフォルト値
+    pub fn disabled() -> Self {
+        Self {
+            enabled: false,
+            left_percent: 5.0,
+            top_percent: 80.0,
+            right_percent: 5.0,
+            bottom_percent: 5.0,
+        }
+    }
+
     /// ピクセル座標系の矩形情報を計算するメソッド
     pub fn calculate_rect(
         &self,
@@ -78,9 +517,7 @@ impl FeatureLayoutChild {
         let height = top - bottom;
 
         Some(RectangleFromFeatureLayoutChild {
-            left_top_abs_pixel: (left, top),
             right_top_abs_pixel: (right, top),
-            right_bottom_abs_pixel: (right, bottom),
             left_bottom_abs_pixel: (left, bottom),
             width_pixel: width,
             height_pixel: height,
@@ -105,7 +542,17 @@ impl Default for Config {
                 wave_file_path: "C:\\Windows\\Media\\onestop.wav".to_string(),
                 window_height: 1080,
                 window_width: 1920,
+                start_seconds: 0.0,
+                audio_offset_ms: 0.0,
             },
+            midi_loader: MidiLoaderConfig::default(),
+            note_group: NoteGroupConfig::default(),
+            piano_roll: PianoRollConfig::default(),
+            piano_roll_grid: PianoRollGridConfig::default(),
+            automation_lane: AutomationLaneConfig::default(),
+            offline_render: OfflineRenderConfig::default(),
+            synth: SynthConfig::default(),
+            theme_switch: ThemeSwitchConfig::default(),
             feature_and_layout: FeatureLayoutRoot {
                 piano_roll: FeatureLayoutChild {
                     enabled: true,
@@ -128,6 +575,8 @@ impl Default for Config {
                     right_percent: 5.0,
                     bottom_percent: 5.0,
                 },
+                automation_lane: FeatureLayoutChild::disabled(),
+                lyrics: FeatureLayoutChild::disabled(),
             },
             theme: vec![Theme {
                 background_hex: "#2e3440".to_string(),
@@ -148,8 +597,69 @@ pub struct GlobalSettings {
     pub config: Config,
     pub format: midly::Format,
     pub ppm: u16,
-    pub time_axis_vec: Vec<TimeAxis>,
-    pub midi_notes_vec: Vec<Vec<MidiNote>>,
+    pub smpte_fps: Option<midly::Fps>, // タイムコード形式のときのみSome
+    pub tempo_map: TempoMap,
+    pub midi_notes_vec: Vec<Vec<MidiNote>>, // 小節を跨ぐノートは小節ごとに分割されている
+    pub unsplit_midi_notes_vec: Vec<Vec<MidiNote>>, // 小節で分割する前のノート
+    pub track_names: Vec<String>,           // トラック番号順。TrackNameが無いトラックは空文字
+    pub midi_control_lanes_vec: Vec<MidiControlLanes>, // channel
+    pub midi_text_events: Vec<MidiTextEvent>, // tickの昇順
+    pub theme_change_events: Vec<ThemeChangeEvent>, // tickの昇順。theme_switch.enabledがfalseの場合は空
+}
+
+impl GlobalSettings {
+    /// 設定に応じたノートのグループ番号(0始まり)
+    pub fn note_group_index(&self, midi_note: &MidiNote) -> u32 {
+        match self.config.note_group.group_by {
+            NoteGroupBy::Channel => midi_note.channel,
+            NoteGroupBy::Track => midi_note.track,
+        }
+    }
+
+    pub fn note_group_count(&self) -> u32 {
+        match self.config.note_group.group_by {
+            NoteGroupBy::Channel => self.midi_notes_vec.len() as u32,
+            NoteGroupBy::Track => self.track_names.len() as u32,
+        }
+    }
+
+    pub fn is_note_group_visible(&self, group_index: u32) -> bool {
+        let visible_groups = &self.config.note_group.visible_groups;
+        visible_groups.is_empty() || visible_groups.contains(&(group_index + 1))
+    }
+
+    /// 設定に応じて、曲全体で表示するkeyの範囲
+    pub fn key_range(&self) -> KeyRange {
+        let piano_roll_config = &self.config.piano_roll;
+        match piano_roll_config.key_range_mode {
+            KeyRangeMode::Piano => KeyRange::PIANO,
+            KeyRangeMode::Fixed => KeyRange {
+                min_key: piano_roll_config.min_key,
+                max_key: piano_roll_config.max_key,
+            },
+            KeyRangeMode::Auto | KeyRangeMode::AutoPerPage => KeyRange::fit(
+                self.midi_notes_vec
+                    .iter()
+                    .flatten()
+                    .filter(|x| self.is_note_group_visible(self.note_group_index(x))),
+                piano_roll_config.key_range_padding,
+            )
+            .unwrap_or(KeyRange::PIANO),
+        }
+    }
+
+    /// 画面に表示するグループ名
+    pub fn note_group_name(&self, group_index: u32) -> String {
+        match self.config.note_group.group_by {
+            NoteGroupBy::Channel => format!("ch{}", group_index + 1),
+            NoteGroupBy::Track => match self.track_names.get(group_index as usize) {
+                Some(track_name) if !track_name.is_empty() => {
+                    format!("tr{} {}", group_index + 1, track_name)
+                }
+                _ => format!("tr{}", group_index + 1),
+            },
+        }
+    }
 }
 
 #[derive(Resource)]
@@ -158,6 +668,14 @@ pub struct GlobalMonitorValues {
     pub elapsed_time_from_start: Stopwatch,
     pub current_time_axis: TimeAxis,
 }
+
+/// MIDIの読み込みに失敗したときだけ存在するリソース
+#[derive(Resource)]
+pub struct MidiLoadErrorMessage(pub String);
+
+/// MIDIの読み込みに失敗したときに、ウィンドウに表示するエラー
+#[derive(Component)]
+pub struct MidiLoadErrorText;
 // ==================== Bevy Global Resource ====================
 
 #[derive(Clone, Copy, Debug)]
@@ -173,8 +691,13 @@ pub struct TimeAxis {
     pub tempo: f32,                  // テンポ
     pub time_signature_numerator: u8,
     pub time_signature_denominator: u8,
+    // MIDIの拍子イベントの値をそのまま持つ。メトロノームの機能は無いので、今は読んでいない
+    #[allow(dead_code)]
     pub time_signature_midi_clocks_per_metronome_click: u8,
+    #[allow(dead_code)]
     pub time_signature_thirty_seconds_notes_per_quarter_note: u8,
+    pub key_signature_sharps: i8, // 調号のシャープの数。負の値はフラットの数
+    pub key_signature_minor: bool,
 }
 
 impl Default for TimeAxis {
@@ -193,6 +716,40 @@ impl Default for TimeAxis {
             time_signature_denominator: 4,
             time_signature_midi_clocks_per_metronome_click: 24,
             time_signature_thirty_seconds_notes_per_quarter_note: 8,
+            key_signature_sharps: 0,
+            key_signature_minor: false,
+        }
+    }
+}
+
+impl TimeAxis {
+    /// このTimeAxis時点の調での音名。フラット系の調ではフラットを使う
+    pub fn key_name(&self, key: u32) -> &'static str {
+        const SHARP_NAMES: [&str; 12] = [
+            "C", "C#", "D", "D#", "E", "F", "F#", "G", "G#", "A", "A#", "B",
+        ];
+        const FLAT_NAMES: [&str; 12] = [
+            "C", "Db", "D", "Eb", "E", "F", "Gb", "G", "Ab", "A", "Bb", "B",
+        ];
+        match self.key_signature_sharps < 0 {
+            true => FLAT_NAMES[key as usize % 12],
+            false => SHARP_NAMES[key as usize % 12],
+        }
+    }
+
+    /// 調の名前 (例: "Eb major", "F# minor")
+    pub fn key_signature_name(&self) -> String {
+        // 五度圏の順に、フラット7つからシャープ7つまで
+        const MAJOR_NAMES: [&str; 15] = [
+            "Cb", "Gb", "Db", "Ab", "Eb", "Bb", "F", "C", "G", "D", "A", "E", "B", "F#", "C#",
+        ];
+        const MINOR_NAMES: [&str; 15] = [
+            "Ab", "Eb", "Bb", "F", "C", "G", "D", "A", "E", "B", "F#", "C#", "G#", "D#", "A#",
+        ];
+        let index = (self.key_signature_sharps.clamp(-7, 7) + 7) as usize;
+        match self.key_signature_minor {
+            true => format!("{} minor", MINOR_NAMES[index]),
+            false => format!("{} major", MAJOR_NAMES[index]),
         }
     }
 }
@@ -201,16 +758,184 @@ impl Default for TimeAxis {
 pub struct MidiNote {
     pub note_on_time_axis: TimeAxis,
     pub note_off_time_axis: Option<TimeAxis>,
+    pub sustain_off_time_axis: Option<TimeAxis>, // ペダルによって離鍵後も鳴っている場合の終端
 
     pub note_length_ticks: Option<u32>,
 
     pub key: u32,
-    pub key_cdefgab: String,    // C, C#, D, D#, E, F, F#, G, G#, A, A#, B
+    // 表示にはkey_and_octave_yamahaを使う。音名とオクターブを別々に使う表示のために残している
+    #[allow(dead_code)]
+    pub key_cdefgab: String, // C, C#(Db), D, D#(Eb), E, F, F#(Gb), G, G#(Ab), A, A#(Bb), B
+    #[allow(dead_code)]
     pub key_octave_yamaha: i32, // -2 ~ 8
+    #[allow(dead_code)]
     pub key_octave_general_midi: i32, // -1 ~ 9
     pub key_and_octave_yamaha: String, // C-2 ~ G8
     pub velocity: u32,
     pub channel: u32, // 0 ~ 15
+    pub track: u32,   // 0始まりのトラック番号
+    // グループの見出しにはtrack_namesを使うので、ノート単位では今は読んでいない
+    #[allow(dead_code)]
+    pub track_name: String, // MetaMessage::TrackName
+}
+
+/// コントロールチェンジ等の、ある時点での値
+#[derive(Clone, Copy, Debug)]
+pub struct MidiControlPoint {
+    pub time_axis: TimeAxis,
+    pub value: i32, // CC・アフタータッチ・プログラムは0 ~ 127、ピッチベンドは-8192 ~ 8191
+}
+
+/// 1チャンネル分のコントロール系イベントの時系列。それぞれtickの昇順
+#[derive(Clone, Debug)]
+pub struct MidiControlLanes {
+    pub control_changes: Vec<Vec<MidiControlPoint>>, // controller番号 0 ~ 127
+    pub pitch_bend: Vec<MidiControlPoint>,
+    pub channel_aftertouch: Vec<MidiControlPoint>,
+    pub program_change: Vec<MidiControlPoint>,
+}
+
+impl Default for MidiControlLanes {
+    fn default() -> Self {
+        Self {
+            control_changes: vec![Vec::new(); 128],
+            pitch_bend: Vec::new(),
+            channel_aftertouch: Vec::new(),
+            program_change: Vec::new(),
+        }
+    }
+}
+
+impl MidiControlLanes {
+    pub fn get(&self, kind: AutomationLaneKind) -> &Vec<MidiControlPoint> {
+        match kind {
+            AutomationLaneKind::ControlChange(controller) => {
+                &self.control_changes[controller as usize]
+            }
+            AutomationLaneKind::PitchBend => &self.pitch_bend,
+            AutomationLaneKind::ChannelAftertouch => &self.channel_aftertouch,
+            AutomationLaneKind::ProgramChange => &self.program_change,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum MidiTextKind {
+    Text,
+    Copyright,
+    TrackName,
+    InstrumentName,
+    Lyric,
+    Marker,
+    CuePoint,
+}
+
+/// テキスト系のメタイベント
+#[derive(Clone, Debug)]
+pub struct MidiTextEvent {
+    pub time_axis: TimeAxis,
+    pub kind: MidiTextKind,
+    pub track: u32, // 0始まりのトラック番号
+    pub text: String,
+}
+
+/// Ch.16のノートによるテーマの切り替え
+#[derive(Clone, Copy, Debug)]
+pub struct ThemeChangeEvent {
+    pub time_axis: TimeAxis,
+    pub theme_index: usize, // C-1(key 0)が0、C#-1が1 ...
+}
+
+/// 現在のテーマ。切り替えの途中は、fromの色からtoの色へratioの割合で混ぜる
+#[derive(Resource, Debug, Clone, Copy, PartialEq)]
+pub struct ActiveTheme {
+    pub from_index: usize,
+    pub to_index: usize,
+    pub ratio: f32, // 0.0でfrom、1.0でto
+}
+
+impl Default for ActiveTheme {
+    fn default() -> Self {
+        Self {
+            from_index: 0,
+            to_index: 0,
+            ratio: 1.0,
+        }
+    }
+}
+
+impl ActiveTheme {
+    pub fn color(&self, themes: &[Theme], theme_color: ThemeColor) -> Color {
+        // 読み込み直した設定でテーマが減った場合は、先頭のテーマを使う
+        let from = theme_color.srgb(themes.get(self.from_index).unwrap_or(&themes[0]));
+        let to = theme_color.srgb(themes.get(self.to_index).unwrap_or(&themes[0]));
+        let color = util_color::mix_color(&from, &to, self.ratio);
+        Color::srgb(color[0], color[1], color[2])
+    }
+}
+
+/// テーマのどの色を使うか。これを持つエンティティは、テーマが切り替わると色が変わる
+///
+/// MeshMaterial2d<ColorMaterial>・TextColor・Cameraの背景色に反映する。
+#[derive(Component, Debug, Clone, Copy, PartialEq)]
+pub enum ThemeColor {
+    Background,
+    Main(u32), // main_base_hexから背景色へ近づける段階。0 ~ 4
+    Accent,
+    NoteChannel {
+        group_index: u32,
+        group_count: u32,
+    },
+    // ペダルによる余韻。brightnessが小さいほど背景色に近づく
+    SustainTail {
+        group_index: u32,
+        group_count: u32,
+        brightness: f32,
+    },
+    Grid {
+        strength: f32, // 0.0で背景色、1.0でaccentの色
+    },
+}
+
+impl ThemeColor {
+    fn srgb(&self, theme: &Theme) -> Vec<f32> {
+        match *self {
+            ThemeColor::Background => util_color::hex_to_srgb(&theme.background_hex).unwrap(),
+            ThemeColor::Main(level) => {
+                util_color::adjust_color(&theme.main_base_hex, &theme.background_hex, level, 4)
+                    .unwrap()
+            }
+            ThemeColor::Accent => util_color::hex_to_srgb(&theme.accent_base_hex).unwrap(),
+            ThemeColor::NoteChannel {
+                group_index,
+                group_count,
+            } => util_color::adjust_color(
+                &theme.note_channel_base_hex,
+                &theme.note_channel_target_hex,
+                group_index,
+                group_count,
+            )
+            .unwrap(),
+            ThemeColor::SustainTail {
+                group_index,
+                group_count,
+                brightness,
+            } => {
+                let color = ThemeColor::NoteChannel {
+                    group_index,
+                    group_count,
+                }
+                .srgb(theme);
+                let bg_color = util_color::hex_to_srgb(&theme.background_hex).unwrap();
+                util_color::mix_color(&color, &bg_color, 1.0 - brightness)
+            }
+            ThemeColor::Grid { strength } => {
+                let accent_color = util_color::hex_to_srgb(&theme.accent_base_hex).unwrap();
+                let bg_color = util_color::hex_to_srgb(&theme.background_hex).unwrap();
+                util_color::mix_color(&accent_color, &bg_color, 1.0 - strength)
+            }
+        }
+    }
 }
 
 #[derive(Debug, Clone, Copy, Default, Eq, PartialEq, Hash, States)]
@@ -218,7 +943,39 @@ pub enum AppState {
     #[default]
     Stop,
     Playing,
+    Paused, // 再生位置を保ったまま止めている
+}
+
+/// A-Bリピートの範囲。両方が設定されている間、終点に達すると始点に戻る
+#[derive(Resource, Default, Debug, Clone, Copy)]
+pub struct LoopRegion {
+    pub start_ticks: Option<u32>,
+    pub end_ticks: Option<u32>,
+}
+
+impl LoopRegion {
+    /// 始点と終点が揃っていて、始点が終点より前の場合だけ範囲を返す
+    pub fn range(&self) -> Option<(u32, u32)> {
+        match (self.start_ticks, self.end_ticks) {
+            (Some(start_ticks), Some(end_ticks)) if start_ticks < end_ticks => {
+                Some((start_ticks, end_ticks))
+            }
+            _ => None,
+        }
+    }
 }
 
 #[derive(Component)]
 pub struct MainWindowCamera;
+
+/// config.tomlを読み込み直したときに送られるイベント。各プラグインは表示を作り直す
+#[derive(Event)]
+pub struct ConfigReloaded;
+
+/// MIDIファイルを読み込み直したときに送られるイベント。ノートなどを持つプラグインは表示を作り直す
+#[derive(Event)]
+pub struct MidiReloaded;
+
+/// 再生位置が飛んだときに送られるイベント。経過時間に沿って状態を持つものは作り直す
+#[derive(Event)]
+pub struct TransportSeeked;
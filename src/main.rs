@@ -1,40 +1,80 @@
 //! Uses two windows to visualize a 3D model from different angles.
 
-use bevy::audio::PlaybackMode;
+use bevy::app::ScheduleRunnerPlugin;
+use bevy::prelude::*;
 use bevy::render::view::RenderLayers;
-use bevy::scene::ron::de;
-use bevy::state::commands;
 use bevy::time::Stopwatch;
-use bevy::window::{EnabledButtons, PrimaryWindow, WindowResolution};
-use bevy::{asset, prelude::*};
+use bevy::window::{EnabledButtons, ExitCondition, PrimaryWindow, WindowResolution};
+use bevy::winit::WinitPlugin;
+use std::path::Path;
+use std::time::Duration;
 
+mod audio_clock;
+mod audio_file;
+mod cli;
 mod config_controller;
 mod cubic_bezier;
 mod global_vars;
+mod midi_dump;
 mod midi_loader;
+mod piano_roll_grid;
+mod plugin_automation_lane;
+mod plugin_config_hot_reload;
+mod plugin_falling_notes;
+mod plugin_lyrics;
+mod plugin_midi_hot_reload;
 mod plugin_midi_note_animater;
+mod plugin_midi_note_scroller;
 mod plugin_midi_note_text;
+mod plugin_offline_render;
 mod plugin_status_window;
+mod plugin_theme_switch;
+mod plugin_transport_control;
 mod plugin_transport_panel;
+mod synth;
+mod tempo_map;
 mod util_color;
 
-#[derive(Component)]
-struct MainAudioComponent;
+/// コマンドライン引数で上書きした設定。setup_sceneでGlobalSettingsに移す
+#[derive(Resource)]
+struct StartupConfig(global_vars::Config);
 
 fn setup_scene(
     mut commands: Commands,
+    startup_config: Res<StartupConfig>,
     mut windows: Query<&mut Window, With<PrimaryWindow>>,
-    asset_server: Res<AssetServer>,
 ) {
-    // 設定の読み込み
-    let config = config_controller::load_config().unwrap();
-    let loaded_midi_return = midi_loader::load_midi(&config.main_config.midi_file_path);
+    let config = &startup_config.0;
+    commands.remove_resource::<StartupConfig>();
+    // MIDIの読み込みに失敗しても起動は続け、エラーをウィンドウに表示する
+    let (loaded_midi_return, midi_load_error_message) =
+        match midi_loader::load_midi(&config.main_config.midi_file_path, &config.midi_loader) {
+            Ok(mut loaded_midi_return) => {
+                if config.theme_switch.enabled {
+                    loaded_midi_return.reserve_theme_switch_channel();
+                }
+                for warning in &loaded_midi_return.warnings {
+                    warn!("{}", warning);
+                }
+                (loaded_midi_return, None)
+            }
+            Err(e) => {
+                error!("{}", e);
+                (midi_loader::LoadMidiReturn::default(), Some(e.to_string()))
+            }
+        };
     commands.insert_resource(global_vars::GlobalSettings {
         config: config.clone(),
         format: loaded_midi_return.format,
         ppm: loaded_midi_return.ppm,
-        time_axis_vec: loaded_midi_return.time_axis_vec,
+        smpte_fps: loaded_midi_return.smpte_fps,
+        tempo_map: loaded_midi_return.tempo_map,
         midi_notes_vec: loaded_midi_return.midi_notes_vec,
+        unsplit_midi_notes_vec: loaded_midi_return.unsplit_midi_notes_vec,
+        track_names: loaded_midi_return.track_names,
+        midi_control_lanes_vec: loaded_midi_return.midi_control_lanes_vec,
+        midi_text_events: loaded_midi_return.midi_text_events,
+        theme_change_events: loaded_midi_return.theme_change_events,
     });
 
     commands.insert_resource(global_vars::GlobalMonitorValues {
@@ -59,111 +99,180 @@ fn setup_scene(
 
     // カメラの設定
     let bg_color = util_color::hex_to_srgb(&config.theme[0].background_hex).unwrap();
-    commands.spawn((
-        Camera2d::default(),
-        Camera {
-            clear_color: ClearColorConfig::Custom(Color::srgb(
-                bg_color[0],
-                bg_color[1],
-                bg_color[2],
-            )),
-            ..default()
-        },
-        global_vars::MainWindowCamera,
-        RenderLayers::layer(0),
-    ));
-}
+    let main_window_camera = commands
+        .spawn((
+            Camera2d,
+            Camera {
+                clear_color: ClearColorConfig::Custom(Color::srgb(
+                    bg_color[0],
+                    bg_color[1],
+                    bg_color[2],
+                )),
+                ..default()
+            },
+            global_vars::MainWindowCamera,
+            global_vars::ThemeColor::Background,
+            RenderLayers::layer(0),
+        ))
+        .id();
 
-fn toggle_play_or_stop(
-    app_state: Res<State<global_vars::AppState>>,
-    mut next_app_state: ResMut<NextState<global_vars::AppState>>,
-    keys: Res<ButtonInput<KeyCode>>,
-    global_settings: Res<global_vars::GlobalSettings>,
-    mut global_monitor_values: ResMut<global_vars::GlobalMonitorValues>,
-    query: Query<&mut AudioSink, With<MainAudioComponent>>,
-    mut commands: Commands,
-    asset_server: Res<AssetServer>,
-) {
-    let is_music_finished = global_monitor_values.elapsed_time_from_start.elapsed_secs()
-        >= global_settings.time_axis_vec.last().unwrap().seconds_total;
-
-    if keys.just_pressed(KeyCode::Space) || is_music_finished {
-        match app_state.get() {
-            global_vars::AppState::Stop => {
-                next_app_state.set(global_vars::AppState::Playing);
-                global_monitor_values.elapsed_time_from_start.reset();
-
-                // オーディオの設定
-                commands.spawn((
-                    AudioPlayer::new(
-                        asset_server.load(&global_settings.config.main_config.wave_file_path),
-                    ),
-                    MainAudioComponent,
-                    PlaybackSettings {
-                        mode: PlaybackMode::Once,
-                        paused: true,
-                        ..default()
-                    },
-                ));
-            }
-            global_vars::AppState::Playing => {
-                next_app_state.set(global_vars::AppState::Stop);
-                global_monitor_values.elapsed_time_from_start.reset();
-                for audio_sink in &mut query.iter() {
-                    audio_sink.stop();
-                }
-            }
-        }
+    // エラー表示
+    if let Some(message) = midi_load_error_message {
+        let text_color = util_color::hex_to_srgb(&config.theme[0].accent_base_hex).unwrap();
+        commands.spawn((
+            global_vars::MidiLoadErrorText,
+            global_vars::ThemeColor::Accent,
+            Node {
+                position_type: PositionType::Absolute,
+                left: Val::Px(20.0),
+                bottom: Val::Px(20.0),
+                ..default()
+            },
+            Text::new(format!("Failed to load MIDI file\n{}", message)),
+            TextColor(Color::srgb(text_color[0], text_color[1], text_color[2])),
+            TargetCamera(main_window_camera),
+        ));
+        commands.insert_resource(global_vars::MidiLoadErrorMessage(message));
     }
 }
 
+/// オーディオの再生位置とのずれがこれを超えたら、補正せずに合わせ直す
+const AUDIO_RESYNC_THRESHOLD_SECONDS: f32 = 0.1;
+/// 1フレームあたりに補正する、ずれの割合
+const AUDIO_CORRECTION_RATE: f32 = 0.1;
+
 fn update_monitor_values(
     time: Res<Time>,
     mut global_monitor_values: ResMut<global_vars::GlobalMonitorValues>,
     global_settings: Res<global_vars::GlobalSettings>,
     app_state: Res<State<global_vars::AppState>>,
-    query: Query<&mut AudioSink, With<MainAudioComponent>>,
+    audio_clock: Option<Res<audio_clock::AudioClock>>,
 ) {
     if app_state.get() == &global_vars::AppState::Playing {
         global_monitor_values
             .elapsed_time_from_start
             .tick(time.delta());
-        let elapsed_time = global_monitor_values.elapsed_time_from_start.elapsed_secs();
-        let time_axis = global_settings
-            .time_axis_vec
-            .iter()
-            .rev()
-            .find(|x| x.seconds_total <= elapsed_time);
-        if let Some(time_axis) = time_axis {
-            global_monitor_values.current_time_axis = *time_axis;
-        }
 
-        // オーディオの再生
-        for audio_sink in &mut query.iter() {
-            if audio_sink.is_paused() && global_monitor_values.current_time_axis.seconds_total > 0.2
-            {
-                audio_sink.play();
-            }
+        // オーディオが鳴っている間は、フレームの経過時間の積み上げをオーディオの再生位置に寄せる
+        if let Some(audio_position) = audio_clock.and_then(|x| x.position()) {
+            let audio_seconds = audio_position.as_secs_f32()
+                - global_settings.config.main_config.audio_offset_ms / 1000.0;
+            let elapsed_seconds = global_monitor_values.elapsed_time_from_start.elapsed_secs();
+            let diff = audio_seconds - elapsed_seconds;
+            // 読み出しはバッファ単位なので、少しのずれは滑らかに補正し、大きくずれたときだけ合わせ直す
+            let corrected_seconds = match diff.abs() > AUDIO_RESYNC_THRESHOLD_SECONDS {
+                true => audio_seconds,
+                false => elapsed_seconds + diff * AUDIO_CORRECTION_RATE,
+            };
+            global_monitor_values
+                .elapsed_time_from_start
+                .set_elapsed(Duration::from_secs_f32(corrected_seconds.max(0.0)));
         }
+
+        let elapsed_time = global_monitor_values.elapsed_time_from_start.elapsed_secs();
+        global_monitor_values.current_time_axis = global_settings
+            .tempo_map
+            .time_axis_from_seconds(elapsed_time);
     } else if app_state.get() == &global_vars::AppState::Stop {
         global_monitor_values.elapsed_time_from_start.reset();
-        global_monitor_values.current_time_axis = global_settings.time_axis_vec[0];
+        global_monitor_values.current_time_axis = global_settings.tempo_map.first_time_axis();
     }
 }
 
-fn main() {
-    App::new()
+fn main() -> AppExit {
+    let cli_args = match cli::parse_args(std::env::args().skip(1)) {
+        Ok(cli_args) => cli_args,
+        Err(e) => {
+            eprintln!("{}\n\n{}", e, cli::USAGE);
+            return AppExit::from_code(2);
+        }
+    };
+    if cli_args.help {
+        println!("{}", cli::USAGE);
+        return AppExit::Success;
+    }
+
+    // 設定の読み込み
+    let mut config = match config_controller::load_config(Path::new(&cli_args.config_path)) {
+        Ok(config) => config,
+        Err(e) => {
+            eprintln!("{}", e);
+            return AppExit::from_code(1);
+        }
+    };
+    cli_args.apply(&mut config);
+
+    // Bevyのシステムが動く前に、設定の問題を全てまとめて表示する
+    let config_problems = config_controller::validate_config(&config);
+    for config_problem in &config_problems {
+        match config_problem.severity {
+            config_controller::ConfigSeverity::Error => eprintln!("error: {}", config_problem),
+            config_controller::ConfigSeverity::Warning => eprintln!("warning: {}", config_problem),
+        }
+    }
+    if config_problems
+        .iter()
+        .any(|x| x.severity == config_controller::ConfigSeverity::Error)
+    {
+        eprintln!("invalid config file {}", cli_args.config_path);
+        return AppExit::from_code(1);
+    }
+
+    if cli_args.mode == Some(cli::RunMode::Analyze) {
+        return match midi_dump::print_midi_summary(&config) {
+            Ok(()) => AppExit::Success,
+            Err(e) => {
+                eprintln!("{}", e);
+                AppExit::from_code(1)
+            }
+        };
+    }
+
+    let mut app = App::new();
+
+    if config.offline_render.enabled {
+        // ウィンドウを開かずに、1フレームずつ画像に描画して書き出す
+        app.add_plugins(
+            DefaultPlugins
+                .set(WindowPlugin {
+                    primary_window: None,
+                    exit_condition: ExitCondition::DontExit,
+                    close_when_requested: false,
+                })
+                .disable::<WinitPlugin>(),
+        )
+        .add_plugins(ScheduleRunnerPlugin::run_loop(Duration::ZERO))
+        .add_plugins(plugin_offline_render::OfflineRenderPlugin)
+        .insert_state(global_vars::AppState::Playing);
+    } else {
         // By default, a primary window gets spawned by `WindowPlugin`, contained in `DefaultPlugins`
-        .add_plugins(DefaultPlugins)
-        .add_plugins(plugin_status_window::StatusWindowPlugin)
-        .add_plugins(plugin_midi_note_text::MidiNoteTextPlugin)
+        app.add_plugins(DefaultPlugins)
+            .add_plugins(plugin_status_window::StatusWindowPlugin)
+            .add_plugins(plugin_config_hot_reload::ConfigHotReloadPlugin {
+                cli_args: cli_args.clone(),
+            })
+            .add_plugins(plugin_midi_hot_reload::MidiHotReloadPlugin)
+            .init_state::<global_vars::AppState>()
+            .add_plugins(plugin_transport_control::TransportControlPlugin)
+            .configure_sets(
+                PreUpdate,
+                plugin_transport_control::TransportControlSet.before(update_monitor_values),
+            );
+    }
+
+    app.add_plugins(plugin_midi_note_text::MidiNoteTextPlugin)
         .add_plugins(plugin_midi_note_animater::MidiNoteAnimatePlugin)
+        .add_plugins(plugin_midi_note_scroller::MidiNoteScrollPlugin)
+        .add_plugins(plugin_falling_notes::FallingNotesPlugin)
         .add_plugins(plugin_transport_panel::TransportPanelPlugin)
-        .init_state::<global_vars::AppState>()
+        .add_plugins(plugin_automation_lane::AutomationLanePlugin)
+        .add_plugins(plugin_lyrics::LyricsPlugin)
+        .add_plugins(plugin_theme_switch::ThemeSwitchPlugin)
+        .add_event::<global_vars::ConfigReloaded>()
+        .add_event::<global_vars::MidiReloaded>()
+        .add_event::<global_vars::TransportSeeked>()
+        .insert_resource(StartupConfig(config))
         .add_systems(Startup, setup_scene)
-        .add_systems(
-            PreUpdate,
-            (toggle_play_or_stop, update_monitor_values).chain(),
-        )
-        .run();
+        .add_systems(PreUpdate, update_monitor_values)
+        .run()
 }
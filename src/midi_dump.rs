@@ -0,0 +1,165 @@
+use crate::global_vars::Config;
+use crate::midi_loader;
+use std::fmt::Write;
+
+/// MIDIを読み込んだ結果を標準出力に書き出す。ウィンドウは開かない
+pub fn print_midi_summary(config: &Config) -> Result<(), midi_loader::MidiLoadError> {
+    let midi_file_path = &config.main_config.midi_file_path;
+    let loaded_midi_return = midi_loader::load_midi(midi_file_path, &config.midi_loader)?;
+
+    let mut summary = String::new();
+    // Stringへの書き込みは失敗しない
+    write_midi_summary(&mut summary, midi_file_path, &loaded_midi_return).unwrap();
+    print!("{}", summary);
+    Ok(())
+}
+
+fn write_midi_summary(
+    out: &mut impl Write,
+    midi_file_path: &str,
+    loaded_midi_return: &midi_loader::LoadMidiReturn,
+) -> std::fmt::Result {
+    let tempo_map = &loaded_midi_return.tempo_map;
+    let last_time_axis = tempo_map.last_time_axis();
+
+    writeln!(out, "File: {}", midi_file_path)?;
+    match loaded_midi_return.smpte_fps {
+        Some(fps) => writeln!(
+            out,
+            "Format: {:?}, PPM: {} (SMPTE {} fps)",
+            loaded_midi_return.format,
+            loaded_midi_return.ppm,
+            fps.as_int()
+        ),
+        None => writeln!(
+            out,
+            "Format: {:?}, PPM: {}",
+            loaded_midi_return.format, loaded_midi_return.ppm
+        ),
+    }?;
+    writeln!(
+        out,
+        "Length: {:.3} s, {} ticks, {} measures",
+        last_time_axis.seconds_total,
+        last_time_axis.ticks_total,
+        last_time_axis.measure + (last_time_axis.ticks_reset_by_measure > 0) as u32
+    )?;
+
+    writeln!(out)?;
+    writeln!(out, "Tempo / Time Signature / Key:")?;
+    for time_axis in tempo_map.change_time_axes() {
+        writeln!(
+            out,
+            "  {:>10.3} s  measure {:>4}  tick {:>8}  {:>7.2} BPM  {}/{}  {}",
+            time_axis.seconds_total,
+            time_axis.measure,
+            time_axis.ticks_total,
+            time_axis.tempo,
+            time_axis.time_signature_numerator,
+            time_axis.time_signature_denominator,
+            time_axis.key_signature_name()
+        )?;
+    }
+
+    writeln!(out)?;
+    writeln!(out, "Tracks:")?;
+    // 小節を跨ぐノートは分割されているので、数えるのは分割前のノート
+    for (track_index, track_name) in loaded_midi_return.track_names.iter().enumerate() {
+        let note_count = loaded_midi_return
+            .unsplit_midi_notes_vec
+            .iter()
+            .flatten()
+            .filter(|x| x.track == track_index as u32)
+            .count();
+        writeln!(
+            out,
+            "  tr{:<3} {:>6} notes  {}",
+            track_index + 1,
+            note_count,
+            track_name
+        )?;
+    }
+
+    writeln!(out)?;
+    writeln!(out, "Channels:")?;
+    for (channel, midi_notes) in loaded_midi_return.unsplit_midi_notes_vec.iter().enumerate() {
+        let lowest = midi_notes.iter().min_by_key(|x| x.key);
+        let highest = midi_notes.iter().max_by_key(|x| x.key);
+        if let (Some(lowest), Some(highest)) = (lowest, highest) {
+            writeln!(
+                out,
+                "  ch{:<3} {:>6} notes  {} - {}",
+                channel + 1,
+                midi_notes.len(),
+                lowest.key_and_octave_yamaha,
+                highest.key_and_octave_yamaha
+            )?;
+        }
+    }
+
+    if !loaded_midi_return.midi_text_events.is_empty() {
+        writeln!(out)?;
+        writeln!(out, "Text Events:")?;
+        for midi_text_event in &loaded_midi_return.midi_text_events {
+            writeln!(
+                out,
+                "  {:>10.3} s  tr{:<3} {:<14} {:?}",
+                midi_text_event.time_axis.seconds_total,
+                midi_text_event.track + 1,
+                format!("{:?}", midi_text_event.kind),
+                midi_text_event.text
+            )?;
+        }
+    }
+
+    if !loaded_midi_return.warnings.is_empty() {
+        writeln!(out)?;
+        writeln!(out, "Warnings:")?;
+        for warning in &loaded_midi_return.warnings {
+            writeln!(out, "  {}", warning)?;
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::global_vars::MidiLoaderConfig;
+
+    /// testディレクトリのファイルを読み込み、書き出した内容を返す
+    fn midi_summary(file_name: &str) -> String {
+        let midi_file_path = format!("{}/test/{}", env!("CARGO_MANIFEST_DIR"), file_name);
+        let loaded_midi_return =
+            midi_loader::load_midi(&midi_file_path, &MidiLoaderConfig::default()).unwrap();
+        let mut summary = String::new();
+        write_midi_summary(&mut summary, &midi_file_path, &loaded_midi_return).unwrap();
+        summary
+    }
+
+    #[test]
+    fn note_counts() {
+        let summary = midi_summary("overlapping_notes/two_notes.mid");
+        assert!(summary.contains("\n  tr1        2 notes  \n"), "{summary}");
+        assert!(
+            summary.contains("\n  ch1        2 notes  C3 - C3\n"),
+            "{summary}"
+        );
+    }
+
+    #[test]
+    fn note_counts_across_barline() {
+        // 小節を跨ぐノートは、分割した断片ではなく1つとして数える
+        let summary = midi_summary("theme_switch/across_barline.mid");
+        assert!(summary.contains("\n  tr1        3 notes  \n"), "{summary}");
+        assert!(
+            summary.contains("\n  ch1        1 notes  C3 - C3\n"),
+            "{summary}"
+        );
+        assert!(
+            summary.contains("\n  ch16       2 notes  C#-2 - D-2\n"),
+            "{summary}"
+        );
+    }
+}
@@ -1,63 +1,188 @@
 use midly::{MetaMessage, Smf, Timing, TrackEventKind};
+use std::fmt;
 use std::fs;
+use std::io;
+
+use crate::global_vars::{
+    MidiControlLanes, MidiControlPoint, MidiLoaderConfig, MidiNote, MidiTextEvent, MidiTextKind,
+    OrphanedNotePolicy, OverlappingNotePolicy, ThemeChangeEvent,
+};
+use crate::tempo_map::{
+    KeySignatureChangeEvent, TempoChangeEvent, TempoMap, TimeSignatureChangeEvent,
+};
+
+#[derive(Debug)]
+pub enum MidiLoadError {
+    FileNotFound(String),
+    Io(String, io::Error),
+    Parse(midly::Error),
+    UnsupportedTiming(Timing),
+    EmptyFile,
+    NoNotes,
+}
 
-use crate::global_vars::{MidiNote, TimeAxis};
+impl fmt::Display for MidiLoadError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            MidiLoadError::FileNotFound(path) => write!(f, "MIDI file not found: {}", path),
+            MidiLoadError::Io(path, e) => write!(f, "failed to read MIDI file {}: {}", path, e),
+            MidiLoadError::Parse(e) => write!(f, "failed to parse MIDI file: {}", e),
+            MidiLoadError::UnsupportedTiming(timing) => {
+                write!(f, "unsupported MIDI timing: {:?}", timing)
+            }
+            MidiLoadError::EmptyFile => write!(f, "MIDI file has no tracks"),
+            MidiLoadError::NoNotes => write!(f, "MIDI file has no notes"),
+        }
+    }
+}
+
+impl std::error::Error for MidiLoadError {}
 
 pub struct LoadMidiReturn {
     pub format: midly::Format,
     pub ppm: u16,
-    pub time_axis_vec: Vec<TimeAxis>,
+    pub smpte_fps: Option<midly::Fps>,
+    pub tempo_map: TempoMap,
     pub midi_notes_vec: Vec<Vec<MidiNote>>,
+    pub unsplit_midi_notes_vec: Vec<Vec<MidiNote>>, // 小節で分割する前のノート
+    pub track_names: Vec<String>,
+    pub midi_control_lanes_vec: Vec<MidiControlLanes>,
+    pub midi_text_events: Vec<MidiTextEvent>,
+    pub theme_change_events: Vec<ThemeChangeEvent>, // reserve_theme_switch_channelを呼んだ場合のみ
+    pub warnings: Vec<String>, // 読み込みは続けられたが、データに問題があったもの
+}
+
+/// テーマの切り替えに使うチャンネル(ch.16)
+const THEME_SWITCH_CHANNEL: usize = 15;
+
+impl LoadMidiReturn {
+    /// Ch.16のノートを取り除き、テーマの切り替えとして読み替える
+    ///
+    /// チャンネルごとの色が変わらないように、Ch.16は空のまま残す。
+    pub fn reserve_theme_switch_channel(&mut self) {
+        if let Some(midi_notes) = self.midi_notes_vec.get_mut(THEME_SWITCH_CHANNEL) {
+            midi_notes.clear();
+        }
+        // 小節で分割したノートでは、小節を跨ぐノートの2つ目以降の断片も切り替えになってしまう
+        let Some(midi_notes) = self.unsplit_midi_notes_vec.get_mut(THEME_SWITCH_CHANNEL) else {
+            return;
+        };
+        self.theme_change_events = std::mem::take(midi_notes)
+            .into_iter()
+            .map(|x| ThemeChangeEvent {
+                time_axis: x.note_on_time_axis,
+                theme_index: x.key as usize,
+            })
+            .collect();
+        self.theme_change_events
+            .sort_by_key(|x| x.time_axis.ticks_total);
+    }
 }
 
-pub fn load_midi(midi_file_path: &str) -> LoadMidiReturn {
-    let bytes = fs::read(midi_file_path).unwrap();
-    let smf = Smf::parse(&bytes).unwrap();
+impl Default for LoadMidiReturn {
+    /// 読み込みに失敗したときに使う、ノートを持たない空のデータ
+    fn default() -> Self {
+        Self {
+            format: midly::Format::SingleTrack,
+            ppm: 480,
+            smpte_fps: None,
+            tempo_map: TempoMap::new(480, 0, Vec::new(), Vec::new(), Vec::new()),
+            midi_notes_vec: vec![Vec::new(); 16],
+            unsplit_midi_notes_vec: vec![Vec::new(); 16],
+            track_names: Vec::new(),
+            midi_control_lanes_vec: vec![MidiControlLanes::default(); 16],
+            midi_text_events: Vec::new(),
+            theme_change_events: Vec::new(),
+            warnings: Vec::new(),
+        }
+    }
+}
+
+pub fn load_midi(
+    midi_file_path: &str,
+    config: &MidiLoaderConfig,
+) -> Result<LoadMidiReturn, MidiLoadError> {
+    let bytes = fs::read(midi_file_path).map_err(|e| match e.kind() {
+        io::ErrorKind::NotFound => MidiLoadError::FileNotFound(midi_file_path.to_string()),
+        _ => MidiLoadError::Io(midi_file_path.to_string(), e),
+    })?;
+    if bytes.is_empty() {
+        return Err(MidiLoadError::EmptyFile);
+    }
+    let smf = Smf::parse(&bytes).map_err(MidiLoadError::Parse)?;
+    if smf.tracks.is_empty() {
+        return Err(MidiLoadError::EmptyFile);
+    }
     let format = smf.header.format;
-    let ppm = match smf.header.timing {
-        Timing::Metrical(ppm) => ppm.as_int(),
-        _ => panic!("unsupported timing"),
+    let (ppm, timecode) = match smf.header.timing {
+        Timing::Metrical(ppm) if ppm.as_int() > 0 => (ppm.as_int(), None),
+        Timing::Timecode(fps, subframe) if subframe > 0 => {
+            // タイムコードには拍の概念が無いので、名目上のテンポから四分音符あたりのtickを決める
+            let ticks_per_second = fps.as_f32() * subframe as f32;
+            let ppm = (ticks_per_second * 60.0 / config.timecode_grid_tempo).round() as u16;
+            (ppm.max(1), Some((fps, subframe)))
+        }
+        timing => return Err(MidiLoadError::UnsupportedTiming(timing)),
     };
 
-    let result_time_axis_vec = get_time_axis(&smf);
-    let result_midi_notes_vec = get_midi_notes(&smf, &result_time_axis_vec.time_axis_vec);
+    let (tempo_map, mut warnings) = get_tempo_map(&smf, ppm, timecode, config);
+    let result_midi_notes_vec = get_midi_notes(&smf, &tempo_map, ppm, config);
+    if result_midi_notes_vec
+        .midi_notes
+        .iter()
+        .all(|x| x.is_empty())
+    {
+        return Err(MidiLoadError::NoNotes);
+    }
+    let midi_control_lanes_vec = get_midi_control_lanes(&smf, &tempo_map);
+    let midi_text_events = get_midi_text_events(&smf, &tempo_map);
+    warnings.extend(result_midi_notes_vec.warnings);
 
-    return LoadMidiReturn {
+    Ok(LoadMidiReturn {
         format,
         ppm,
-        time_axis_vec: result_time_axis_vec.time_axis_vec,
+        smpte_fps: timecode.map(|(fps, _)| fps),
+        tempo_map,
         midi_notes_vec: result_midi_notes_vec.midi_notes,
-    };
-}
-
-struct TempoChangeEvent {
-    tempo: f32,            // how many beats per minute
-    total_ticks: u32,      // how many ticks before the tempo change
-    seconds_per_tick: f32, // how many seconds per tick after the tempo change
-}
-
-struct TimeSignatureChangeEvent {
-    pub numerator: u8,
-    pub denominator: u8,
-    pub midi_clocks_per_metronome_click: u8,
-    pub thirty_seconds_notes_per_quarter_note: u8,
-    pub total_ticks: u32, // how many ticks before the tempo change
-}
-
-struct GetTimeAxisReturn {
-    time_axis_vec: Vec<TimeAxis>,
+        unsplit_midi_notes_vec: result_midi_notes_vec.unsplit_midi_notes,
+        track_names: result_midi_notes_vec.track_names,
+        midi_control_lanes_vec,
+        midi_text_events,
+        theme_change_events: Vec::new(),
+        warnings,
+    })
 }
 
-fn get_time_axis(smf: &Smf) -> GetTimeAxisReturn {
-    let ppm = match smf.header.timing {
-        Timing::Metrical(ppm) => ppm.as_int(),
-        _ => panic!("unsupported timing"),
-    };
-
+fn get_tempo_map(
+    smf: &Smf,
+    ppm: u16,
+    timecode: Option<(midly::Fps, u8)>,
+    config: &MidiLoaderConfig,
+) -> (TempoMap, Vec<String>) {
+    let mut warnings: Vec<String> = Vec::new();
     let mut tempo_change_events: Vec<TempoChangeEvent> = Vec::new();
     let mut time_signature_change_events: Vec<TimeSignatureChangeEvent> = Vec::new();
+    let mut key_signature_change_events: Vec<KeySignatureChangeEvent> = Vec::new();
     let mut end_of_track_ticks = 0;
 
+    // タイムコード形式では1tickの長さが固定で、テンポイベントは時間に影響しない
+    if let Some((fps, subframe)) = timecode {
+        tempo_change_events.push(TempoChangeEvent {
+            tempo: config.timecode_grid_tempo,
+            total_ticks: 0,
+            seconds_per_tick: 1.0 / (fps.as_f32() as f64 * subframe as f64),
+        });
+    }
+
+    // ファイル内の拍子イベントで上書きされる
+    time_signature_change_events.push(TimeSignatureChangeEvent {
+        numerator: config.default_time_signature_numerator,
+        denominator: config.default_time_signature_denominator,
+        midi_clocks_per_metronome_click: 24,
+        thirty_seconds_notes_per_quarter_note: 8,
+        total_ticks: 0,
+    });
+
     // テンポデータや拍子データは他トラックにまぎれていることがあるので、
     // トラックごとに解析する必要がある
     for track in smf.tracks.iter() {
@@ -67,8 +192,8 @@ fn get_time_axis(smf: &Smf) -> GetTimeAxisReturn {
             total_ticks += event.delta.as_int();
 
             match event.kind {
-                TrackEventKind::Meta(MetaMessage::Tempo(tempo)) => {
-                    let current_seconds_per_tick = tempo.as_int() as f32 * 1E-6 / ppm as f32;
+                TrackEventKind::Meta(MetaMessage::Tempo(tempo)) if timecode.is_none() => {
+                    let current_seconds_per_tick = tempo.as_int() as f64 * 1E-6 / ppm as f64;
 
                     let tempo_change_event = TempoChangeEvent {
                         tempo: 60.0 / ((tempo.as_int() as f32) * 1E-6),
@@ -84,9 +209,33 @@ fn get_time_axis(smf: &Smf) -> GetTimeAxisReturn {
                     midi_clocks_per_metronome_click,
                     thirty_seconds_notes_per_quarter_note,
                 )) => {
+                    // 分子が0の拍子や、1拍が1tickに満たない分母は小節を計算できないので、設定の拍子に置き換える
+                    let (numerator, denominator) = match 1u8.checked_shl(denominator as u32) {
+                        Some(denominator)
+                            if numerator > 0
+                                && denominator <= 64
+                                && ppm as u32 * 4 / denominator as u32 > 0 =>
+                        {
+                            (numerator, denominator)
+                        }
+                        _ => {
+                            warnings.push(format!(
+                                "invalid time signature {}/2^{} at tick {}, replaced with {}/{}",
+                                numerator,
+                                denominator,
+                                total_ticks,
+                                config.default_time_signature_numerator,
+                                config.default_time_signature_denominator
+                            ));
+                            (
+                                config.default_time_signature_numerator,
+                                config.default_time_signature_denominator,
+                            )
+                        }
+                    };
                     let time_signature_change_event = TimeSignatureChangeEvent {
                         numerator,
-                        denominator: 2u8.pow(denominator as u32),
+                        denominator,
                         midi_clocks_per_metronome_click,
                         thirty_seconds_notes_per_quarter_note,
                         total_ticks,
@@ -94,12 +243,16 @@ fn get_time_axis(smf: &Smf) -> GetTimeAxisReturn {
                     time_signature_change_events.push(time_signature_change_event);
                 }
 
+                TrackEventKind::Meta(MetaMessage::KeySignature(sharps, minor)) => {
+                    key_signature_change_events.push(KeySignatureChangeEvent {
+                        sharps,
+                        minor,
+                        total_ticks,
+                    });
+                }
+
                 TrackEventKind::Meta(MetaMessage::EndOfTrack) => {
-                    end_of_track_ticks = if total_ticks > end_of_track_ticks {
-                        total_ticks
-                    } else {
-                        end_of_track_ticks
-                    };
+                    end_of_track_ticks = end_of_track_ticks.max(total_ticks);
                 }
                 _ => {}
             }
@@ -126,113 +279,223 @@ fn get_time_axis(smf: &Smf) -> GetTimeAxisReturn {
     //     );
     // }
 
-    // sort by total_ticks
-    tempo_change_events.sort_by(|a, b| a.total_ticks.cmp(&b.total_ticks));
-    time_signature_change_events.sort_by(|a, b| a.total_ticks.cmp(&b.total_ticks));
-
-    // make time_axis
-    let mut time_axis_vec: Vec<TimeAxis> = Vec::new();
-    let mut current_seconds = 0.0;
-    let mut current_tempo = 120.0; // beat per minute
-    let mut current_seconds_per_tick = 60.0 / current_tempo / ppm as f32;
-    let mut current_time_signature_numerator = 4;
-    let mut current_time_signature_denominator = 4;
-    let mut current_time_signature_midi_clocks_per_metronome_click = 24;
-    let mut current_time_signature_thirty_seconds_notes_per_quarter_note = 8;
-
-    let mut current_measure = 0;
-    let mut current_ticks_reset_by_measure = 0;
-
-    for tick in 0..(end_of_track_ticks + 1) {
-        // search tempo_change_event which has same ticks. if none, no change
-        let tempo_change_event = tempo_change_events
-            .iter()
-            .rev()
-            .find(|x| x.total_ticks == tick);
-        if let Some(tempo_change_event) = tempo_change_event {
-            current_tempo = tempo_change_event.tempo;
-            current_seconds_per_tick = tempo_change_event.seconds_per_tick;
+    let tempo_map = TempoMap::new(
+        ppm,
+        end_of_track_ticks,
+        tempo_change_events,
+        time_signature_change_events,
+        key_signature_change_events,
+    );
+    (tempo_map, warnings)
+}
+
+/// ノートを指定したtickで閉じる
+fn close_note(note: &mut MidiNote, tempo_map: &TempoMap, note_off_ticks: u32) {
+    note.note_off_time_axis = Some(tempo_map.time_axis_from_ticks(note_off_ticks));
+    note.note_length_ticks = Some(note_off_ticks - note.note_on_time_axis.ticks_total);
+}
+
+/// NoteOffに対応する、まだ閉じていない同じキーのノートを設定に従って選び、閉じる
+fn close_matching_note(
+    notes: &mut [MidiNote],
+    key: u32,
+    note_off_ticks: u32,
+    tempo_map: &TempoMap,
+    overlapping_note_policy: OverlappingNotePolicy,
+) {
+    let mut open_notes = notes
+        .iter_mut()
+        .filter(|x| x.key == key && x.note_off_time_axis.is_none());
+    let note = match overlapping_note_policy {
+        OverlappingNotePolicy::Fifo => open_notes.next(),
+        OverlappingNotePolicy::Lifo | OverlappingNotePolicy::Retrigger => open_notes.last(),
+    };
+    if let Some(note) = note {
+        close_note(note, tempo_map, note_off_ticks);
+    }
+}
+
+/// チャンネルごとに、コントロールチェンジ・ピッチベンド・アフタータッチ・プログラムチェンジを集める
+fn get_midi_control_lanes(smf: &Smf, tempo_map: &TempoMap) -> Vec<MidiControlLanes> {
+    let mut midi_control_lanes_vec = vec![MidiControlLanes::default(); 16];
+
+    for track in smf.tracks.iter() {
+        let mut ticks_total = 0;
+        for event in track.iter() {
+            ticks_total += event.delta.as_int();
+            let TrackEventKind::Midi { channel, message } = event.kind else {
+                continue;
+            };
+            let lanes = &mut midi_control_lanes_vec[channel.as_int() as usize];
+            let (lane, value) = match message {
+                midly::MidiMessage::Controller { controller, value } => (
+                    &mut lanes.control_changes[controller.as_int() as usize],
+                    value.as_int() as i32,
+                ),
+                midly::MidiMessage::PitchBend { bend } => {
+                    (&mut lanes.pitch_bend, bend.as_int() as i32)
+                }
+                midly::MidiMessage::ChannelAftertouch { vel } => {
+                    (&mut lanes.channel_aftertouch, vel.as_int() as i32)
+                }
+                midly::MidiMessage::ProgramChange { program } => {
+                    (&mut lanes.program_change, program.as_int() as i32)
+                }
+                _ => continue,
+            };
+            lane.push(MidiControlPoint {
+                time_axis: tempo_map.time_axis_from_ticks(ticks_total),
+                value,
+            });
         }
+    }
 
-        // search time_signature_change_event which has same ticks. if none, no change
-        let time_signature_change_event = time_signature_change_events
-            .iter()
-            .rev()
-            .find(|x| x.total_ticks == tick);
-        if let Some(time_signature_change_event) = time_signature_change_event {
-            current_time_signature_numerator = time_signature_change_event.numerator;
-            current_time_signature_denominator = time_signature_change_event.denominator;
-            current_time_signature_midi_clocks_per_metronome_click =
-                time_signature_change_event.midi_clocks_per_metronome_click;
-            current_time_signature_thirty_seconds_notes_per_quarter_note =
-                time_signature_change_event.thirty_seconds_notes_per_quarter_note;
+    // トラックをまたいで集めたので、tick順に並べ直す
+    for lanes in midi_control_lanes_vec.iter_mut() {
+        for lane in lanes.control_changes.iter_mut().chain([
+            &mut lanes.pitch_bend,
+            &mut lanes.channel_aftertouch,
+            &mut lanes.program_change,
+        ]) {
+            lane.sort_by_key(|x| x.time_axis.ticks_total);
         }
+    }
 
-        let measure_length_ticks = ppm as u32 * current_time_signature_numerator as u32 * 4
-            / current_time_signature_denominator as u32;
-        let measure = current_measure;
-        let ticks_reset_by_measure = current_ticks_reset_by_measure;
-        let beat_length_ticks = ppm as u32 * 4 / current_time_signature_denominator as u32;
-        let beat = ticks_reset_by_measure as u32 / beat_length_ticks
-            % current_time_signature_numerator as u32
-            + 1;
-        let ticks_reset_by_beat = ticks_reset_by_measure % beat_length_ticks;
-
-        let time_axis_data = TimeAxis {
-            ticks_total: tick,
-            seconds_total: current_seconds, // ticks == 0のときは0
-            measure,                        // ticks == 0のときは0
-            beat,                           // ticks == 0のときは1
-            ticks_reset_by_beat,            // ticks == 0のときは0
-            ticks_reset_by_measure,
-            measure_length_ticks,
-            beat_length_ticks,
-            tempo: current_tempo,
-            time_signature_numerator: current_time_signature_numerator,
-            time_signature_denominator: current_time_signature_denominator,
-            time_signature_midi_clocks_per_metronome_click:
-                current_time_signature_midi_clocks_per_metronome_click,
-            time_signature_thirty_seconds_notes_per_quarter_note:
-                current_time_signature_thirty_seconds_notes_per_quarter_note,
-        };
-        time_axis_vec.push(time_axis_data);
-
-        current_seconds += current_seconds_per_tick;
-        if current_ticks_reset_by_measure == measure_length_ticks - 1 {
-            current_ticks_reset_by_measure = 0;
-            current_measure += 1;
-        } else {
-            current_ticks_reset_by_measure += 1;
+    midi_control_lanes_vec
+}
+
+/// 歌詞・マーカー等のテキスト系メタイベントを集める
+fn get_midi_text_events(smf: &Smf, tempo_map: &TempoMap) -> Vec<MidiTextEvent> {
+    let mut midi_text_events: Vec<MidiTextEvent> = Vec::new();
+
+    for (track_index, track) in smf.tracks.iter().enumerate() {
+        let mut ticks_total = 0;
+        for event in track.iter() {
+            ticks_total += event.delta.as_int();
+            let (kind, text) = match event.kind {
+                TrackEventKind::Meta(MetaMessage::Text(text)) => (MidiTextKind::Text, text),
+                TrackEventKind::Meta(MetaMessage::Copyright(text)) => {
+                    (MidiTextKind::Copyright, text)
+                }
+                TrackEventKind::Meta(MetaMessage::TrackName(text)) => {
+                    (MidiTextKind::TrackName, text)
+                }
+                TrackEventKind::Meta(MetaMessage::InstrumentName(text)) => {
+                    (MidiTextKind::InstrumentName, text)
+                }
+                TrackEventKind::Meta(MetaMessage::Lyric(text)) => (MidiTextKind::Lyric, text),
+                TrackEventKind::Meta(MetaMessage::Marker(text)) => (MidiTextKind::Marker, text),
+                TrackEventKind::Meta(MetaMessage::CuePoint(text)) => (MidiTextKind::CuePoint, text),
+                _ => continue,
+            };
+            midi_text_events.push(MidiTextEvent {
+                time_axis: tempo_map.time_axis_from_ticks(ticks_total),
+                kind,
+                track: track_index as u32,
+                text: String::from_utf8_lossy(text).to_string(),
+            });
         }
     }
 
-    // // for debug print time_axis_vec
-    // for time_axis in time_axis_vec.iter() {
-    //     println!(
-    //         "ticks:{} sec:{} measure:{} beat:{} tempo:{}",
-    //         time_axis.ticks_total,
-    //         time_axis.seconds_total,
-    //         time_axis.measure,
-    //         time_axis.beat,
-    //         time_axis.tempo
-    //     );
-    // }
+    midi_text_events.sort_by_key(|x| x.time_axis.ticks_total);
+    midi_text_events
+}
+
+/// ペダルによる余韻の終端を延ばす
+fn extend_sustain_off(note: &mut MidiNote, tempo_map: &TempoMap, sustain_off_ticks: u32) {
+    let current_ticks = note
+        .sustain_off_time_axis
+        .unwrap_or(note.note_off_time_axis.unwrap())
+        .ticks_total;
+    if sustain_off_ticks > current_ticks {
+        note.sustain_off_time_axis = Some(tempo_map.time_axis_from_ticks(sustain_off_ticks));
+    }
+}
+
+/// チャンネルごとに、ペダルが踏まれている区間(踏んだtick, 離したtick)を求める
+fn get_pedal_intervals(smf: &Smf, controller: u8, end_ticks: u32) -> Vec<Vec<(u32, u32)>> {
+    // ペダルのイベントは別のトラックに書かれていることもあるので、全トラックから集める
+    let mut pedal_events: Vec<Vec<(u32, bool)>> = vec![Vec::new(); 16];
+    for track in smf.tracks.iter() {
+        let mut ticks_total = 0;
+        for event in track.iter() {
+            ticks_total += event.delta.as_int();
+            if let TrackEventKind::Midi {
+                channel,
+                message:
+                    midly::MidiMessage::Controller {
+                        controller: c,
+                        value,
+                    },
+            } = event.kind
+            {
+                if c.as_int() == controller {
+                    pedal_events[channel.as_int() as usize]
+                        .push((ticks_total, value.as_int() >= 64));
+                }
+            }
+        }
+    }
 
-    return GetTimeAxisReturn { time_axis_vec };
+    pedal_events
+        .iter_mut()
+        .map(|events| {
+            events.sort_by_key(|x| x.0);
+            let mut intervals: Vec<(u32, u32)> = Vec::new();
+            let mut down_ticks: Option<u32> = None;
+            for &(ticks, is_down) in events.iter() {
+                match (down_ticks, is_down) {
+                    (None, true) => down_ticks = Some(ticks),
+                    (Some(start), false) => {
+                        intervals.push((start, ticks));
+                        down_ticks = None;
+                    }
+                    _ => {}
+                }
+            }
+            // 離されないまま終わったペダルは曲の終端まで
+            if let Some(start) = down_ticks {
+                intervals.push((start, end_ticks.max(start)));
+            }
+            intervals
+        })
+        .collect()
 }
 
 struct GetMidiNotesReturn {
     midi_notes: Vec<Vec<MidiNote>>, // channel, notes
+    unsplit_midi_notes: Vec<Vec<MidiNote>>,
+    track_names: Vec<String>,
+    warnings: Vec<String>,
 }
 
-fn get_midi_notes(smf: &Smf, time_axis: &Vec<TimeAxis>) -> GetMidiNotesReturn {
+fn get_midi_notes(
+    smf: &Smf,
+    tempo_map: &TempoMap,
+    ppm: u16,
+    config: &MidiLoaderConfig,
+) -> GetMidiNotesReturn {
     let mut midi_notes: Vec<Vec<MidiNote>> = vec![Vec::new(); 16]; // 16 channels
+    let mut track_names: Vec<String> = Vec::new();
+    let mut warnings: Vec<String> = Vec::new();
 
     // secondsは後でticksを元に計算する
 
-    for track in smf.tracks.iter() {
+    for (track_index, track) in smf.tracks.iter().enumerate() {
         let mut ticks_total = 0;
 
+        // トラック名はノートより後に書かれていることもあるので、先に探しておく
+        let track_name = track
+            .iter()
+            .find_map(|event| match event.kind {
+                TrackEventKind::Meta(MetaMessage::TrackName(name)) => {
+                    Some(String::from_utf8_lossy(name).trim().to_string())
+                }
+                _ => None,
+            })
+            .unwrap_or_default();
+        track_names.push(track_name.clone());
+
         for event in track.iter() {
             ticks_total += event.delta.as_int();
 
@@ -242,33 +505,18 @@ fn get_midi_notes(smf: &Smf, time_axis: &Vec<TimeAxis>) -> GetMidiNotesReturn {
                     message: midly::MidiMessage::NoteOn { key, vel },
                 } => {
                     if vel > 0 {
-                        let note_on_time_axis = time_axis
-                            .iter()
-                            .find(|x| x.ticks_total == ticks_total)
-                            .unwrap();
-
-                        let key_cdefgab = match key.as_int() % 12 {
-                            0 => "C",
-                            1 => "C#",
-                            2 => "D",
-                            3 => "D#",
-                            4 => "E",
-                            5 => "F",
-                            6 => "F#",
-                            7 => "G",
-                            8 => "G#",
-                            9 => "A",
-                            10 => "A#",
-                            11 => "B",
-                            _ => panic!("key error"),
-                        };
+                        let note_on_time_axis = tempo_map.time_axis_from_ticks(ticks_total);
+
+                        // NoteOn時点の調に合わせて、シャープ・フラットを使い分ける
+                        let key_cdefgab = note_on_time_axis.key_name(key.as_int() as u32);
 
                         let key_octave_yamaha = key.as_int() as i32 / 12 - 2;
                         let key_octave_general_midi = key.as_int() as i32 / 12 - 1;
 
                         let midi_note = MidiNote {
-                            note_on_time_axis: note_on_time_axis.clone(),
+                            note_on_time_axis,
                             note_off_time_axis: None,
+                            sustain_off_time_axis: None,
 
                             note_length_ticks: None,
 
@@ -279,54 +527,85 @@ fn get_midi_notes(smf: &Smf, time_axis: &Vec<TimeAxis>) -> GetMidiNotesReturn {
                             key_and_octave_yamaha: format!("{}{}", key_cdefgab, key_octave_yamaha),
                             velocity: vel.as_int() as u32,
                             channel: channel.as_int() as u32,
+                            track: track_index as u32,
+                            track_name: track_name.clone(),
                         };
 
-                        midi_notes[channel.as_int() as usize].push(midi_note);
+                        let notes = &mut midi_notes[channel.as_int() as usize];
+
+                        // 同じキーが鳴っている間に再度NoteOnされた場合、前のノートをここで閉じる
+                        if config.overlapping_note_policy == OverlappingNotePolicy::Retrigger {
+                            for note in notes.iter_mut().filter(|x| {
+                                x.key == midi_note.key && x.note_off_time_axis.is_none()
+                            }) {
+                                close_note(note, tempo_map, ticks_total);
+                            }
+                        }
+
+                        notes.push(midi_note);
                     } else {
                         // it is possibly note off event
-                        let note_on_event = midi_notes[channel.as_int() as usize]
-                            .iter_mut()
-                            .rev()
-                            .find(|x| {
-                                x.key == key.as_int() as u32 && x.note_off_time_axis.is_none()
-                            });
-                        if let Some(note_on_event) = note_on_event {
-                            let note_off_time_axis = time_axis
-                                .iter()
-                                .find(|x| x.ticks_total == ticks_total)
-                                .unwrap();
-                            note_on_event.note_off_time_axis = Some(note_off_time_axis.clone());
-                            note_on_event.note_length_ticks = Some(
-                                note_off_time_axis.ticks_total
-                                    - note_on_event.note_on_time_axis.ticks_total,
-                            );
-                        }
+                        close_matching_note(
+                            &mut midi_notes[channel.as_int() as usize],
+                            key.as_int() as u32,
+                            ticks_total,
+                            tempo_map,
+                            config.overlapping_note_policy,
+                        );
                     }
                 }
                 TrackEventKind::Midi {
                     channel,
                     message: midly::MidiMessage::NoteOff { key, vel: _ },
                 } => {
-                    // search note_on event which has same key and channel and note_off_ticks is None
-                    let note_on_event = midi_notes[channel.as_int() as usize]
-                        .iter_mut()
-                        .rev()
-                        .find(|x| x.key == key.as_int() as u32 && x.note_off_time_axis.is_none());
-                    if let Some(note_on_event) = note_on_event {
-                        let note_off_time_axis = time_axis
-                            .iter()
-                            .find(|x| x.ticks_total == ticks_total)
-                            .unwrap();
-                        note_on_event.note_off_time_axis = Some(note_off_time_axis.clone());
-                        note_on_event.note_length_ticks = Some(
-                            note_off_time_axis.ticks_total
-                                - note_on_event.note_on_time_axis.ticks_total,
-                        );
-                    }
+                    close_matching_note(
+                        &mut midi_notes[channel.as_int() as usize],
+                        key.as_int() as u32,
+                        ticks_total,
+                        tempo_map,
+                        config.overlapping_note_policy,
+                    );
                 }
                 _ => {}
             }
         }
+
+        // NoteOffが無いままトラックが終わったノートを、設定に従って閉じる
+        let end_of_track_ticks = ticks_total;
+        for notes in midi_notes.iter_mut() {
+            for i in 0..notes.len() {
+                if notes[i].note_off_time_axis.is_some() {
+                    continue;
+                }
+
+                let note_on_ticks = notes[i].note_on_time_axis.ticks_total;
+                let note_off_ticks = match config.orphaned_note_policy {
+                    OrphanedNotePolicy::EndOfTrack => end_of_track_ticks,
+                    OrphanedNotePolicy::NextNoteOn => notes
+                        .iter()
+                        .filter(|x| {
+                            x.key == notes[i].key && x.note_on_time_axis.ticks_total > note_on_ticks
+                        })
+                        .map(|x| x.note_on_time_axis.ticks_total)
+                        .min()
+                        .unwrap_or(end_of_track_ticks),
+                    OrphanedNotePolicy::MaxLength => (note_on_ticks
+                        + (config.orphaned_note_max_length_beats * ppm as f32) as u32)
+                        .min(end_of_track_ticks),
+                };
+
+                let note = &mut notes[i];
+                warnings.push(format!(
+                    "ch{} {} (key {}) at tick {} has no NoteOff, closed at tick {}",
+                    note.channel + 1,
+                    note.key_and_octave_yamaha,
+                    note.key,
+                    note_on_ticks,
+                    note_off_ticks
+                ));
+                close_note(note, tempo_map, note_off_ticks);
+            }
+        }
     }
 
     // for debug print ch1 notes
@@ -349,54 +628,100 @@ fn get_midi_notes(smf: &Smf, time_axis: &Vec<TimeAxis>) -> GetMidiNotesReturn {
     // }
     // println!("");
 
+    // ペダルで伸びるノートの余韻を計算する
+    let end_ticks = tempo_map.last_time_axis().ticks_total;
+    if config.sustain_pedal_enabled {
+        let sustain_intervals = get_pedal_intervals(smf, 64, end_ticks);
+        for (notes, intervals) in midi_notes.iter_mut().zip(sustain_intervals.iter()) {
+            for note in notes.iter_mut() {
+                // 離鍵時にペダルが踏まれていれば、ペダルを離すまで鳴る
+                let note_off_ticks = note.note_off_time_axis.unwrap().ticks_total;
+                if let Some(&(_, up_ticks)) = intervals.iter().find(|(down_ticks, up_ticks)| {
+                    *down_ticks <= note_off_ticks && note_off_ticks < *up_ticks
+                }) {
+                    extend_sustain_off(note, tempo_map, up_ticks);
+                }
+            }
+        }
+    }
+    if config.sostenuto_pedal_enabled {
+        let sostenuto_intervals = get_pedal_intervals(smf, 66, end_ticks);
+        for (notes, intervals) in midi_notes.iter_mut().zip(sostenuto_intervals.iter()) {
+            for note in notes.iter_mut() {
+                // ペダルを踏んだ時点で押鍵されていたノートだけが、ペダルを離すまで鳴る
+                let note_on_ticks = note.note_on_time_axis.ticks_total;
+                let note_off_ticks = note.note_off_time_axis.unwrap().ticks_total;
+                if let Some(&(_, up_ticks)) = intervals.iter().find(|(down_ticks, up_ticks)| {
+                    note_on_ticks <= *down_ticks
+                        && *down_ticks < note_off_ticks
+                        && note_off_ticks < *up_ticks
+                }) {
+                    extend_sustain_off(note, tempo_map, up_ticks);
+                }
+            }
+        }
+    }
+
     // 小節を跨ぐノートは分割する
+    // ペダルによる余韻も含めた、描画上の終端で判定する
     let mut new_midi_notes: Vec<Vec<MidiNote>> = Vec::new();
     for channel in midi_notes.iter_mut() {
         let mut new_notes: Vec<MidiNote> = Vec::new();
         for note in channel.iter_mut() {
-            if note.note_on_time_axis.measure == note.note_off_time_axis.unwrap().measure
-                || (note.note_on_time_axis.measure + 1 == note.note_off_time_axis.unwrap().measure
-                    && note.note_off_time_axis.unwrap().beat == 1
-                    && note.note_off_time_axis.unwrap().ticks_reset_by_measure == 0)
+            let visual_off_time_axis = note
+                .sustain_off_time_axis
+                .unwrap_or(note.note_off_time_axis.unwrap());
+            if note.note_on_time_axis.measure == visual_off_time_axis.measure
+                || (note.note_on_time_axis.measure + 1 == visual_off_time_axis.measure
+                    && visual_off_time_axis.beat == 1
+                    && visual_off_time_axis.ticks_reset_by_measure == 0)
             {
                 new_notes.push(note.clone());
             } else {
                 // まず現状のノートを修正
                 let mut current_note = note.clone();
 
-                for current_measure in current_note.note_on_time_axis.measure
-                    ..(current_note.note_off_time_axis.unwrap().measure + 1)
+                for current_measure in
+                    current_note.note_on_time_axis.measure..(visual_off_time_axis.measure + 1)
                 {
-                    if current_note.note_off_time_axis.unwrap().measure == current_measure {
+                    if visual_off_time_axis.measure == current_measure {
                         // 小節を跨ぐノートの最後の小節に該当
                         new_notes.push(current_note.clone());
                     } else {
                         // 小節を跨ぐノートの途中の小説に該当
                         // 末尾を小節内に収めたノートを作成し、current_noteはnote_on系を修正
                         let mut new_note = current_note.clone();
-                        let last_time_axis = time_axis
-                            .iter()
-                            .rev()
-                            .find(|x| x.measure == current_measure)
-                            .unwrap();
-                        new_note.note_off_time_axis = Some(last_time_axis.clone());
+                        let first_ticks_of_next_measure =
+                            tempo_map.ticks_from_measure(current_measure + 1);
+                        let last_time_axis =
+                            tempo_map.time_axis_from_ticks(first_ticks_of_next_measure - 1);
+                        if new_note.note_off_time_axis.unwrap().ticks_total
+                            > last_time_axis.ticks_total
+                        {
+                            new_note.note_off_time_axis = Some(last_time_axis);
+                            new_note.sustain_off_time_axis = None;
+                        } else if new_note.sustain_off_time_axis.is_some() {
+                            // 押鍵はこの小節で終わり、余韻だけが次の小節に続く
+                            new_note.sustain_off_time_axis = Some(last_time_axis);
+                        }
                         new_note.note_length_ticks = Some(
-                            last_time_axis.ticks_total - new_note.note_on_time_axis.ticks_total,
+                            new_note.note_off_time_axis.unwrap().ticks_total
+                                - new_note.note_on_time_axis.ticks_total,
                         );
 
                         new_notes.push(new_note);
 
                         // current_noteを修正
-                        let first_time_axis = time_axis
-                            .iter()
-                            .find(|x| {
-                                x.measure == current_measure + 1
-                                    && x.beat == 1
-                                    && x.ticks_reset_by_beat == 0
-                            })
-                            .unwrap();
-
-                        current_note.note_on_time_axis = first_time_axis.clone();
+                        let first_time_axis =
+                            tempo_map.time_axis_from_ticks(first_ticks_of_next_measure);
+
+                        current_note.note_on_time_axis = first_time_axis;
+                        // 押鍵が前の小節までに終わっている場合は、余韻だけのノートになる
+                        if current_note.note_off_time_axis.unwrap().ticks_total
+                            < first_time_axis.ticks_total
+                        {
+                            current_note.note_off_time_axis = Some(first_time_axis);
+                        }
                         current_note.note_length_ticks = Some(
                             current_note.note_off_time_axis.unwrap().ticks_total
                                 - first_time_axis.ticks_total,
@@ -428,7 +753,113 @@ fn get_midi_notes(smf: &Smf, time_axis: &Vec<TimeAxis>) -> GetMidiNotesReturn {
     //     );
     // }
 
-    return GetMidiNotesReturn {
+    GetMidiNotesReturn {
         midi_notes: new_midi_notes,
-    };
+        unsplit_midi_notes: midi_notes,
+        track_names,
+        warnings,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::global_vars::OverlappingNotePolicy;
+
+    /// test/overlapping_notes/のファイルを読み込み、ch.1のノートを(velocity, NoteOn tick, NoteOff tick)の一覧にする
+    fn load_overlapping_notes(
+        file_name: &str,
+        overlapping_note_policy: OverlappingNotePolicy,
+    ) -> Vec<(u32, u32, u32)> {
+        let midi_file_path = format!(
+            "{}/test/overlapping_notes/{}",
+            env!("CARGO_MANIFEST_DIR"),
+            file_name
+        );
+        let config = MidiLoaderConfig {
+            overlapping_note_policy,
+            ..Default::default()
+        };
+        let loaded_midi_return = load_midi(&midi_file_path, &config).unwrap();
+
+        let mut notes: Vec<(u32, u32, u32)> = loaded_midi_return.midi_notes_vec[0]
+            .iter()
+            .map(|x| {
+                assert_eq!(x.key, 60);
+                (
+                    x.velocity,
+                    x.note_on_time_axis.ticks_total,
+                    x.note_off_time_axis.unwrap().ticks_total,
+                )
+            })
+            .collect();
+        notes.sort_by_key(|x| x.1);
+        notes
+    }
+
+    #[test]
+    fn two_notes() {
+        for file_name in ["two_notes.mid", "two_notes_velocity_zero.mid"] {
+            assert_eq!(
+                load_overlapping_notes(file_name, OverlappingNotePolicy::Fifo),
+                vec![(100, 0, 480), (80, 240, 960)],
+                "{file_name}"
+            );
+            assert_eq!(
+                load_overlapping_notes(file_name, OverlappingNotePolicy::Lifo),
+                vec![(100, 0, 960), (80, 240, 480)],
+                "{file_name}"
+            );
+            assert_eq!(
+                load_overlapping_notes(file_name, OverlappingNotePolicy::Retrigger),
+                vec![(100, 0, 240), (80, 240, 480)],
+                "{file_name}"
+            );
+        }
+    }
+
+    #[test]
+    fn three_notes() {
+        assert_eq!(
+            load_overlapping_notes("three_notes.mid", OverlappingNotePolicy::Fifo),
+            vec![(100, 0, 480), (90, 120, 720), (80, 240, 960)]
+        );
+        assert_eq!(
+            load_overlapping_notes("three_notes.mid", OverlappingNotePolicy::Lifo),
+            vec![(100, 0, 960), (90, 120, 720), (80, 240, 480)]
+        );
+        assert_eq!(
+            load_overlapping_notes("three_notes.mid", OverlappingNotePolicy::Retrigger),
+            vec![(100, 0, 120), (90, 120, 240), (80, 240, 480)]
+        );
+    }
+
+    #[test]
+    fn theme_switch_note_across_barline() {
+        let midi_file_path = format!(
+            "{}/test/theme_switch/across_barline.mid",
+            env!("CARGO_MANIFEST_DIR")
+        );
+        let mut loaded_midi_return =
+            load_midi(&midi_file_path, &MidiLoaderConfig::default()).unwrap();
+        // 小節で分割したノートでは、ch.16のkey 1は2つに分かれている
+        assert_eq!(
+            loaded_midi_return.midi_notes_vec[THEME_SWITCH_CHANNEL].len(),
+            3
+        );
+
+        loaded_midi_return.reserve_theme_switch_channel();
+
+        let theme_change_events: Vec<(u32, usize)> = loaded_midi_return
+            .theme_change_events
+            .iter()
+            .map(|x| (x.time_axis.ticks_total, x.theme_index))
+            .collect();
+        assert_eq!(theme_change_events, vec![(960, 1), (3840, 2)]);
+        assert!(loaded_midi_return.midi_notes_vec[THEME_SWITCH_CHANNEL].is_empty());
+        assert!(loaded_midi_return.unsplit_midi_notes_vec[THEME_SWITCH_CHANNEL].is_empty());
+        // ch.1は切り替えに使わないので、そのまま残る
+        assert_eq!(loaded_midi_return.midi_notes_vec[0].len(), 2);
+        assert_eq!(loaded_midi_return.unsplit_midi_notes_vec[0].len(), 1);
+    }
 }
@@ -0,0 +1,108 @@
+use crate::global_vars::{KeyLaneMode, PianoRollGridConfig, ThemeColor, TimeAxis};
+use crate::tempo_map::TempoMap;
+
+/// 区切り線の種類。小節の先頭は拍の先頭でもあるが、小節の線だけを描画する
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum GridLineKind {
+    Measure,
+    Beat,
+    Subdivision,
+}
+
+impl GridLineKind {
+    /// 設定で描画するかどうかと、その色
+    pub fn theme_color(&self, grid_config: &PianoRollGridConfig) -> Option<ThemeColor> {
+        let (enabled, strength) = match self {
+            GridLineKind::Measure => (grid_config.measure_lines, grid_config.measure_line_strength),
+            GridLineKind::Beat => (grid_config.beat_lines, grid_config.beat_line_strength),
+            GridLineKind::Subdivision => (
+                grid_config.beat_lines && grid_config.subdivisions > 1,
+                grid_config.subdivision_line_strength,
+            ),
+        };
+        enabled.then_some(ThemeColor::Grid { strength })
+    }
+
+    /// 強い線ほど手前に描画する。ノートや余韻(0.5以上)よりは奥
+    pub fn z(&self) -> f32 {
+        match self {
+            GridLineKind::Measure => 0.3,
+            GridLineKind::Beat => 0.2,
+            GridLineKind::Subdivision => 0.1,
+        }
+    }
+}
+
+pub struct GridLine {
+    pub time_axis: TimeAxis,
+    pub kind: GridLineKind,
+}
+
+/// start_ticksからend_ticksまで(両端を含む)の区切り線を、小節ごとの拍子に合わせて並べる
+pub fn grid_lines(
+    tempo_map: &TempoMap,
+    subdivisions: u32,
+    start_ticks: u32,
+    end_ticks: u32,
+) -> Vec<GridLine> {
+    let subdivisions = subdivisions.max(1);
+    let mut grid_lines = Vec::new();
+    let mut measure = tempo_map.time_axis_from_ticks(start_ticks).measure;
+    loop {
+        let measure_start_ticks = tempo_map.ticks_from_measure(measure);
+        let next_measure_start_ticks = tempo_map.ticks_from_measure(measure + 1);
+        if measure_start_ticks > end_ticks || next_measure_start_ticks <= measure_start_ticks {
+            break;
+        }
+        let beat_length_ticks = tempo_map
+            .time_axis_from_ticks(measure_start_ticks)
+            .beat_length_ticks
+            .max(1);
+
+        // 小節の途中で拍子が変わっても、次の小節の先頭で揃え直す
+        for beat_start_ticks in
+            (measure_start_ticks..next_measure_start_ticks).step_by(beat_length_ticks as usize)
+        {
+            for i in 0..subdivisions {
+                let ticks = beat_start_ticks + beat_length_ticks * i / subdivisions;
+                if ticks >= next_measure_start_ticks {
+                    break;
+                }
+                if !(start_ticks..=end_ticks).contains(&ticks) {
+                    continue;
+                }
+                let kind = match (ticks == measure_start_ticks, i == 0) {
+                    (true, _) => GridLineKind::Measure,
+                    (false, true) => GridLineKind::Beat,
+                    (false, false) => GridLineKind::Subdivision,
+                };
+                grid_lines.push(GridLine {
+                    time_axis: tempo_map.time_axis_from_ticks(ticks),
+                    kind,
+                });
+            }
+        }
+        measure += 1;
+    }
+    grid_lines
+}
+
+pub fn is_black_key(key: u32) -> bool {
+    matches!(key % 12, 1 | 3 | 6 | 8 | 10)
+}
+
+/// 設定に応じて、keyのレーンに色を付けるかどうか
+pub fn is_key_lane_shaded(key_lanes: KeyLaneMode, key: u32) -> bool {
+    match key_lanes {
+        KeyLaneMode::None => false,
+        KeyLaneMode::BlackKeys => is_black_key(key),
+        KeyLaneMode::Octaves => key.is_multiple_of(12),
+    }
+}
+
+/// keyのレーンの色
+pub fn key_lane_theme_color(grid_config: &PianoRollGridConfig) -> ThemeColor {
+    ThemeColor::Grid {
+        strength: grid_config.key_lane_strength,
+    }
+}
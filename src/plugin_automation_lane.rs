@@ -0,0 +1,258 @@
+use crate::global_vars::{
+    ActiveTheme, AutomationLaneKind, ConfigReloaded, GlobalMonitorValues, GlobalSettings,
+    LookAheadUnit, PianoRollViewMode, RectangleFromFeatureLayoutChild, ThemeColor, TimeAxis,
+};
+use crate::plugin_config_hot_reload::despawn_with;
+use crate::plugin_midi_note_animater::PianoRollPages;
+use bevy::prelude::*;
+use bevy::render::view::RenderLayers;
+use bevy::sprite::Anchor;
+
+pub struct AutomationLanePlugin;
+
+impl Plugin for AutomationLanePlugin {
+    fn build(&self, app: &mut App) {
+        app.add_systems(PostStartup, setup)
+            .add_systems(Update, draw_automation_lanes)
+            .add_systems(
+                Update,
+                (despawn_with::<AutomationLaneRoot>, setup)
+                    .chain()
+                    .run_if(on_event::<ConfigReloaded>),
+            );
+    }
+}
+
+#[derive(Component)]
+struct AutomationLaneRoot;
+
+fn setup(
+    mut commands: Commands,
+    global_settings: Res<GlobalSettings>,
+    active_theme: Res<ActiveTheme>,
+    asset_server: Res<AssetServer>,
+) {
+    let Some(automation_lane_layout) = global_settings
+        .config
+        .feature_and_layout
+        .automation_lane
+        .calculate_rect(
+            global_settings.config.main_config.window_width,
+            global_settings.config.main_config.window_height,
+        )
+    else {
+        return;
+    };
+
+    let font: Handle<Font> = asset_server.load("fonts\\NotoSansJP-Thin.ttf");
+    let color = active_theme.color(&global_settings.config.theme, ThemeColor::Main(1));
+
+    let lanes = &global_settings.config.automation_lane.lanes;
+    let lane_height = automation_lane_layout.height_pixel / lanes.len().max(1) as f32;
+
+    commands
+        .spawn((
+            AutomationLaneRoot,
+            Transform::from_xyz(
+                automation_lane_layout.left_bottom_abs_pixel.0,
+                automation_lane_layout.left_bottom_abs_pixel.1,
+                0.0,
+            ),
+            Visibility::default(),
+            RenderLayers::layer(0),
+            automation_lane_layout.clone(),
+        ))
+        .with_children(|parent| {
+            // レーン名は各レーンの左上に表示する
+            for (lane_index, lane_kind) in lanes.iter().enumerate() {
+                parent.spawn((
+                    Transform::from_xyz(
+                        0.0,
+                        automation_lane_layout.height_pixel - lane_height * lane_index as f32,
+                        1.0,
+                    ),
+                    GlobalTransform::default(),
+                    Text2d::new(String::from(*lane_kind)),
+                    TextFont {
+                        font: font.clone(),
+                        font_size: 16.0,
+                        ..default()
+                    },
+                    TextColor(color),
+                    ThemeColor::Main(1),
+                    Anchor::TopLeft,
+                ));
+            }
+        });
+}
+
+/// レーンの値を0.0 ~ 1.0に正規化する
+fn normalize_lane_value(lane_kind: AutomationLaneKind, value: i32) -> f32 {
+    match lane_kind {
+        AutomationLaneKind::PitchBend => (value + 8192) as f32 / 16383.0,
+        _ => value as f32 / 127.0,
+    }
+}
+
+/// 曲頭から最初のイベントまでの値
+fn default_lane_value(lane_kind: AutomationLaneKind) -> i32 {
+    match lane_kind {
+        AutomationLaneKind::ControlChange(7) => 100, // ボリューム
+        AutomationLaneKind::ControlChange(10) => 64, // パン
+        AutomationLaneKind::ControlChange(11) => 127, // エクスプレッション
+        _ => 0,
+    }
+}
+
+/// レーンの横幅いっぱいに表示する範囲。ピアノロールに表示している範囲に合わせる
+struct TimeWindow {
+    start_position: f64,
+    end_position: f64,
+    unit: LookAheadUnit, // Beatsの場合はtick、Secondsの場合は秒の軸
+}
+
+impl TimeWindow {
+    fn new(
+        global_settings: &GlobalSettings,
+        global_monitor_values: &GlobalMonitorValues,
+        pages: &PianoRollPages,
+    ) -> Self {
+        let piano_roll_config = &global_settings.config.piano_roll;
+        let tempo_map = &global_settings.tempo_map;
+        let unit = piano_roll_config.look_ahead_unit;
+        let current_position = match unit {
+            LookAheadUnit::Beats => tempo_map.exact_ticks_from_seconds(
+                global_monitor_values.elapsed_time_from_start.elapsed_secs(),
+            ),
+            LookAheadUnit::Seconds => {
+                global_monitor_values.elapsed_time_from_start.elapsed_secs() as f64
+            }
+        };
+        let look_ahead_units = match unit {
+            LookAheadUnit::Beats => {
+                piano_roll_config.look_ahead as f64 * global_settings.ppm as f64
+            }
+            LookAheadUnit::Seconds => piano_roll_config.look_ahead as f64,
+        };
+
+        match piano_roll_config.view_mode {
+            // 現在のページの先頭の小節から、最後の小節の終わりまで
+            PianoRollViewMode::Page => {
+                let page_index = pages.page_index(global_monitor_values.current_time_axis.measure);
+                let (start_ticks, end_ticks) = pages.ticks_range(tempo_map, page_index);
+                Self {
+                    start_position: start_ticks as f64,
+                    end_position: end_ticks as f64,
+                    unit: LookAheadUnit::Beats,
+                }
+            }
+            // 再生ヘッドより左に見えている分も含める
+            PianoRollViewMode::Scroll => {
+                let playhead_percent = piano_roll_config.playhead_percent as f64;
+                Self {
+                    start_position: current_position
+                        - look_ahead_units * playhead_percent / (100.0 - playhead_percent),
+                    end_position: current_position + look_ahead_units,
+                    unit,
+                }
+            }
+            // 鍵盤に届いたノートはすぐに隠れるので、再生位置から先だけ
+            PianoRollViewMode::Falling => Self {
+                start_position: current_position,
+                end_position: current_position + look_ahead_units,
+                unit,
+            },
+        }
+    }
+
+    fn position_of(&self, time_axis: &TimeAxis) -> f64 {
+        match self.unit {
+            LookAheadUnit::Beats => time_axis.ticks_total as f64,
+            LookAheadUnit::Seconds => time_axis.seconds_total as f64,
+        }
+    }
+}
+
+fn draw_automation_lanes(
+    mut gizmos: Gizmos,
+    global_monitor_values: Res<GlobalMonitorValues>,
+    global_settings: Res<GlobalSettings>,
+    active_theme: Res<ActiveTheme>,
+    pages: Res<PianoRollPages>,
+    query: Query<&RectangleFromFeatureLayoutChild, With<AutomationLaneRoot>>,
+) {
+    let config = &global_settings.config.automation_lane;
+    let guide_color = active_theme.color(&global_settings.config.theme, ThemeColor::Main(3));
+
+    let time_window = TimeWindow::new(&global_settings, &global_monitor_values, &pages);
+    // 小節の長さが0のまま、などで範囲が空になる場合は描画しない
+    if time_window.end_position <= time_window.start_position {
+        return;
+    }
+
+    for layout in query.iter() {
+        let (left, bottom) = layout.left_bottom_abs_pixel;
+        let right = left + layout.width_pixel;
+        let width_per_unit =
+            layout.width_pixel as f64 / (time_window.end_position - time_window.start_position);
+        let lane_height = layout.height_pixel / config.lanes.len().max(1) as f32;
+
+        for (lane_index, lane_kind) in config.lanes.iter().enumerate() {
+            let lane_bottom = bottom + layout.height_pixel - lane_height * (lane_index + 1) as f32;
+            let y_of_value = |value: i32| {
+                lane_bottom + normalize_lane_value(*lane_kind, value) * lane_height * 0.9
+            };
+
+            gizmos.line_2d(
+                Vec2::new(left, lane_bottom),
+                Vec2::new(right, lane_bottom),
+                guide_color,
+            );
+
+            for &channel in &config.channels {
+                let Some(midi_control_lanes) = channel
+                    .checked_sub(1)
+                    .and_then(|x| global_settings.midi_control_lanes_vec.get(x as usize))
+                else {
+                    continue;
+                };
+                let points = midi_control_lanes.get(*lane_kind);
+                if points.is_empty() {
+                    continue;
+                }
+
+                // 左端での値は、それ以前で最後のイベントの値
+                let start_index = points.partition_point(|x| {
+                    time_window.position_of(&x.time_axis) <= time_window.start_position
+                });
+                let mut value = match start_index {
+                    0 => default_lane_value(*lane_kind),
+                    _ => points[start_index - 1].value,
+                };
+
+                // 次のイベントまで値を保持する階段状の線にする
+                let mut positions = vec![Vec2::new(left, y_of_value(value))];
+                for point in points[start_index..].iter().take_while(|x| {
+                    time_window.position_of(&x.time_axis) < time_window.end_position
+                }) {
+                    let x = left
+                        + ((time_window.position_of(&point.time_axis) - time_window.start_position)
+                            * width_per_unit) as f32;
+                    positions.push(Vec2::new(x, y_of_value(value)));
+                    value = point.value;
+                    positions.push(Vec2::new(x, y_of_value(value)));
+                }
+                positions.push(Vec2::new(right, y_of_value(value)));
+
+                let color = active_theme.color(
+                    &global_settings.config.theme,
+                    ThemeColor::NoteChannel {
+                        group_index: channel - 1,
+                        group_count: global_settings.midi_control_lanes_vec.len() as u32,
+                    },
+                );
+                gizmos.linestrip_2d(positions, color);
+            }
+        }
+    }
+}
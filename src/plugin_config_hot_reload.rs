@@ -0,0 +1,150 @@
+use crate::cli::CliArgs;
+use crate::config_controller::{self, ConfigSeverity};
+use crate::global_vars::{
+    ActiveTheme, Config, ConfigReloaded, GlobalSettings, MainWindowCamera, ThemeColor,
+};
+use bevy::prelude::*;
+use bevy::window::{PrimaryWindow, WindowResolution};
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::SystemTime;
+
+/// config.tomlの変更を監視し、MIDIを読み込み直さずに表示だけを作り直す
+///
+/// midi_file_path・[midi_loader]・theme_switch.enabledの変更は、MidiHotReloadPluginが見つけて読み込み直す。
+///
+/// 読み込みに成功すると`ConfigReloaded`を送り、各プラグインはそれを受けて表示を作り直す。
+/// 失敗した場合は前の設定のまま、エラーを画面に重ねて表示する。
+pub struct ConfigHotReloadPlugin {
+    pub cli_args: CliArgs, // 読み込み直した設定にも、コマンドライン引数の上書きを適用する
+}
+
+impl Plugin for ConfigHotReloadPlugin {
+    fn build(&self, app: &mut App) {
+        let config_path = PathBuf::from(&self.cli_args.config_path);
+        app.insert_resource(ConfigHotReload {
+            last_modified: get_modified(&config_path),
+            config_path,
+            cli_args: self.cli_args.clone(),
+            timer: Timer::from_seconds(0.5, TimerMode::Repeating),
+        })
+        .add_systems(PreUpdate, watch_config_file)
+        .add_systems(Update, apply_main_config.run_if(on_event::<ConfigReloaded>));
+    }
+}
+
+#[derive(Resource)]
+struct ConfigHotReload {
+    config_path: PathBuf,
+    cli_args: CliArgs,
+    last_modified: Option<SystemTime>,
+    timer: Timer, // ファイルの更新日時を確認する間隔
+}
+
+#[derive(Component)]
+struct ConfigErrorOverlay;
+
+fn get_modified(config_path: &Path) -> Option<SystemTime> {
+    fs::metadata(config_path).and_then(|x| x.modified()).ok()
+}
+
+/// 設定を読み込み、コマンドライン引数の上書きと検証を行う。失敗した場合はエラーの一覧を返す
+fn reload_config(config_hot_reload: &ConfigHotReload) -> Result<Config, Vec<String>> {
+    let mut config = config_controller::load_config(&config_hot_reload.config_path)
+        .map_err(|e| vec![e.to_string()])?;
+    config_hot_reload.cli_args.apply(&mut config);
+
+    let mut errors = Vec::new();
+    for config_problem in config_controller::validate_config(&config) {
+        match config_problem.severity {
+            ConfigSeverity::Error => errors.push(config_problem.to_string()),
+            ConfigSeverity::Warning => warn!("{}", config_problem),
+        }
+    }
+    match errors.is_empty() {
+        true => Ok(config),
+        false => Err(errors),
+    }
+}
+
+#[allow(clippy::too_many_arguments)]
+fn watch_config_file(
+    mut commands: Commands,
+    time: Res<Time<Real>>,
+    mut config_hot_reload: ResMut<ConfigHotReload>,
+    mut global_settings: ResMut<GlobalSettings>,
+    active_theme: Res<ActiveTheme>,
+    mut config_reloaded: EventWriter<ConfigReloaded>,
+    query_overlay: Query<Entity, With<ConfigErrorOverlay>>,
+    query_camera: Query<Entity, With<MainWindowCamera>>,
+) {
+    if !config_hot_reload.timer.tick(time.delta()).just_finished() {
+        return;
+    }
+    let modified = get_modified(&config_hot_reload.config_path);
+    if modified == config_hot_reload.last_modified {
+        return;
+    }
+    config_hot_reload.last_modified = modified;
+
+    for entity in query_overlay.iter() {
+        commands.entity(entity).despawn_recursive();
+    }
+
+    match reload_config(&config_hot_reload) {
+        Ok(config) => {
+            info!(
+                "reloaded config {}",
+                config_hot_reload.config_path.display()
+            );
+            global_settings.config = config;
+            config_reloaded.send(ConfigReloaded);
+        }
+        Err(errors) => {
+            for error in &errors {
+                error!("{}", error);
+            }
+
+            // 前の設定のまま動かし続け、エラーを画面に重ねて表示する
+            let text_color = active_theme.color(&global_settings.config.theme, ThemeColor::Accent);
+            commands.spawn((
+                ConfigErrorOverlay,
+                ThemeColor::Accent,
+                Node {
+                    position_type: PositionType::Absolute,
+                    right: Val::Px(20.0),
+                    top: Val::Px(20.0),
+                    ..default()
+                },
+                Text::new(format!(
+                    "Failed to reload {}\n{}",
+                    config_hot_reload.config_path.display(),
+                    errors.join("\n")
+                )),
+                TextColor(text_color),
+                TargetCamera(query_camera.single()),
+            ));
+        }
+    }
+}
+
+/// ウィンドウサイズを、読み込み直した設定に合わせる。背景色はThemeSwitchPluginが塗り替える
+fn apply_main_config(
+    global_settings: Res<GlobalSettings>,
+    mut windows: Query<&mut Window, With<PrimaryWindow>>,
+) {
+    let config = &global_settings.config;
+    for mut window in windows.iter_mut() {
+        window.resolution = WindowResolution::new(
+            config.main_config.window_width as f32,
+            config.main_config.window_height as f32,
+        );
+    }
+}
+
+/// 表示を作り直す前に、指定したコンポーネントを持つエンティティを子ごと削除する
+pub fn despawn_with<T: Component>(mut commands: Commands, query: Query<Entity, With<T>>) {
+    for entity in query.iter() {
+        commands.entity(entity).despawn_recursive();
+    }
+}
@@ -0,0 +1,407 @@
+use crate::global_vars::{
+    ActiveTheme, AppState, ConfigReloaded, GlobalMonitorValues, GlobalSettings, KeyRange,
+    LookAheadUnit, MainWindowCamera, MidiReloaded, PianoRollViewMode, ThemeColor, TimeAxis,
+};
+use crate::piano_roll_grid::{self, is_black_key};
+use crate::plugin_config_hot_reload::despawn_with;
+use crate::tempo_map::TempoMap;
+use bevy::prelude::*;
+use bevy::render::view::RenderLayers;
+
+/// piano_roll.view_mode = "falling" のときに、下端の鍵盤に向かってノートが落ちてくるピアノロール
+///
+/// 鍵盤は鳴っているノートのグループの色で光る。
+/// ノートは鍵盤の上端に触れたところから隠れていき、鍵盤に重ならない。
+pub struct FallingNotesPlugin;
+
+impl Plugin for FallingNotesPlugin {
+    fn build(&self, app: &mut App) {
+        app.add_systems(PostStartup, setup)
+            .add_systems(Update, (update_falling_notes, update_piano_keys))
+            .add_systems(
+                Update,
+                (despawn_with::<FallingNotesRoot>, setup)
+                    .chain()
+                    .run_if(on_event::<ConfigReloaded>.or(on_event::<MidiReloaded>)),
+            );
+    }
+}
+
+#[derive(Component)]
+struct FallingNotesRoot;
+
+/// ルートに持たせる、落下の軸とピクセルの対応
+#[derive(Component)]
+struct FallingView {
+    height: f32,
+    keyboard_height: f32,
+    pixels_per_unit: f64, // 1tick、または1秒あたりのピクセル数
+    unit: LookAheadUnit,
+}
+
+impl FallingView {
+    /// 経過秒数を、落下の軸上の位置に変換する
+    fn position_at(&self, tempo_map: &TempoMap, seconds: f32) -> f64 {
+        match self.unit {
+            LookAheadUnit::Beats => tempo_map.exact_ticks_from_seconds(seconds),
+            LookAheadUnit::Seconds => seconds as f64,
+        }
+    }
+
+    fn position_of(&self, time_axis: &TimeAxis) -> f64 {
+        match self.unit {
+            LookAheadUnit::Beats => time_axis.ticks_total as f64,
+            LookAheadUnit::Seconds => time_axis.seconds_total as f64,
+        }
+    }
+}
+
+/// ノートの、落下の軸上での開始位置と生成したときの高さ
+#[derive(Component)]
+struct FallingNote {
+    start_position: f64,
+    full_height: f32,
+}
+
+/// 鍵盤。光っていないときの色に戻せるように、元の色を持つ
+#[derive(Component)]
+struct PianoKey {
+    key: u32,
+    base_theme_color: ThemeColor,
+}
+
+/// 鍵盤の横方向の配置。白鍵は同じ幅で並べ、黒鍵は両隣の白鍵の境目に置く
+struct KeyboardLayout {
+    key_range: KeyRange,
+    white_key_width: f32,
+    black_key_width: f32,
+}
+
+impl KeyboardLayout {
+    /// 両端が黒鍵の場合は、外側の白鍵まで広げる
+    fn new(width: f32, key_range: KeyRange) -> Self {
+        let key_range = KeyRange {
+            min_key: match is_black_key(key_range.min_key) {
+                true => key_range.min_key - 1,
+                false => key_range.min_key,
+            },
+            max_key: match is_black_key(key_range.max_key) {
+                true => key_range.max_key + 1,
+                false => key_range.max_key,
+            },
+        };
+        let white_key_count = (key_range.min_key..=key_range.max_key)
+            .filter(|x| !is_black_key(*x))
+            .count();
+        let white_key_width = width / white_key_count as f32;
+        Self {
+            key_range,
+            white_key_width,
+            black_key_width: white_key_width * 0.6,
+        }
+    }
+
+    /// 最低音からこのキーの手前までにある白鍵の数
+    fn white_keys_before(&self, key: u32) -> u32 {
+        (self.key_range.min_key..key)
+            .filter(|x| !is_black_key(*x))
+            .count() as u32
+    }
+
+    /// キーの中心のx座標と幅
+    fn key_center_and_width(&self, key: u32) -> (f32, f32) {
+        match is_black_key(key) {
+            true => (
+                self.white_keys_before(key) as f32 * self.white_key_width,
+                self.black_key_width,
+            ),
+            false => (
+                (self.white_keys_before(key) as f32 + 0.5) * self.white_key_width,
+                self.white_key_width,
+            ),
+        }
+    }
+}
+
+fn setup(
+    mut commands: Commands,
+    query_camera: Query<Entity, With<MainWindowCamera>>,
+    global_settings: Res<GlobalSettings>,
+    active_theme: Res<ActiveTheme>,
+    mut meshes: ResMut<Assets<Mesh>>,
+    mut materials: ResMut<Assets<ColorMaterial>>,
+) {
+    let piano_roll_config = &global_settings.config.piano_roll;
+    if piano_roll_config.view_mode != PianoRollViewMode::Falling {
+        return;
+    }
+    let Some(piano_roll_layout) = global_settings
+        .config
+        .feature_and_layout
+        .piano_roll
+        .calculate_rect(
+            global_settings.config.main_config.window_width,
+            global_settings.config.main_config.window_height,
+        )
+    else {
+        return;
+    };
+    let main_window_camera = query_camera.single();
+    let themes = &global_settings.config.theme;
+
+    let height = piano_roll_layout.height_pixel;
+    let keyboard_height = height * piano_roll_config.keyboard_height_percent / 100.0;
+    let keyboard_layout =
+        KeyboardLayout::new(piano_roll_layout.width_pixel, global_settings.key_range());
+    let look_ahead_units = match piano_roll_config.look_ahead_unit {
+        LookAheadUnit::Beats => piano_roll_config.look_ahead as f64 * global_settings.ppm as f64,
+        LookAheadUnit::Seconds => piano_roll_config.look_ahead as f64,
+    };
+    let falling_view = FallingView {
+        height,
+        keyboard_height,
+        pixels_per_unit: (height - keyboard_height) as f64 / look_ahead_units,
+        unit: piano_roll_config.look_ahead_unit,
+    };
+
+    let mut children = Vec::new();
+
+    // 白鍵の上に黒鍵を重ねる
+    let key_range = keyboard_layout.key_range;
+    for key in key_range.min_key..=key_range.max_key {
+        let (x, width) = keyboard_layout.key_center_and_width(key);
+        let (key_height, z, base_theme_color) = match is_black_key(key) {
+            true => (keyboard_height * 0.62, 0.5, ThemeColor::Main(3)),
+            false => (keyboard_height, 0.0, ThemeColor::Main(0)),
+        };
+        children.push(
+            commands
+                .spawn((
+                    PianoKey {
+                        key,
+                        base_theme_color,
+                    },
+                    Transform::from_xyz(x, keyboard_height - key_height / 2.0, z),
+                    GlobalTransform::default(),
+                    // 隣の鍵盤と区別できるように、1px狭くする
+                    Mesh2d(meshes.add(Rectangle::new((width - 1.0).max(1.0), key_height))),
+                    MeshMaterial2d(materials.add(active_theme.color(themes, base_theme_color))),
+                    base_theme_color,
+                    Visibility::Inherited,
+                    TargetCamera(main_window_camera),
+                ))
+                .id(),
+        );
+    }
+
+    // ノートの奥に、keyのレーンと区切り線を描画する
+    let grid_config = &global_settings.config.piano_roll_grid;
+    let lane_height = height - keyboard_height;
+    for key in key_range.min_key..=key_range.max_key {
+        if !piano_roll_grid::is_key_lane_shaded(grid_config.key_lanes, key) {
+            continue;
+        }
+        let (x, width) = keyboard_layout.key_center_and_width(key);
+        let theme_color = piano_roll_grid::key_lane_theme_color(grid_config);
+        children.push(
+            commands
+                .spawn((
+                    Transform::from_xyz(x, keyboard_height + lane_height / 2.0, 0.0),
+                    GlobalTransform::default(),
+                    Mesh2d(meshes.add(Rectangle::new(width, lane_height))),
+                    MeshMaterial2d(materials.add(active_theme.color(themes, theme_color))),
+                    theme_color,
+                    Visibility::Inherited,
+                    TargetCamera(main_window_camera),
+                ))
+                .id(),
+        );
+    }
+    let tempo_map = &global_settings.tempo_map;
+    for grid_line in piano_roll_grid::grid_lines(
+        tempo_map,
+        grid_config.subdivisions,
+        0,
+        tempo_map.last_time_axis().ticks_total,
+    ) {
+        let Some(theme_color) = grid_line.kind.theme_color(grid_config) else {
+            continue;
+        };
+        // 区切り線も、ノートと同じように落とす
+        children.push(
+            commands
+                .spawn((
+                    FallingNote {
+                        start_position: falling_view.position_of(&grid_line.time_axis),
+                        full_height: 1.0,
+                    },
+                    Transform::from_xyz(
+                        piano_roll_layout.width_pixel / 2.0,
+                        0.0,
+                        grid_line.kind.z(),
+                    ),
+                    GlobalTransform::default(),
+                    Mesh2d(meshes.add(Rectangle::new(piano_roll_layout.width_pixel, 1.0))),
+                    MeshMaterial2d(materials.add(active_theme.color(themes, theme_color))),
+                    theme_color,
+                    Visibility::Hidden,
+                    TargetCamera(main_window_camera),
+                ))
+                .id(),
+        );
+    }
+
+    let note_group_count = global_settings.note_group_count();
+    // 小節で分割したノートでは小節線の所に隙間ができるので、分割前のノートを1本の矩形にする
+    for midi_note in global_settings.unsplit_midi_notes_vec.iter().flatten() {
+        // 設定で非表示にしたグループと、鍵盤の範囲外のノートは生成しない
+        let note_group_index = global_settings.note_group_index(midi_note);
+        if !global_settings.is_note_group_visible(note_group_index)
+            || !key_range.contains(midi_note.key)
+        {
+            continue;
+        }
+        let Some(note_off_time_axis) = midi_note.note_off_time_axis else {
+            continue;
+        };
+
+        let start_position = falling_view.position_of(&midi_note.note_on_time_axis);
+        let full_height = (((falling_view.position_of(&note_off_time_axis) - start_position)
+            * falling_view.pixels_per_unit) as f32)
+            .max(1.0);
+        let (x, width) = keyboard_layout.key_center_and_width(midi_note.key);
+        // 黒鍵のノートは白鍵のノートより手前に描画する
+        let z = (note_group_count - note_group_index) as f32
+            + match is_black_key(midi_note.key) {
+                true => note_group_count as f32,
+                false => 0.0,
+            };
+        let theme_color = ThemeColor::NoteChannel {
+            group_index: note_group_index,
+            group_count: note_group_count,
+        };
+        children.push(
+            commands
+                .spawn((
+                    FallingNote {
+                        start_position,
+                        full_height,
+                    },
+                    Transform::from_xyz(x, 0.0, z),
+                    GlobalTransform::default(),
+                    Mesh2d(meshes.add(Rectangle::new((width - 2.0).max(1.0), full_height))),
+                    MeshMaterial2d(materials.add(active_theme.color(themes, theme_color))),
+                    theme_color,
+                    Visibility::Hidden,
+                    TargetCamera(main_window_camera),
+                ))
+                .id(),
+        );
+    }
+
+    commands
+        .spawn((
+            FallingNotesRoot,
+            Transform::from_xyz(
+                piano_roll_layout.left_bottom_abs_pixel.0,
+                piano_roll_layout.left_bottom_abs_pixel.1,
+                0.0,
+            ),
+            Visibility::default(),
+            RenderLayers::layer(0),
+            falling_view,
+            piano_roll_layout,
+        ))
+        .add_children(&children);
+}
+
+/// 再生位置に合わせてノートを落とし、鍵盤とピアノロールの上端からはみ出る部分を隠す
+fn update_falling_notes(
+    global_settings: Res<GlobalSettings>,
+    global_monitor_values: Res<GlobalMonitorValues>,
+    query_root: Query<&FallingView, With<FallingNotesRoot>>,
+    mut query: Query<(&FallingNote, &mut Transform, &mut Visibility)>,
+) {
+    let Ok(falling_view) = query_root.get_single() else {
+        return;
+    };
+    let current_position = falling_view.position_at(
+        &global_settings.tempo_map,
+        global_monitor_values.elapsed_time_from_start.elapsed_secs(),
+    );
+
+    for (falling_note, mut transform, mut visibility) in query.iter_mut() {
+        let y_start = falling_view.keyboard_height
+            + ((falling_note.start_position - current_position) * falling_view.pixels_per_unit)
+                as f32;
+        let y_end = y_start + falling_note.full_height;
+        let bottom = y_start.max(falling_view.keyboard_height);
+        let top = y_end.min(falling_view.height);
+        if top <= bottom {
+            *visibility = Visibility::Hidden;
+            continue;
+        }
+        *visibility = Visibility::Inherited;
+        transform.scale.y = (top - bottom) / falling_note.full_height;
+        transform.translation.y = (bottom + top) / 2.0;
+    }
+}
+
+/// 鳴っているノートの鍵盤を、そのノートのグループの色にする
+fn update_piano_keys(
+    global_settings: Res<GlobalSettings>,
+    global_monitor_values: Res<GlobalMonitorValues>,
+    app_state: Res<State<AppState>>,
+    active_theme: Res<ActiveTheme>,
+    mut materials: ResMut<Assets<ColorMaterial>>,
+    mut query: Query<(&PianoKey, &mut ThemeColor, &MeshMaterial2d<ColorMaterial>)>,
+) {
+    if query.is_empty() {
+        return;
+    }
+
+    // 同じキーで重なっている場合は、後から鳴ったノートの色にする
+    let current_ticks = global_monitor_values.current_time_axis.ticks_total;
+    let mut sounding: Vec<Option<(u32, ThemeColor)>> = vec![None; 128];
+    if app_state.get() != &AppState::Stop {
+        let note_group_count = global_settings.note_group_count();
+        // 小節線で一瞬離鍵したように見えないように、分割前のノートで判定する
+        for midi_note in global_settings.unsplit_midi_notes_vec.iter().flatten() {
+            let note_on_ticks = midi_note.note_on_time_axis.ticks_total;
+            let is_sounding = note_on_ticks <= current_ticks
+                && midi_note
+                    .note_off_time_axis
+                    .is_some_and(|x| current_ticks < x.ticks_total);
+            let note_group_index = global_settings.note_group_index(midi_note);
+            if !is_sounding || !global_settings.is_note_group_visible(note_group_index) {
+                continue;
+            }
+            let Some(slot) = sounding.get_mut(midi_note.key as usize) else {
+                continue;
+            };
+            if slot.is_none_or(|(ticks, _)| ticks <= note_on_ticks) {
+                *slot = Some((
+                    note_on_ticks,
+                    ThemeColor::NoteChannel {
+                        group_index: note_group_index,
+                        group_count: note_group_count,
+                    },
+                ));
+            }
+        }
+    }
+
+    for (piano_key, mut theme_color, mesh_material) in query.iter_mut() {
+        let next_theme_color = sounding[piano_key.key as usize]
+            .map(|(_, x)| x)
+            .unwrap_or(piano_key.base_theme_color);
+        if *theme_color == next_theme_color {
+            continue;
+        }
+        // テーマの切り替え中も、ThemeSwitchPluginがこの色で塗り替える
+        *theme_color = next_theme_color;
+        if let Some(material) = materials.get_mut(&mesh_material.0) {
+            material.color = active_theme.color(&global_settings.config.theme, next_theme_color);
+        }
+    }
+}
@@ -0,0 +1,293 @@
+use crate::global_vars::{
+    ActiveTheme, ConfigReloaded, GlobalMonitorValues, GlobalSettings, MidiReloaded, MidiTextEvent,
+    MidiTextKind, ThemeColor,
+};
+use crate::plugin_config_hot_reload::despawn_with;
+use bevy::prelude::*;
+use bevy::render::view::RenderLayers;
+use bevy::sprite::Anchor;
+
+pub struct LyricsPlugin;
+
+impl Plugin for LyricsPlugin {
+    fn build(&self, app: &mut App) {
+        app.add_systems(PostStartup, setup)
+            .add_systems(
+                Update,
+                (update_marker_text, update_lyrics_text).run_if(resource_exists::<LyricsTimeline>),
+            )
+            .add_systems(
+                Update,
+                (despawn_with::<LyricsRoot>, remove_lyrics_timeline, setup)
+                    .chain()
+                    .run_if(on_event::<ConfigReloaded>.or(on_event::<MidiReloaded>)),
+            );
+    }
+}
+
+/// 表示用に整理したテキスト系メタイベント
+#[derive(Resource)]
+struct LyricsTimeline {
+    markers: Vec<(u32, String)>,           // (tick, マーカー名)
+    lyrics_lines: Vec<Vec<(u32, String)>>, // 行ごとの(tick, 音節)
+}
+
+#[derive(Component)]
+struct LyricsRoot;
+
+#[derive(Component)]
+struct MarkerText;
+
+#[derive(Component)]
+struct LyricsSungText;
+
+#[derive(Component)]
+struct LyricsUnsungText;
+
+#[derive(Component)]
+struct LyricsNextLineText;
+
+/// 歌詞を行に分ける
+fn get_lyrics_lines(midi_text_events: &[MidiTextEvent]) -> Vec<Vec<(u32, String)>> {
+    // Lyricイベントが無い場合は、KAR形式のTextイベントを歌詞として扱う
+    let has_lyric = midi_text_events
+        .iter()
+        .any(|x| x.kind == MidiTextKind::Lyric);
+    let is_kar = midi_text_events
+        .iter()
+        .any(|x| x.kind == MidiTextKind::Text && x.text.starts_with("@K"));
+
+    let mut lyrics_lines: Vec<Vec<(u32, String)>> = vec![Vec::new()];
+    for event in midi_text_events.iter().filter(|x| {
+        x.kind == MidiTextKind::Lyric
+            || (!has_lyric && is_kar && x.kind == MidiTextKind::Text && !x.text.starts_with('@'))
+    }) {
+        // KAR形式では先頭の"/"が改行、"\"が段落の区切り
+        let mut text = event.text.as_str();
+        if let Some(stripped) = text.strip_prefix(['/', '\\']) {
+            lyrics_lines.push(Vec::new());
+            text = stripped;
+        }
+
+        // 末尾の改行で行を区切る形式もある
+        let is_end_of_line = text.ends_with(['\r', '\n']);
+        let text = text.trim_end_matches(['\r', '\n']);
+        if !text.is_empty() {
+            lyrics_lines
+                .last_mut()
+                .unwrap()
+                .push((event.time_axis.ticks_total, text.to_string()));
+        }
+        if is_end_of_line {
+            lyrics_lines.push(Vec::new());
+        }
+    }
+
+    lyrics_lines.retain(|x| !x.is_empty());
+    lyrics_lines
+}
+
+fn remove_lyrics_timeline(mut commands: Commands) {
+    commands.remove_resource::<LyricsTimeline>();
+}
+
+fn setup(
+    mut commands: Commands,
+    global_settings: Res<GlobalSettings>,
+    active_theme: Res<ActiveTheme>,
+    asset_server: Res<AssetServer>,
+) {
+    let Some(lyrics_layout) = global_settings
+        .config
+        .feature_and_layout
+        .lyrics
+        .calculate_rect(
+            global_settings.config.main_config.window_width,
+            global_settings.config.main_config.window_height,
+        )
+    else {
+        return;
+    };
+
+    // 曲名は先頭トラックのトラック名。著作権表示があれば後ろに並べる
+    let title = global_settings
+        .midi_text_events
+        .iter()
+        .find(|x| x.kind == MidiTextKind::TrackName && x.track == 0)
+        .into_iter()
+        .chain(
+            global_settings
+                .midi_text_events
+                .iter()
+                .find(|x| x.kind == MidiTextKind::Copyright),
+        )
+        .map(|x| x.text.trim())
+        .filter(|x| !x.is_empty())
+        .collect::<Vec<_>>()
+        .join("  ");
+    let markers = global_settings
+        .midi_text_events
+        .iter()
+        .filter(|x| x.kind == MidiTextKind::Marker)
+        .map(|x| (x.time_axis.ticks_total, x.text.trim().to_string()))
+        .collect();
+    commands.insert_resource(LyricsTimeline {
+        markers,
+        lyrics_lines: get_lyrics_lines(&global_settings.midi_text_events),
+    });
+
+    let font: Handle<Font> = asset_server.load("fonts\\NotoSansJP-Thin.ttf");
+    let color_levels: Vec<_> = (0..=4)
+        .map(|level| active_theme.color(&global_settings.config.theme, ThemeColor::Main(level)))
+        .collect();
+    let accent_color = active_theme.color(&global_settings.config.theme, ThemeColor::Accent);
+
+    let height = lyrics_layout.height_pixel;
+    commands
+        .spawn((
+            LyricsRoot,
+            Transform::from_xyz(
+                lyrics_layout.left_bottom_abs_pixel.0,
+                lyrics_layout.left_bottom_abs_pixel.1,
+                0.0,
+            ),
+            Visibility::default(),
+            RenderLayers::layer(0),
+            lyrics_layout,
+        ))
+        .with_children(|parent| {
+            parent.spawn((
+                Transform::from_xyz(0.0, height, 0.0),
+                GlobalTransform::default(),
+                Text2d::new(title),
+                TextFont {
+                    font: font.clone(),
+                    font_size: 20.0,
+                    ..default()
+                },
+                TextColor(color_levels[1]),
+                ThemeColor::Main(1),
+                Anchor::TopLeft,
+            ));
+            parent.spawn((
+                Transform::from_xyz(0.0, height - 30.0, 0.0),
+                GlobalTransform::default(),
+                MarkerText,
+                Text2d::new(""),
+                TextFont {
+                    font: font.clone(),
+                    font_size: 28.0,
+                    ..default()
+                },
+                TextColor(accent_color),
+                ThemeColor::Accent,
+                Anchor::TopLeft,
+            ));
+
+            // 歌い終わった部分と、これから歌う部分で色を変える
+            parent
+                .spawn((
+                    Transform::from_xyz(0.0, 40.0, 0.0),
+                    GlobalTransform::default(),
+                    LyricsSungText,
+                    Text2d::new(""),
+                    TextFont {
+                        font: font.clone(),
+                        font_size: 40.0,
+                        ..default()
+                    },
+                    TextColor(accent_color),
+                    ThemeColor::Accent,
+                    Anchor::BottomLeft,
+                ))
+                .with_child((
+                    LyricsUnsungText,
+                    TextSpan::new(""),
+                    TextFont {
+                        font: font.clone(),
+                        font_size: 40.0,
+                        ..default()
+                    },
+                    TextColor(color_levels[0]),
+                    ThemeColor::Main(0),
+                ));
+            parent.spawn((
+                Transform::from_xyz(0.0, 0.0, 0.0),
+                GlobalTransform::default(),
+                LyricsNextLineText,
+                Text2d::new(""),
+                TextFont {
+                    font: font.clone(),
+                    font_size: 30.0,
+                    ..default()
+                },
+                TextColor(color_levels[2]),
+                ThemeColor::Main(2),
+                Anchor::BottomLeft,
+            ));
+        });
+}
+
+fn update_marker_text(
+    global_monitor_values: Res<GlobalMonitorValues>,
+    lyrics_timeline: Res<LyricsTimeline>,
+    mut query: Query<&mut Text2d, With<MarkerText>>,
+) {
+    let ticks_total = global_monitor_values.current_time_axis.ticks_total;
+    let index = lyrics_timeline
+        .markers
+        .partition_point(|x| x.0 <= ticks_total);
+
+    for mut text in &mut query {
+        text.clear();
+        if index > 0 {
+            text.push_str(&lyrics_timeline.markers[index - 1].1);
+        }
+    }
+}
+
+fn update_lyrics_text(
+    global_monitor_values: Res<GlobalMonitorValues>,
+    lyrics_timeline: Res<LyricsTimeline>,
+    mut query_sung: Query<&mut Text2d, (With<LyricsSungText>, Without<LyricsNextLineText>)>,
+    mut query_unsung: Query<&mut TextSpan, With<LyricsUnsungText>>,
+    mut query_next_line: Query<&mut Text2d, (With<LyricsNextLineText>, Without<LyricsSungText>)>,
+) {
+    let ticks_total = global_monitor_values.current_time_axis.ticks_total;
+    let lyrics_lines = &lyrics_timeline.lyrics_lines;
+
+    // 歌い始めている最後の行。まだ1行目が始まっていなければ1行目を予告として表示する
+    let index = lyrics_lines
+        .partition_point(|x| x[0].0 <= ticks_total)
+        .max(1)
+        - 1;
+
+    let mut sung = String::new();
+    let mut unsung = String::new();
+    if let Some(line) = lyrics_lines.get(index) {
+        for (ticks, syllable) in line {
+            if *ticks <= ticks_total {
+                sung.push_str(syllable);
+            } else {
+                unsung.push_str(syllable);
+            }
+        }
+    }
+    let next_line: String = lyrics_lines
+        .get(index + 1)
+        .map(|x| x.iter().map(|(_, syllable)| syllable.as_str()).collect())
+        .unwrap_or_default();
+
+    for mut text in &mut query_sung {
+        text.clear();
+        text.push_str(&sung);
+    }
+    for mut text in &mut query_unsung {
+        text.clear();
+        text.push_str(&unsung);
+    }
+    for mut text in &mut query_next_line {
+        text.clear();
+        text.push_str(&next_line);
+    }
+}
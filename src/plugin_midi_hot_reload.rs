@@ -0,0 +1,192 @@
+use crate::global_vars::{
+    ActiveTheme, AppState, GlobalMonitorValues, GlobalSettings, MainWindowCamera,
+    MidiLoadErrorMessage, MidiLoadErrorText, MidiLoaderConfig, MidiReloaded, ThemeColor, TimeAxis,
+};
+use crate::midi_loader::{self, LoadMidiReturn, MidiLoadError};
+use crate::plugin_transport_control::{TransportCommand, TransportControlSet};
+use crate::tempo_map::TempoMap;
+use bevy::prelude::*;
+use bevy::tasks::{block_on, poll_once, AsyncComputeTaskPool, Task};
+use std::fs;
+use std::path::Path;
+use std::time::{Duration, SystemTime};
+
+/// midi_file_pathの変更を監視し、バックグラウンドで読み込み直す
+///
+/// 読み込み中も再生は止めず、読み込みが終わったフレームでデータを差し替える。
+/// 再生位置は秒数ではなく小節・拍で引き継ぐので、テンポを変えても同じ場所から続く。
+pub struct MidiHotReloadPlugin;
+
+impl Plugin for MidiHotReloadPlugin {
+    fn build(&self, app: &mut App) {
+        app.add_systems(PostStartup, setup).add_systems(
+            PreUpdate,
+            (watch_midi_file, finish_midi_reload)
+                .chain()
+                .before(TransportControlSet)
+                .run_if(resource_exists::<MidiHotReload>),
+        );
+    }
+}
+
+#[derive(Resource)]
+struct MidiHotReload {
+    midi_file_path: String,               // 最後に読み込んだパス
+    midi_loader_config: MidiLoaderConfig, // 最後に読み込んだときの設定
+    theme_switch_enabled: bool, // 最後に読み込んだときに、Ch.16をテーマの切り替えに使ったか
+    last_modified: Option<SystemTime>,
+    timer: Timer, // ファイルの更新日時を確認する間隔
+    task: Option<Task<Result<LoadMidiReturn, MidiLoadError>>>,
+}
+
+fn get_modified(midi_file_path: &str) -> Option<SystemTime> {
+    fs::metadata(Path::new(midi_file_path))
+        .and_then(|x| x.modified())
+        .ok()
+}
+
+fn setup(mut commands: Commands, global_settings: Res<GlobalSettings>) {
+    let main_config = &global_settings.config.main_config;
+    commands.insert_resource(MidiHotReload {
+        midi_file_path: main_config.midi_file_path.clone(),
+        midi_loader_config: global_settings.config.midi_loader.clone(),
+        theme_switch_enabled: global_settings.config.theme_switch.enabled,
+        last_modified: get_modified(&main_config.midi_file_path),
+        timer: Timer::from_seconds(0.5, TimerMode::Repeating),
+        task: None,
+    });
+}
+
+/// ファイルの更新、または設定のパス・読み込み設定の変更を見つけたら、読み込みを始める
+fn watch_midi_file(
+    time: Res<Time<Real>>,
+    global_settings: Res<GlobalSettings>,
+    mut midi_hot_reload: ResMut<MidiHotReload>,
+) {
+    if !midi_hot_reload.timer.tick(time.delta()).just_finished() || midi_hot_reload.task.is_some() {
+        return;
+    }
+
+    let config = &global_settings.config;
+    let modified = get_modified(&config.main_config.midi_file_path);
+    if modified == midi_hot_reload.last_modified
+        && config.main_config.midi_file_path == midi_hot_reload.midi_file_path
+        && config.midi_loader == midi_hot_reload.midi_loader_config
+        && config.theme_switch.enabled == midi_hot_reload.theme_switch_enabled
+    {
+        return;
+    }
+    midi_hot_reload.last_modified = modified;
+    midi_hot_reload.midi_file_path = config.main_config.midi_file_path.clone();
+    midi_hot_reload.midi_loader_config = config.midi_loader.clone();
+    midi_hot_reload.theme_switch_enabled = config.theme_switch.enabled;
+
+    let midi_file_path = midi_hot_reload.midi_file_path.clone();
+    let midi_loader_config = midi_hot_reload.midi_loader_config.clone();
+    let theme_switch_enabled = midi_hot_reload.theme_switch_enabled;
+    midi_hot_reload.task = Some(AsyncComputeTaskPool::get().spawn(async move {
+        let mut loaded_midi_return = midi_loader::load_midi(&midi_file_path, &midi_loader_config)?;
+        if theme_switch_enabled {
+            loaded_midi_return.reserve_theme_switch_channel();
+        }
+        Ok(loaded_midi_return)
+    }));
+}
+
+/// 再生位置を小節・拍で引き継ぐ。新しい曲の長さを超える場合は曲末に丸める
+fn carry_over_position(tempo_map: &TempoMap, time_axis: &TimeAxis) -> TimeAxis {
+    let measure_start_ticks = tempo_map.ticks_from_measure(time_axis.measure);
+    let measure_start = tempo_map.time_axis_from_ticks(measure_start_ticks);
+    let beat_progress =
+        time_axis.ticks_reset_by_beat as f32 / time_axis.beat_length_ticks.max(1) as f32;
+    let ticks_in_measure =
+        ((time_axis.beat - 1) as f32 + beat_progress) * measure_start.beat_length_ticks as f32;
+    let ticks_in_measure =
+        (ticks_in_measure as u32).min(measure_start.measure_length_ticks.saturating_sub(1));
+    let ticks =
+        (measure_start_ticks + ticks_in_measure).min(tempo_map.last_time_axis().ticks_total);
+    tempo_map.time_axis_from_ticks(ticks)
+}
+
+#[allow(clippy::too_many_arguments)]
+fn finish_midi_reload(
+    mut commands: Commands,
+    mut midi_hot_reload: ResMut<MidiHotReload>,
+    mut global_settings: ResMut<GlobalSettings>,
+    active_theme: Res<ActiveTheme>,
+    mut global_monitor_values: ResMut<GlobalMonitorValues>,
+    app_state: Res<State<AppState>>,
+    mut midi_reloaded: EventWriter<MidiReloaded>,
+    mut transport_commands: EventWriter<TransportCommand>,
+    query_error: Query<Entity, With<MidiLoadErrorText>>,
+    query_camera: Query<Entity, With<MainWindowCamera>>,
+) {
+    let Some(task) = midi_hot_reload.task.as_mut() else {
+        return;
+    };
+    let Some(result) = block_on(poll_once(task)) else {
+        return;
+    };
+    midi_hot_reload.task = None;
+
+    for entity in query_error.iter() {
+        commands.entity(entity).despawn_recursive();
+    }
+
+    let loaded_midi_return = match result {
+        Ok(loaded_midi_return) => loaded_midi_return,
+        Err(e) => {
+            // 前のデータのまま再生を続け、エラーを画面に重ねて表示する
+            error!("{}", e);
+            let text_color = active_theme.color(&global_settings.config.theme, ThemeColor::Accent);
+            commands.spawn((
+                MidiLoadErrorText,
+                ThemeColor::Accent,
+                Node {
+                    position_type: PositionType::Absolute,
+                    left: Val::Px(20.0),
+                    bottom: Val::Px(20.0),
+                    ..default()
+                },
+                Text::new(format!("Failed to reload MIDI file\n{}", e)),
+                TextColor(text_color),
+                TargetCamera(query_camera.single()),
+            ));
+            return;
+        }
+    };
+    for warning in &loaded_midi_return.warnings {
+        warn!("{}", warning);
+    }
+
+    if app_state.get() != &AppState::Stop {
+        let time_axis = carry_over_position(
+            &loaded_midi_return.tempo_map,
+            &global_monitor_values.current_time_axis,
+        );
+        global_monitor_values
+            .elapsed_time_from_start
+            .set_elapsed(Duration::from_secs_f32(time_axis.seconds_total));
+        global_monitor_values.current_time_axis = time_axis;
+        // オーディオを引き継いだ位置から鳴らし直す
+        transport_commands.send(TransportCommand::SeekSeconds(time_axis.seconds_total));
+    } else {
+        global_monitor_values.current_time_axis = loaded_midi_return.tempo_map.first_time_axis();
+    }
+
+    global_settings.format = loaded_midi_return.format;
+    global_settings.ppm = loaded_midi_return.ppm;
+    global_settings.smpte_fps = loaded_midi_return.smpte_fps;
+    global_settings.tempo_map = loaded_midi_return.tempo_map;
+    global_settings.midi_notes_vec = loaded_midi_return.midi_notes_vec;
+    global_settings.unsplit_midi_notes_vec = loaded_midi_return.unsplit_midi_notes_vec;
+    global_settings.track_names = loaded_midi_return.track_names;
+    global_settings.midi_control_lanes_vec = loaded_midi_return.midi_control_lanes_vec;
+    global_settings.midi_text_events = loaded_midi_return.midi_text_events;
+    global_settings.theme_change_events = loaded_midi_return.theme_change_events;
+
+    // 起動時の読み込みに失敗していた場合は、ここから再生できるようになる
+    commands.remove_resource::<MidiLoadErrorMessage>();
+    info!("reloaded MIDI file {}", midi_hot_reload.midi_file_path);
+    midi_reloaded.send(MidiReloaded);
+}
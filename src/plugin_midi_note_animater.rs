@@ -1,25 +1,49 @@
 use crate::cubic_bezier;
 use crate::global_vars::{
-    AppState, GlobalMonitorValues, GlobalSettings, MainWindowCamera, MidiNote,
+    ActiveTheme, AppState, ConfigReloaded, GlobalMonitorValues, GlobalSettings, KeyRange,
+    KeyRangeMode, MainWindowCamera, MidiNote, MidiReloaded, PianoRollConfig, PianoRollViewMode,
+    RectangleFromFeatureLayoutChild, ThemeColor, TimeAxis, TransportSeeked,
 };
-use crate::util_color;
+use crate::piano_roll_grid::{self, GridLineKind};
+use crate::plugin_config_hot_reload::despawn_with;
+use crate::tempo_map::TempoMap;
 use bevy::prelude::*;
 use bevy::render::view::RenderLayers;
+use bevy::sprite::Anchor;
 use bevy::time::Stopwatch;
 
 pub struct MidiNoteAnimatePlugin;
 
 impl Plugin for MidiNoteAnimatePlugin {
     fn build(&self, app: &mut App) {
-        app.add_systems(PostStartup, setup).add_systems(
-            Update,
-            (
-                update_midi_note_state_1,
-                update_midi_note_state_2,
-                update_midi_note_state_3,
-                update_midi_note_state_4,
-            ),
-        );
+        app.init_resource::<PianoRollPages>()
+            .add_systems(PostStartup, setup)
+            .add_systems(
+                Update,
+                (
+                    update_midi_note_state_1,
+                    update_midi_note_state_2,
+                    update_midi_note_state_3,
+                    update_midi_note_state_4,
+                    update_page_guides,
+                    update_key_axis,
+                ),
+            )
+            .add_systems(
+                Update,
+                reset_midi_note_state
+                    .run_if(on_event::<TransportSeeked>)
+                    .before(update_midi_note_state_1)
+                    .before(update_midi_note_state_2)
+                    .before(update_midi_note_state_3)
+                    .before(update_midi_note_state_4),
+            )
+            .add_systems(
+                Update,
+                (despawn_with::<MidiPianoRollRoot>, setup)
+                    .chain()
+                    .run_if(on_event::<ConfigReloaded>.or(on_event::<MidiReloaded>)),
+            );
     }
 }
 
@@ -39,7 +63,9 @@ struct MidiNoteForAnimate {
     total_animation_time_sec: f32,
     state: AnimateState,
     full_note_length: f32,
+    sustain_tail_length: f32,
     x_pos_of_note: f32,
+    page_index: usize,
 }
 
 #[derive(Component)]
@@ -57,17 +83,159 @@ struct MidiNoteParallel4;
 #[derive(Component)]
 struct MidiPianoRollRoot;
 
+/// 現在のページの、小節の区切り線と小節番号
+#[derive(Component)]
+struct PageGuides;
+
+/// ノートを並べる縦軸。ノートはkeyを単位とした座標に置き、この軸の伸縮でピクセルに合わせる
+///
+/// 表示するkeyの範囲が変わると、前の範囲から伸び縮みさせて移り変わる。
+#[derive(Component)]
+struct KeyAxis {
+    height: f32,
+    cubic_bezier: cubic_bezier::CubicBezier,
+    elapsed_time: Stopwatch,
+    from: (f32, f32), // 移り変わりを始めたときの(最低音, 最高音)
+    current: (f32, f32),
+    to: KeyRange,
+}
+
+impl KeyAxis {
+    fn transform(&self) -> Transform {
+        let (min_key, max_key) = self.current;
+        let note_height = self.height / (max_key - min_key + 1.0);
+        Transform::from_xyz(0.0, -min_key * note_height, 0.0).with_scale(Vec3::new(
+            1.0,
+            note_height,
+            1.0,
+        ))
+    }
+
+    /// 表示している範囲からはみ出す部分を除いた、keyのレーンの中心と高さ。keyの単位
+    fn clip_key_lane(&self, key: u32) -> Option<(f32, f32)> {
+        let (min_key, max_key) = self.current;
+        let bottom = (key as f32).max(min_key);
+        let top = (key as f32 + 1.0).min(max_key + 1.0);
+        (top > bottom).then_some(((bottom + top) / 2.0, top - bottom))
+    }
+
+    fn apply_to_key_lane(
+        &self,
+        key_lane: &KeyLane,
+        transform: &mut Transform,
+        visibility: &mut Visibility,
+    ) {
+        match self.clip_key_lane(key_lane.key) {
+            Some((y, height)) => {
+                transform.translation.y = y;
+                transform.scale.y = height;
+                *visibility = Visibility::Inherited;
+            }
+            None => *visibility = Visibility::Hidden,
+        }
+    }
+}
+
+/// 色を付けるkeyのレーン。KeyAxisの子として置き、表示している範囲に合わせて切り詰める
+#[derive(Component)]
+struct KeyLane {
+    key: u32,
+}
+
+/// ページの区切り。1ページに表示する小節の範囲を、曲頭から順に並べる
+#[derive(Resource)]
+pub struct PianoRollPages {
+    measure_ranges: Vec<(u32, u32)>, // (先頭の小節, 次のページの先頭の小節)
+    key_ranges: Vec<KeyRange>,       // ページごとに表示するkeyの範囲
+}
+
+impl Default for PianoRollPages {
+    fn default() -> Self {
+        Self {
+            measure_ranges: vec![(0, 1)],
+            key_ranges: vec![KeyRange::PIANO],
+        }
+    }
+}
+
+impl PianoRollPages {
+    /// 設定に応じて、曲の最後の小節までページに分ける
+    fn new(
+        piano_roll_config: &PianoRollConfig,
+        tempo_map: &TempoMap,
+        note_count_by_measure: &[u32],
+    ) -> Self {
+        let last_measure = tempo_map.last_time_axis().measure;
+        let max_measures = piano_roll_config.measures_per_page.max(1);
+        let mut measure_ranges = Vec::new();
+        let mut start_measure = 0;
+        while start_measure <= last_measure {
+            let mut end_measure = start_measure + max_measures;
+            if piano_roll_config.auto_measures_per_page {
+                // ノートが目安の数を超えない範囲で、できるだけ多くの小節を1ページに収める
+                let count_of = |measure: u32| {
+                    note_count_by_measure
+                        .get(measure as usize)
+                        .copied()
+                        .unwrap_or(0)
+                };
+                let mut note_count = count_of(start_measure);
+                end_measure = start_measure + 1;
+                while end_measure < start_measure + max_measures
+                    && end_measure <= last_measure
+                    && note_count + count_of(end_measure) <= piano_roll_config.max_notes_per_page
+                {
+                    note_count += count_of(end_measure);
+                    end_measure += 1;
+                }
+            }
+            measure_ranges.push((start_measure, end_measure));
+            start_measure = end_measure;
+        }
+        Self {
+            key_ranges: vec![KeyRange::PIANO; measure_ranges.len()],
+            measure_ranges,
+        }
+    }
+
+    /// 小節を含むページの番号。最後のページより後の小節は、最後のページに含める
+    pub fn page_index(&self, measure: u32) -> usize {
+        self.measure_ranges
+            .partition_point(|(start_measure, _)| *start_measure <= measure)
+            .max(1)
+            - 1
+    }
+
+    fn is_page_start(&self, time_axis: &TimeAxis) -> bool {
+        time_axis.ticks_reset_by_measure == 0
+            && self.measure_ranges[self.page_index(time_axis.measure)].0 == time_axis.measure
+    }
+
+    /// ページの先頭と終端のtick
+    pub fn ticks_range(&self, tempo_map: &TempoMap, page_index: usize) -> (u32, u32) {
+        let (start_measure, end_measure) = self.measure_ranges[page_index];
+        (
+            tempo_map.ticks_from_measure(start_measure),
+            tempo_map.ticks_from_measure(end_measure),
+        )
+    }
+}
+
 fn setup(
     mut commands: Commands,
     query_camera: Query<Entity, With<MainWindowCamera>>,
-    global_monitor_values: Res<GlobalMonitorValues>,
     global_settings: Res<GlobalSettings>,
+    active_theme: Res<ActiveTheme>,
 
     mut meshes: ResMut<Assets<Mesh>>,
     mut materials: ResMut<Assets<ColorMaterial>>,
 ) {
+    // スクロール・落下の表示は、それぞれのプラグインが描画する
+    if global_settings.config.piano_roll.view_mode != PianoRollViewMode::Page {
+        return;
+    }
     let main_window_camera = commands.entity(query_camera.single()).id();
-    let piano_roll_layout = global_settings
+    let Some(piano_roll_layout) = global_settings
         .config
         .feature_and_layout
         .piano_roll
@@ -75,22 +243,14 @@ fn setup(
             global_settings.config.main_config.window_width,
             global_settings.config.main_config.window_height,
         )
-        .unwrap();
+    else {
+        return;
+    };
 
     // midiピアノロールの領域等の定数
     let width_piano_roll = piano_roll_layout.width_pixel;
     let height_piano_roll = piano_roll_layout.height_pixel;
 
-    // 表示する縦方向の分解能
-    let note_height = height_piano_roll / 88.0;
-
-    // 最低音と最高音のkey u32
-    let min_key = 21;
-    let max_key = 108;
-
-    // 表示する横方向の分解能
-    let mut width_per_tick = width_piano_roll / (4.0 * global_settings.ppm as f32);
-
     let piano_roll_root_entity = commands
         .spawn((
             MidiPianoRollRoot,
@@ -105,36 +265,167 @@ fn setup(
         ))
         .id();
 
+    let piano_roll_config = &global_settings.config.piano_roll;
+    let themes = &global_settings.config.theme;
+
+    // 小節ごとのノート数から、ページの区切りを決める
+    let mut note_count_by_measure = Vec::new();
+    for midi_note in global_settings.midi_notes_vec.iter().flatten() {
+        if !global_settings.is_note_group_visible(global_settings.note_group_index(midi_note)) {
+            continue;
+        }
+        let measure = midi_note.note_on_time_axis.measure as usize;
+        if note_count_by_measure.len() <= measure {
+            note_count_by_measure.resize(measure + 1, 0);
+        }
+        note_count_by_measure[measure] += 1;
+    }
+    let mut pages = PianoRollPages::new(
+        piano_roll_config,
+        &global_settings.tempo_map,
+        &note_count_by_measure,
+    );
+
+    // ページごとに、表示するkeyの範囲を決める
+    let key_range = global_settings.key_range();
+    pages.key_ranges = match piano_roll_config.key_range_mode {
+        KeyRangeMode::AutoPerPage => {
+            let mut midi_notes_by_page = vec![Vec::new(); pages.measure_ranges.len()];
+            for midi_note in global_settings.midi_notes_vec.iter().flatten() {
+                if global_settings
+                    .is_note_group_visible(global_settings.note_group_index(midi_note))
+                {
+                    midi_notes_by_page[pages.page_index(midi_note.note_on_time_axis.measure)]
+                        .push(midi_note);
+                }
+            }
+            // ノートが無いページは、前のページの範囲のままにする
+            let mut previous_key_range = None;
+            midi_notes_by_page
+                .iter()
+                .map(|midi_notes| {
+                    let page_key_range = KeyRange::fit(
+                        midi_notes.iter().copied(),
+                        piano_roll_config.key_range_padding,
+                    )
+                    .or(previous_key_range)
+                    .unwrap_or(key_range);
+                    previous_key_range = Some(page_key_range);
+                    page_key_range
+                })
+                .collect()
+        }
+        _ => vec![key_range; pages.measure_ranges.len()],
+    };
+
+    let first_key_range = pages.key_ranges[0];
+    let key_axis = KeyAxis {
+        height: height_piano_roll,
+        cubic_bezier: cubic_bezier::CubicBezier::new(
+            cubic_bezier::Vec2 { x: 0.85, y: 0.0 },
+            cubic_bezier::Vec2 { x: 0.15, y: 1.0 },
+        ),
+        elapsed_time: Stopwatch::new(),
+        from: (
+            first_key_range.min_key as f32,
+            first_key_range.max_key as f32,
+        ),
+        current: (
+            first_key_range.min_key as f32,
+            first_key_range.max_key as f32,
+        ),
+        to: first_key_range,
+    };
+
+    // ノートの奥に、keyのレーンを描画する。どのページの範囲でも描画できるように作っておく
+    let grid_config = &global_settings.config.piano_roll_grid;
+    let lanes_min_key = pages.key_ranges.iter().map(|x| x.min_key).min().unwrap();
+    let lanes_max_key = pages.key_ranges.iter().map(|x| x.max_key).max().unwrap();
+    let mut key_lane_entities = Vec::new();
+    for key in lanes_min_key..=lanes_max_key {
+        if !piano_roll_grid::is_key_lane_shaded(grid_config.key_lanes, key) {
+            continue;
+        }
+        let key_lane = KeyLane { key };
+        let mut transform = Transform::from_xyz(width_piano_roll / 2.0, 0.0, 0.0);
+        let mut visibility = Visibility::Inherited;
+        key_axis.apply_to_key_lane(&key_lane, &mut transform, &mut visibility);
+        let theme_color = piano_roll_grid::key_lane_theme_color(grid_config);
+        key_lane_entities.push(
+            commands
+                .spawn((
+                    key_lane,
+                    transform,
+                    Mesh2d(meshes.add(Rectangle::new(width_piano_roll, 1.0))),
+                    MeshMaterial2d(materials.add(active_theme.color(themes, theme_color))),
+                    theme_color,
+                    visibility,
+                ))
+                .id(),
+        );
+    }
+
+    let key_axis_entity = commands
+        .spawn((key_axis.transform(), Visibility::Inherited, key_axis))
+        .add_children(&key_lane_entities)
+        .id();
+    commands
+        .entity(piano_roll_root_entity)
+        .add_child(key_axis_entity);
+
     // spawn midi notes
+    let note_group_count = global_settings.note_group_count();
     for midi_notes in &global_settings.midi_notes_vec {
         for midi_note in midi_notes {
+            // 設定で非表示にしたグループと、表示する範囲外のノートは生成しない
+            let note_group_index = global_settings.note_group_index(midi_note);
+            let page_index = pages.page_index(midi_note.note_on_time_axis.measure);
+            if !global_settings.is_note_group_visible(note_group_index)
+                || !pages.key_ranges[page_index].contains(midi_note.key)
+            {
+                continue;
+            }
+
             let cubic_bezier = cubic_bezier::CubicBezier::new(
                 cubic_bezier::Vec2 { x: 0.85, y: 0.0 },
                 cubic_bezier::Vec2 { x: 0.15, y: 1.0 },
             );
-            width_per_tick =
-                width_piano_roll / midi_note.note_on_time_axis.measure_length_ticks as f32;
+            // 表示する横方向の分解能。ページ内で拍子が変わっても、ページの横幅に収める
+            let (page_start_ticks, page_end_ticks) =
+                pages.ticks_range(&global_settings.tempo_map, page_index);
+            let width_per_tick =
+                width_piano_roll / (page_end_ticks - page_start_ticks).max(1) as f32;
             let note_width = width_per_tick * midi_note.note_length_ticks.unwrap() as f32;
-            let x_pos_of_note =
-                width_per_tick * midi_note.note_on_time_axis.ticks_reset_by_measure as f32;
-            let color = util_color::adjust_color(
-                &global_settings.config.theme[0].note_channel_base_hex,
-                &global_settings.config.theme[0].note_channel_target_hex,
-                midi_note.channel,
-                global_settings.midi_notes_vec.len() as u32,
-            )
-            .unwrap();
+            let x_pos_of_note = width_per_tick
+                * (midi_note.note_on_time_axis.ticks_total - page_start_ticks) as f32;
+            let theme_color = ThemeColor::NoteChannel {
+                group_index: note_group_index,
+                group_count: note_group_count,
+            };
+
+            // ペダルによる余韻の長さ
+            let sustain_tail_width = match midi_note.sustain_off_time_axis {
+                Some(sustain_off_time_axis) if piano_roll_config.sustain_tail_enabled => {
+                    width_per_tick
+                        * (sustain_off_time_axis.ticks_total
+                            - midi_note.note_off_time_axis.unwrap().ticks_total)
+                            as f32
+                }
+                _ => 0.0,
+            };
 
             let default_bundle = (
                 Transform::from_xyz(
                     x_pos_of_note,
-                    (midi_note.key - min_key) as f32 * note_height + note_height / 2.0,
-                    16.0 - midi_note.channel as f32,
+                    // 縦方向はkeyを単位とし、KeyAxisでピクセルに合わせる
+                    midi_note.key as f32 + 0.5,
+                    (note_group_count - note_group_index) as f32,
                 )
                 .with_scale(Vec3::new(0.0, 1.0, 1.0)),
                 GlobalTransform::default(),
-                Mesh2d(meshes.add(Rectangle::new(note_width, note_height))),
-                MeshMaterial2d(materials.add(Color::srgb(color[0], color[1], color[2]))),
+                Mesh2d(meshes.add(Rectangle::new(note_width, 1.0))),
+                MeshMaterial2d(materials.add(active_theme.color(themes, theme_color))),
+                theme_color,
                 MidiNoteForAnimate {
                     midi_note: midi_note.clone(),
                     cubic_bezier,
@@ -142,44 +433,230 @@ fn setup(
                     total_animation_time_sec: 0.3,
                     state: AnimateState::Invisible,
                     full_note_length: note_width,
+                    sustain_tail_length: sustain_tail_width,
                     x_pos_of_note,
+                    page_index,
                 },
                 Visibility::Hidden,
                 TargetCamera(main_window_camera),
             );
 
             // channelによって、spawnするentityを変える。MidiNoteParallel1 ~ 4
-            match midi_note.channel % 4 {
-                0 => {
-                    commands
-                        .entity(piano_roll_root_entity)
-                        .with_children(|parent| {
-                            parent.spawn((MidiNoteParallel1, default_bundle));
-                        });
-                }
-                1 => {
-                    commands
-                        .entity(piano_roll_root_entity)
-                        .with_children(|parent| {
-                            parent.spawn((MidiNoteParallel2, default_bundle));
-                        });
-                }
-                2 => {
-                    commands
-                        .entity(piano_roll_root_entity)
-                        .with_children(|parent| {
-                            parent.spawn((MidiNoteParallel3, default_bundle));
-                        });
-                }
-                3 => {
-                    commands
-                        .entity(piano_roll_root_entity)
-                        .with_children(|parent| {
-                            parent.spawn((MidiNoteParallel4, default_bundle));
-                        });
-                }
-                _ => {}
+            let midi_note_entity = match midi_note.channel % 4 {
+                0 => commands.spawn((MidiNoteParallel1, default_bundle)).id(),
+                1 => commands.spawn((MidiNoteParallel2, default_bundle)).id(),
+                2 => commands.spawn((MidiNoteParallel3, default_bundle)).id(),
+                _ => commands.spawn((MidiNoteParallel4, default_bundle)).id(),
             };
+            commands.entity(key_axis_entity).add_child(midi_note_entity);
+
+            // 余韻はノートの子として右隣に置き、ノートと一緒に伸び縮みさせる
+            if sustain_tail_width > 0.0 {
+                let tail_theme_color = ThemeColor::SustainTail {
+                    group_index: note_group_index,
+                    group_count: note_group_count,
+                    brightness: piano_roll_config.sustain_tail_brightness,
+                };
+                commands.entity(midi_note_entity).with_children(|parent| {
+                    parent.spawn((
+                        Transform::from_xyz(note_width / 2.0 + sustain_tail_width / 2.0, 0.0, 0.0),
+                        GlobalTransform::default(),
+                        Mesh2d(meshes.add(Rectangle::new(sustain_tail_width, 1.0))),
+                        MeshMaterial2d(materials.add(active_theme.color(themes, tail_theme_color))),
+                        tail_theme_color,
+                        Visibility::Inherited,
+                    ));
+                });
+            }
+        }
+    }
+    commands.insert_resource(pages);
+}
+
+/// 現在のページの小節の区切り線と小節番号を、ページが変わったときに描き直す
+#[allow(clippy::too_many_arguments)]
+fn update_page_guides(
+    mut commands: Commands,
+    global_settings: Res<GlobalSettings>,
+    global_monitor_values: Res<GlobalMonitorValues>,
+    pages: Res<PianoRollPages>,
+    active_theme: Res<ActiveTheme>,
+    asset_server: Res<AssetServer>,
+    query_root: Query<(Entity, &RectangleFromFeatureLayoutChild), With<MidiPianoRollRoot>>,
+    query_guides: Query<Entity, With<PageGuides>>,
+    mut drawn_page: Local<Option<(Entity, usize)>>,
+    mut meshes: ResMut<Assets<Mesh>>,
+    mut materials: ResMut<Assets<ColorMaterial>>,
+) {
+    let Ok((root_entity, piano_roll_layout)) = query_root.get_single() else {
+        return;
+    };
+    // ピアノロールを作り直した場合も描き直す
+    let page_index = pages.page_index(global_monitor_values.current_time_axis.measure);
+    if *drawn_page == Some((root_entity, page_index)) {
+        return;
+    }
+    *drawn_page = Some((root_entity, page_index));
+    for entity in query_guides.iter() {
+        commands.entity(entity).despawn_recursive();
+    }
+
+    let themes = &global_settings.config.theme;
+    let tempo_map = &global_settings.tempo_map;
+    let font: Handle<Font> = asset_server.load("fonts\\NotoSansJP-Thin.ttf");
+    let (page_start_ticks, page_end_ticks) = pages.ticks_range(tempo_map, page_index);
+    let width_per_tick =
+        piano_roll_layout.width_pixel / (page_end_ticks - page_start_ticks).max(1) as f32;
+    let height = piano_roll_layout.height_pixel;
+    let grid_config = &global_settings.config.piano_roll_grid;
+    let number_theme_color = ThemeColor::Main(2);
+
+    let (start_measure, end_measure) = pages.measure_ranges[page_index];
+    let guides_entity = commands
+        .spawn((PageGuides, Transform::default(), Visibility::Inherited))
+        .with_children(|parent| {
+            // 区切り線はノートより奥に描画する
+            for grid_line in piano_roll_grid::grid_lines(
+                tempo_map,
+                grid_config.subdivisions,
+                page_start_ticks,
+                page_end_ticks.saturating_sub(1),
+            ) {
+                let Some(theme_color) = grid_line.kind.theme_color(grid_config) else {
+                    continue;
+                };
+                let x =
+                    (grid_line.time_axis.ticks_total - page_start_ticks) as f32 * width_per_tick;
+                parent.spawn((
+                    Transform::from_xyz(x + 0.5, height / 2.0, grid_line.kind.z()),
+                    Mesh2d(meshes.add(Rectangle::new(1.0, height))),
+                    MeshMaterial2d(materials.add(active_theme.color(themes, theme_color))),
+                    theme_color,
+                ));
+            }
+            for measure in start_measure..end_measure {
+                let x = (tempo_map.ticks_from_measure(measure) - page_start_ticks) as f32
+                    * width_per_tick;
+                parent.spawn((
+                    Text2d::new(measure.to_string()),
+                    TextFont {
+                        font: font.clone(),
+                        font_size: 16.0,
+                        ..default()
+                    },
+                    TextColor(active_theme.color(themes, number_theme_color)),
+                    number_theme_color,
+                    Anchor::TopLeft,
+                    Transform::from_xyz(x + 4.0, height, GridLineKind::Measure.z()),
+                ));
+            }
+        })
+        .id();
+    commands.entity(root_entity).add_child(guides_entity);
+}
+
+/// 現在のページのkeyの範囲に向けて、KeyAxisを伸び縮みさせる。再生位置が飛んだときは移り変わりを省く
+fn update_key_axis(
+    time: Res<Time>,
+    global_settings: Res<GlobalSettings>,
+    global_monitor_values: Res<GlobalMonitorValues>,
+    pages: Res<PianoRollPages>,
+    mut transport_seeked: EventReader<TransportSeeked>,
+    mut query: Query<(&mut KeyAxis, &mut Transform)>,
+    mut query_lanes: Query<(&KeyLane, &mut Transform, &mut Visibility), Without<KeyAxis>>,
+) {
+    let is_seeked = !transport_seeked.is_empty();
+    transport_seeked.clear();
+    let Ok((mut key_axis, mut transform)) = query.get_single_mut() else {
+        return;
+    };
+
+    let page_index = pages.page_index(global_monitor_values.current_time_axis.measure);
+    let next_key_range = pages.key_ranges[page_index];
+    if next_key_range != key_axis.to {
+        key_axis.from = key_axis.current;
+        key_axis.to = next_key_range;
+        key_axis.elapsed_time.reset();
+    }
+
+    let to = (key_axis.to.min_key as f32, key_axis.to.max_key as f32);
+    let transition_seconds = global_settings
+        .config
+        .piano_roll
+        .key_range_transition_seconds;
+    let next_current = match is_seeked || transition_seconds <= 0.0 {
+        true => {
+            key_axis.from = to;
+            to
+        }
+        false => {
+            key_axis.elapsed_time.tick(time.delta());
+            let x = key_axis.elapsed_time.elapsed_secs() / transition_seconds;
+            let y = key_axis.cubic_bezier.solve_y(x).unwrap();
+            (
+                key_axis.from.0 + (to.0 - key_axis.from.0) * y,
+                key_axis.from.1 + (to.1 - key_axis.from.1) * y,
+            )
+        }
+    };
+    // 範囲が変わらない間は、Transformを書き換えない
+    if next_current != key_axis.current {
+        key_axis.current = next_current;
+        *transform = key_axis.transform();
+        for (key_lane, mut transform, mut visibility) in query_lanes.iter_mut() {
+            key_axis.apply_to_key_lane(key_lane, &mut transform, &mut visibility);
+        }
+    }
+}
+
+/// ノートが消え始める条件。余韻がある場合は余韻の終端を使い、ページが変わるまでは表示し続ける
+fn is_note_finished(
+    midi_note: &MidiNote,
+    current_time_axis: &TimeAxis,
+    pages: &PianoRollPages,
+) -> bool {
+    let Some(note_off_time_axis) = midi_note
+        .sustain_off_time_axis
+        .or(midi_note.note_off_time_axis)
+    else {
+        return false;
+    };
+
+    let is_note_off_before_current =
+        note_off_time_axis.ticks_total <= current_time_axis.ticks_total;
+
+    let is_page_condition_met = pages.page_index(note_off_time_axis.measure)
+        < pages.page_index(current_time_axis.measure)
+        || pages.is_page_start(&note_off_time_axis);
+
+    is_note_off_before_current && is_page_condition_met
+}
+
+/// 再生位置が飛んだときに、その位置で表示されているはずの状態にする。アニメーションは省く
+fn reset_midi_note_state(
+    global_monitor_values: Res<GlobalMonitorValues>,
+    pages: Res<PianoRollPages>,
+    mut query: Query<(&mut MidiNoteForAnimate, &mut Transform, &mut Visibility)>,
+) {
+    let current_time_axis = &global_monitor_values.current_time_axis;
+    for (mut midi_note_for_animate, mut transform, mut visibility) in query.iter_mut() {
+        let midi_note = &midi_note_for_animate.midi_note;
+        let is_visible = midi_note.note_on_time_axis.ticks_total <= current_time_axis.ticks_total
+            && midi_note_for_animate.page_index == pages.page_index(current_time_axis.measure)
+            && !is_note_finished(midi_note, current_time_axis, &pages);
+
+        midi_note_for_animate.elapsed_time.reset();
+        if is_visible {
+            midi_note_for_animate.state = AnimateState::Visible;
+            transform.scale = Vec3::new(1.0, 1.0, 1.0);
+            transform.translation.x =
+                midi_note_for_animate.x_pos_of_note + midi_note_for_animate.full_note_length / 2.0;
+            *visibility = Visibility::Visible;
+        } else {
+            midi_note_for_animate.state = AnimateState::Invisible;
+            transform.scale = Vec3::new(0.0, 1.0, 1.0);
+            transform.translation.x = midi_note_for_animate.x_pos_of_note;
+            *visibility = Visibility::Hidden;
         }
     }
 }
@@ -190,12 +667,13 @@ fn update_midi_note_state_logic(
     transform: &mut Transform,
     visibility: &mut Visibility,
     global_monitor_values: &GlobalMonitorValues,
+    pages: &PianoRollPages,
     app_state: &Res<State<AppState>>,
 ) {
     if app_state.get() == &AppState::Stop {
         midi_note_for_animate.state = AnimateState::Invisible;
         midi_note_for_animate.elapsed_time.reset();
-        if visibility.clone() == Visibility::Visible {
+        if *visibility == Visibility::Visible {
             visibility.toggle_visible_hidden();
         }
         transform.scale = Vec3::new(0.0, 1.0, 1.0);
@@ -208,8 +686,8 @@ fn update_midi_note_state_logic(
             .note_on_time_axis
             .ticks_total
             <= global_monitor_values.current_time_axis.ticks_total)
-            && (midi_note_for_animate.midi_note.note_on_time_axis.measure
-                == global_monitor_values.current_time_axis.measure)
+            && (midi_note_for_animate.page_index
+                == pages.page_index(global_monitor_values.current_time_axis.measure))
         {
             midi_note_for_animate.state = AnimateState::In;
             visibility.toggle_visible_hidden();
@@ -229,18 +707,12 @@ fn update_midi_note_state_logic(
             midi_note_for_animate.elapsed_time.reset();
         }
     } else if midi_note_for_animate.state == AnimateState::Visible {
-        if let Some(note_off_time_axis) = midi_note_for_animate.midi_note.note_off_time_axis {
-            let current_time_axis = &global_monitor_values.current_time_axis;
-
-            let is_note_off_before_current =
-                note_off_time_axis.ticks_total <= current_time_axis.ticks_total;
-
-            let is_measure_condition_met = note_off_time_axis.measure < current_time_axis.measure
-                || (note_off_time_axis.beat == 1 && note_off_time_axis.ticks_reset_by_measure == 0);
-
-            if is_note_off_before_current && is_measure_condition_met {
-                midi_note_for_animate.state = AnimateState::Out;
-            }
+        if is_note_finished(
+            &midi_note_for_animate.midi_note,
+            &global_monitor_values.current_time_axis,
+            pages,
+        ) {
+            midi_note_for_animate.state = AnimateState::Out;
         }
     } else if midi_note_for_animate.state == AnimateState::Out {
         midi_note_for_animate.elapsed_time.tick(time.delta());
@@ -249,7 +721,8 @@ fn update_midi_note_state_logic(
         let y = midi_note_for_animate.cubic_bezier.solve_y(x).unwrap();
         transform.scale = Vec3::new(1.0 - y, 1.0, 1.0);
         transform.translation.x = midi_note_for_animate.x_pos_of_note
-            + midi_note_for_animate.full_note_length * y
+            + (midi_note_for_animate.full_note_length + midi_note_for_animate.sustain_tail_length)
+                * y
             + midi_note_for_animate.full_note_length * (1.0 - y) / 2.0;
         if midi_note_for_animate.elapsed_time.elapsed_secs()
             >= midi_note_for_animate.total_animation_time_sec
@@ -264,6 +737,7 @@ fn update_midi_note_state_logic(
 fn update_midi_note_state_1(
     time: Res<Time>,
     global_monitor_values: Res<GlobalMonitorValues>,
+    pages: Res<PianoRollPages>,
     mut query: Query<
         (&mut MidiNoteForAnimate, &mut Transform, &mut Visibility),
         With<MidiNoteParallel1>,
@@ -277,6 +751,7 @@ fn update_midi_note_state_1(
             &mut transform,
             &mut visibility,
             &global_monitor_values,
+            &pages,
             &app_state,
         );
     }
@@ -285,6 +760,7 @@ fn update_midi_note_state_1(
 fn update_midi_note_state_2(
     time: Res<Time>,
     global_monitor_values: Res<GlobalMonitorValues>,
+    pages: Res<PianoRollPages>,
     mut query: Query<
         (&mut MidiNoteForAnimate, &mut Transform, &mut Visibility),
         With<MidiNoteParallel2>,
@@ -298,6 +774,7 @@ fn update_midi_note_state_2(
             &mut transform,
             &mut visibility,
             &global_monitor_values,
+            &pages,
             &app_state,
         );
     }
@@ -306,6 +783,7 @@ fn update_midi_note_state_2(
 fn update_midi_note_state_3(
     time: Res<Time>,
     global_monitor_values: Res<GlobalMonitorValues>,
+    pages: Res<PianoRollPages>,
     mut query: Query<
         (&mut MidiNoteForAnimate, &mut Transform, &mut Visibility),
         With<MidiNoteParallel3>,
@@ -319,6 +797,7 @@ fn update_midi_note_state_3(
             &mut transform,
             &mut visibility,
             &global_monitor_values,
+            &pages,
             &app_state,
         );
     }
@@ -327,6 +806,7 @@ fn update_midi_note_state_3(
 fn update_midi_note_state_4(
     time: Res<Time>,
     global_monitor_values: Res<GlobalMonitorValues>,
+    pages: Res<PianoRollPages>,
     mut query: Query<
         (&mut MidiNoteForAnimate, &mut Transform, &mut Visibility),
         With<MidiNoteParallel4>,
@@ -340,6 +820,7 @@ fn update_midi_note_state_4(
             &mut transform,
             &mut visibility,
             &global_monitor_values,
+            &pages,
             &app_state,
         );
     }
@@ -0,0 +1,308 @@
+use crate::global_vars::{
+    ActiveTheme, ConfigReloaded, GlobalMonitorValues, GlobalSettings, LookAheadUnit,
+    MainWindowCamera, MidiNote, MidiReloaded, PianoRollViewMode, ThemeColor, TimeAxis,
+};
+use crate::piano_roll_grid;
+use crate::plugin_config_hot_reload::despawn_with;
+use crate::tempo_map::TempoMap;
+use bevy::prelude::*;
+use bevy::render::view::RenderLayers;
+
+/// piano_roll.view_mode = "scroll" のときに、固定した再生ヘッドの前をノートが流れるピアノロール
+///
+/// ノートは生成したときの幅のまま動かし、ピアノロールの領域からはみ出る部分だけを縮めて隠す。
+pub struct MidiNoteScrollPlugin;
+
+impl Plugin for MidiNoteScrollPlugin {
+    fn build(&self, app: &mut App) {
+        app.add_systems(PostStartup, setup)
+            .add_systems(Update, update_scrolling_notes)
+            .add_systems(
+                Update,
+                (despawn_with::<MidiNoteScrollRoot>, setup)
+                    .chain()
+                    .run_if(on_event::<ConfigReloaded>.or(on_event::<MidiReloaded>)),
+            );
+    }
+}
+
+#[derive(Component)]
+struct MidiNoteScrollRoot;
+
+/// ルートに持たせる、スクロールの軸とピクセルの対応
+#[derive(Component)]
+struct ScrollView {
+    width: f32,
+    playhead_x: f32,
+    pixels_per_unit: f64, // 1tick、または1秒あたりのピクセル数
+    unit: LookAheadUnit,
+}
+
+impl ScrollView {
+    /// 経過秒数を、スクロールの軸上の位置に変換する
+    fn position_at(&self, tempo_map: &TempoMap, seconds: f32) -> f64 {
+        match self.unit {
+            LookAheadUnit::Beats => tempo_map.exact_ticks_from_seconds(seconds),
+            LookAheadUnit::Seconds => seconds as f64,
+        }
+    }
+}
+
+/// ノートや余韻の、スクロールの軸上での開始位置と生成したときの幅
+#[derive(Component)]
+struct ScrollingNote {
+    start_position: f64,
+    full_width: f32,
+}
+
+fn setup(
+    mut commands: Commands,
+    query_camera: Query<Entity, With<MainWindowCamera>>,
+    global_settings: Res<GlobalSettings>,
+    active_theme: Res<ActiveTheme>,
+    mut meshes: ResMut<Assets<Mesh>>,
+    mut materials: ResMut<Assets<ColorMaterial>>,
+) {
+    let piano_roll_config = &global_settings.config.piano_roll;
+    if piano_roll_config.view_mode != PianoRollViewMode::Scroll {
+        return;
+    }
+    let Some(piano_roll_layout) = global_settings
+        .config
+        .feature_and_layout
+        .piano_roll
+        .calculate_rect(
+            global_settings.config.main_config.window_width,
+            global_settings.config.main_config.window_height,
+        )
+    else {
+        return;
+    };
+    let main_window_camera = query_camera.single();
+    let themes = &global_settings.config.theme;
+
+    // 表示するkeyの範囲
+    let key_range = global_settings.key_range();
+    let note_height = piano_roll_layout.height_pixel / key_range.key_count() as f32;
+
+    let width = piano_roll_layout.width_pixel;
+    let playhead_x = width * piano_roll_config.playhead_percent / 100.0;
+    let look_ahead_units = match piano_roll_config.look_ahead_unit {
+        LookAheadUnit::Beats => piano_roll_config.look_ahead as f64 * global_settings.ppm as f64,
+        LookAheadUnit::Seconds => piano_roll_config.look_ahead as f64,
+    };
+    let scroll_view = ScrollView {
+        width,
+        playhead_x,
+        pixels_per_unit: (width - playhead_x) as f64 / look_ahead_units,
+        unit: piano_roll_config.look_ahead_unit,
+    };
+    let position_of = |time_axis: &TimeAxis| match scroll_view.unit {
+        LookAheadUnit::Beats => time_axis.ticks_total as f64,
+        LookAheadUnit::Seconds => time_axis.seconds_total as f64,
+    };
+
+    let mut children = Vec::new();
+
+    // ノートの奥に、keyのレーンと区切り線を描画する
+    let grid_config = &global_settings.config.piano_roll_grid;
+    for key in key_range.min_key..=key_range.max_key {
+        if !piano_roll_grid::is_key_lane_shaded(grid_config.key_lanes, key) {
+            continue;
+        }
+        let theme_color = piano_roll_grid::key_lane_theme_color(grid_config);
+        children.push(
+            commands
+                .spawn((
+                    Transform::from_xyz(
+                        width / 2.0,
+                        (key - key_range.min_key) as f32 * note_height + note_height / 2.0,
+                        0.0,
+                    ),
+                    GlobalTransform::default(),
+                    Mesh2d(meshes.add(Rectangle::new(width, note_height))),
+                    MeshMaterial2d(materials.add(active_theme.color(themes, theme_color))),
+                    theme_color,
+                    Visibility::Inherited,
+                    TargetCamera(main_window_camera),
+                ))
+                .id(),
+        );
+    }
+    let tempo_map = &global_settings.tempo_map;
+    for grid_line in piano_roll_grid::grid_lines(
+        tempo_map,
+        grid_config.subdivisions,
+        0,
+        tempo_map.last_time_axis().ticks_total,
+    ) {
+        let Some(theme_color) = grid_line.kind.theme_color(grid_config) else {
+            continue;
+        };
+        // 区切り線も、ノートと同じように流す
+        children.push(
+            commands
+                .spawn((
+                    ScrollingNote {
+                        start_position: position_of(&grid_line.time_axis),
+                        full_width: 1.0,
+                    },
+                    Transform::from_xyz(
+                        0.0,
+                        piano_roll_layout.height_pixel / 2.0,
+                        grid_line.kind.z(),
+                    ),
+                    GlobalTransform::default(),
+                    Mesh2d(meshes.add(Rectangle::new(1.0, piano_roll_layout.height_pixel))),
+                    MeshMaterial2d(materials.add(active_theme.color(themes, theme_color))),
+                    theme_color,
+                    Visibility::Hidden,
+                    TargetCamera(main_window_camera),
+                ))
+                .id(),
+        );
+    }
+
+    let mut spawn_part =
+        |start_position: f64, end_position: f64, y: f32, z: f32, theme_color: ThemeColor| {
+            let full_width =
+                (((end_position - start_position) * scroll_view.pixels_per_unit) as f32).max(1.0);
+            let entity = commands
+                .spawn((
+                    ScrollingNote {
+                        start_position,
+                        full_width,
+                    },
+                    Transform::from_xyz(0.0, y, z),
+                    GlobalTransform::default(),
+                    Mesh2d(meshes.add(Rectangle::new(full_width, note_height))),
+                    MeshMaterial2d(materials.add(active_theme.color(themes, theme_color))),
+                    theme_color,
+                    Visibility::Hidden,
+                    TargetCamera(main_window_camera),
+                ))
+                .id();
+            children.push(entity);
+        };
+
+    let note_group_count = global_settings.note_group_count();
+    // 小節で分割したノートでは小節線の所に隙間ができるので、分割前のノートを1本の矩形にする
+    for midi_note in global_settings.unsplit_midi_notes_vec.iter().flatten() {
+        // 設定で非表示にしたグループと、表示する範囲外のノートは生成しない
+        let note_group_index = global_settings.note_group_index(midi_note);
+        if !global_settings.is_note_group_visible(note_group_index)
+            || !key_range.contains(midi_note.key)
+        {
+            continue;
+        }
+        let Some(note_off_time_axis) = midi_note.note_off_time_axis else {
+            continue;
+        };
+        let y = (midi_note.key - key_range.min_key) as f32 * note_height + note_height / 2.0;
+        let z = (note_group_count - note_group_index) as f32;
+
+        spawn_part(
+            position_of(&midi_note.note_on_time_axis),
+            position_of(&note_off_time_axis),
+            y,
+            z,
+            ThemeColor::NoteChannel {
+                group_index: note_group_index,
+                group_count: note_group_count,
+            },
+        );
+
+        // ペダルによる余韻は、ノートの後ろに別の矩形として置く
+        if let Some(sustain_off_time_axis) = sustain_tail_end(midi_note) {
+            if piano_roll_config.sustain_tail_enabled {
+                spawn_part(
+                    position_of(&note_off_time_axis),
+                    position_of(&sustain_off_time_axis),
+                    y,
+                    z - 0.5,
+                    ThemeColor::SustainTail {
+                        group_index: note_group_index,
+                        group_count: note_group_count,
+                        brightness: piano_roll_config.sustain_tail_brightness,
+                    },
+                );
+            }
+        }
+    }
+
+    // 再生ヘッドはノートより手前に描画する
+    let playhead_theme_color = ThemeColor::Accent;
+    children.push(
+        commands
+            .spawn((
+                Transform::from_xyz(
+                    playhead_x,
+                    piano_roll_layout.height_pixel / 2.0,
+                    note_group_count as f32 + 1.0,
+                ),
+                GlobalTransform::default(),
+                Mesh2d(meshes.add(Rectangle::new(2.0, piano_roll_layout.height_pixel))),
+                MeshMaterial2d(materials.add(active_theme.color(themes, playhead_theme_color))),
+                playhead_theme_color,
+                Visibility::Inherited,
+                TargetCamera(main_window_camera),
+            ))
+            .id(),
+    );
+
+    commands
+        .spawn((
+            MidiNoteScrollRoot,
+            Transform::from_xyz(
+                piano_roll_layout.left_bottom_abs_pixel.0,
+                piano_roll_layout.left_bottom_abs_pixel.1,
+                0.0,
+            ),
+            Visibility::default(),
+            RenderLayers::layer(0),
+            scroll_view,
+            piano_roll_layout,
+        ))
+        .add_children(&children);
+}
+
+/// 余韻がある場合は、その終端
+fn sustain_tail_end(midi_note: &MidiNote) -> Option<TimeAxis> {
+    let note_off_time_axis = midi_note.note_off_time_axis?;
+    midi_note
+        .sustain_off_time_axis
+        .filter(|x| x.ticks_total > note_off_time_axis.ticks_total)
+}
+
+/// 再生位置に合わせてノートを動かし、ピアノロールの領域からはみ出る部分を隠す
+fn update_scrolling_notes(
+    global_settings: Res<GlobalSettings>,
+    global_monitor_values: Res<GlobalMonitorValues>,
+    query_root: Query<&ScrollView, With<MidiNoteScrollRoot>>,
+    mut query: Query<(&ScrollingNote, &mut Transform, &mut Visibility)>,
+) {
+    let Ok(scroll_view) = query_root.get_single() else {
+        return;
+    };
+    // 停止中は経過時間が0に戻るので、曲頭のノートが再生ヘッドの右に並ぶ
+    let current_position = scroll_view.position_at(
+        &global_settings.tempo_map,
+        global_monitor_values.elapsed_time_from_start.elapsed_secs(),
+    );
+
+    for (scrolling_note, mut transform, mut visibility) in query.iter_mut() {
+        let x_start = scroll_view.playhead_x
+            + ((scrolling_note.start_position - current_position) * scroll_view.pixels_per_unit)
+                as f32;
+        let x_end = x_start + scrolling_note.full_width;
+        let left = x_start.max(0.0);
+        let right = x_end.min(scroll_view.width);
+        if right <= left {
+            *visibility = Visibility::Hidden;
+            continue;
+        }
+        *visibility = Visibility::Inherited;
+        transform.scale.x = (right - left) / scrolling_note.full_width;
+        transform.translation.x = (left + right) / 2.0;
+    }
+}
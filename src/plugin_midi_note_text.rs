@@ -1,5 +1,8 @@
-use crate::global_vars::{GlobalMonitorValues, GlobalSettings, MainWindowCamera, MidiNote};
-use crate::util_color;
+use crate::global_vars::{
+    ActiveTheme, ConfigReloaded, GlobalMonitorValues, GlobalSettings, MainWindowCamera, MidiNote,
+    ThemeColor,
+};
+use crate::plugin_config_hot_reload::despawn_with;
 use bevy::prelude::*;
 
 pub struct MidiNoteTextPlugin;
@@ -7,10 +10,19 @@ pub struct MidiNoteTextPlugin;
 impl Plugin for MidiNoteTextPlugin {
     fn build(&self, app: &mut App) {
         app.add_systems(PostStartup, setup)
-            .add_systems(Update, (update_midinote_ch1_text,));
+            .add_systems(Update, (update_midinote_ch1_text,))
+            .add_systems(
+                Update,
+                (despawn_with::<MidiNoteTextRoot>, setup)
+                    .chain()
+                    .run_if(on_event::<ConfigReloaded>),
+            );
     }
 }
 
+#[derive(Component)]
+struct MidiNoteTextRoot;
+
 #[derive(Component)]
 struct MidiNoteCh1Text;
 
@@ -18,30 +30,30 @@ fn setup(
     mut commands: Commands,
     query: Query<Entity, With<MainWindowCamera>>,
     global_settings: Res<GlobalSettings>,
+    active_theme: Res<ActiveTheme>,
 ) {
+    if !global_settings.config.feature_and_layout.note_list.enabled {
+        return;
+    }
+
     let main_window_camera = commands.entity(query.single()).id();
-    let color = util_color::adjust_color(
-        &global_settings.config.theme[0].main_base_hex,
-        &global_settings.config.theme[0].background_hex,
-        1,
-        4,
-    )
-    .unwrap();
+    let theme_color = ThemeColor::Main(1);
+    let color = active_theme.color(&global_settings.config.theme, theme_color);
 
     commands
-        .spawn(Node {
-            width: Val::Percent(100.),
-            flex_direction: FlexDirection::Column,
-            align_items: AlignItems::FlexStart,
-            ..default()
-        })
+        .spawn((
+            MidiNoteTextRoot,
+            Node {
+                width: Val::Percent(100.),
+                flex_direction: FlexDirection::Column,
+                align_items: AlignItems::FlexStart,
+                ..default()
+            },
+            // Since we are using multiple cameras, we need to specify which camera UI should be rendered to
+            TargetCamera(main_window_camera),
+        ))
         .with_children(|parent| {
-            parent.spawn((
-                Text::new("First window"),
-                // Since we are using multiple cameras, we need to specify which camera UI should be rendered to
-                TargetCamera(main_window_camera),
-                TextColor(Color::srgb(color[0], color[1], color[2])),
-            ));
+            parent.spawn((Text::new("First window"), TextColor(color), theme_color));
             parent
                 .spawn(Node {
                     width: Val::Percent(100.),
@@ -50,14 +62,12 @@ fn setup(
                     ..default()
                 })
                 .with_children(|parent| {
-                    parent.spawn((
-                        Text::new("Notes: "),
-                        TextColor(Color::srgb(color[0], color[1], color[2])),
-                    ));
+                    parent.spawn((Text::new("Notes: "), TextColor(color), theme_color));
                     parent.spawn((
                         Text::new(""),
                         MidiNoteCh1Text,
-                        TextColor(Color::srgb(color[0], color[1], color[2])),
+                        TextColor(color),
+                        theme_color,
                     ));
                 });
         });
@@ -70,25 +80,35 @@ fn update_midinote_ch1_text(
 ) {
     let time_axis = global_monitor_values.current_time_axis;
 
+    // 設定に応じて、チャンネルごと・トラックごとに鳴っているノートを集める
+    let mut current_note_on_notes_vecs: Vec<Vec<&MidiNote>> =
+        vec![Vec::new(); global_settings.note_group_count() as usize];
+    for midi_note in global_settings.midi_notes_vec.iter().flatten() {
+        if midi_note.note_on_time_axis.ticks_total <= time_axis.ticks_total
+            && midi_note
+                .note_off_time_axis
+                .is_some_and(|off| off.ticks_total >= time_axis.ticks_total)
+        {
+            let note_group_index = global_settings.note_group_index(midi_note) as usize;
+            current_note_on_notes_vecs[note_group_index].push(midi_note);
+        }
+    }
+
     for mut text in &mut query {
         text.clear();
-        for (i, midi_notes) in global_settings.midi_notes_vec.iter().enumerate() {
-            let current_note_on_notes_vec = midi_notes
-                .iter()
-                .filter(|x| {
-                    x.note_on_time_axis.ticks_total <= time_axis.ticks_total
-                        && x.note_off_time_axis.unwrap().ticks_total >= time_axis.ticks_total
-                })
-                .collect::<Vec<&MidiNote>>();
+        for (i, current_note_on_notes_vec) in current_note_on_notes_vecs.iter().enumerate() {
+            if !global_settings.is_note_group_visible(i as u32) {
+                continue;
+            }
             let mut text_str = String::new();
-            text_str.push_str(&format!("ch{}: ", i + 1));
+            text_str.push_str(&format!("{}: ", global_settings.note_group_name(i as u32)));
             for note in current_note_on_notes_vec {
                 text_str.push_str(&format!(
                     "(Note: {}, Velocity: {}) ",
                     note.key_and_octave_yamaha, note.velocity
                 ));
             }
-            text_str.push_str("\n");
+            text_str.push('\n');
             text.push_str(text_str.as_str());
         }
     }
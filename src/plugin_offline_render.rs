@@ -0,0 +1,264 @@
+use crate::global_vars::{
+    GlobalMonitorValues, GlobalSettings, MainWindowCamera, MidiLoadErrorMessage,
+    OfflineRenderOutput,
+};
+use bevy::prelude::*;
+use bevy::render::camera::RenderTarget;
+use bevy::render::render_asset::RenderAssetUsages;
+use bevy::render::render_resource::{Extent3d, TextureDimension, TextureFormat, TextureUsages};
+use bevy::render::view::screenshot::{Screenshot, ScreenshotCaptured};
+use bevy::time::TimeUpdateStrategy;
+use std::collections::BTreeMap;
+use std::fs;
+use std::io::Write;
+use std::path::PathBuf;
+use std::process::{Child, Command, Stdio};
+use std::time::Duration;
+
+/// ウィンドウを使わず、固定のフレームレートで1フレームずつ画像に描画して書き出す
+///
+/// 経過時間は`Time::delta`を1/fps秒に固定して進めるので、
+/// 描画にかかる時間に関係なくフレーム単位で正確な映像になる。
+pub struct OfflineRenderPlugin;
+
+impl Plugin for OfflineRenderPlugin {
+    fn build(&self, app: &mut App) {
+        app.add_systems(PostStartup, setup).add_systems(
+            Update,
+            capture_frame.run_if(resource_exists::<OfflineRenderState>),
+        );
+    }
+}
+
+#[derive(Resource)]
+struct OfflineRenderState {
+    image: Handle<Image>,
+    frame_count: u32,
+    next_capture_frame: u32,
+    next_write_frame: u32,
+    pending_images: BTreeMap<u32, Image>, // 書き出し順を保つため、先に届いたフレームを待たせておく
+    encoder: Option<Child>,
+}
+
+/// スクリーンショットのエンティティに付ける、フレーム番号
+#[derive(Component)]
+struct OfflineRenderFrame(u32);
+
+#[allow(clippy::too_many_arguments)]
+fn setup(
+    mut commands: Commands,
+    global_settings: Res<GlobalSettings>,
+    midi_load_error_message: Option<Res<MidiLoadErrorMessage>>,
+    mut global_monitor_values: ResMut<GlobalMonitorValues>,
+    mut images: ResMut<Assets<Image>>,
+    mut query_camera: Query<&mut Camera, With<MainWindowCamera>>,
+    mut time: ResMut<Time<Virtual>>,
+    mut app_exit: EventWriter<AppExit>,
+) {
+    // 書き出すものが無いので、空の映像を成功として扱わずに終了する
+    if let Some(midi_load_error_message) = midi_load_error_message {
+        error!(
+            "offline render: failed to load MIDI file: {}",
+            midi_load_error_message.0
+        );
+        app_exit.send(AppExit::error());
+        return;
+    }
+
+    let config = &global_settings.config.offline_render;
+    let width = global_settings.config.main_config.window_width;
+    let height = global_settings.config.main_config.window_height;
+    let fps = config.fps.max(1);
+
+    // 1フレームごとに、実時間に関係なく1/fps秒だけ進める
+    commands.insert_resource(TimeUpdateStrategy::ManualDuration(Duration::from_secs_f64(
+        1.0 / fps as f64,
+    )));
+
+    // ウィンドウの代わりに描画する画像
+    let mut image = Image::new_fill(
+        Extent3d {
+            width,
+            height,
+            depth_or_array_layers: 1,
+        },
+        TextureDimension::D2,
+        &[0, 0, 0, 255],
+        TextureFormat::Rgba8UnormSrgb,
+        RenderAssetUsages::default(),
+    );
+    image.texture_descriptor.usage |=
+        TextureUsages::COPY_SRC | TextureUsages::RENDER_ATTACHMENT | TextureUsages::TEXTURE_BINDING;
+    let image = images.add(image);
+    for mut camera in query_camera.iter_mut() {
+        camera.target = RenderTarget::Image(image.clone());
+    }
+
+    // 書き出す範囲
+    let start_seconds = config.start_seconds.max(0.0);
+    let end_seconds = config
+        .end_seconds
+        .unwrap_or(global_settings.tempo_map.last_time_axis().seconds_total);
+    let frame_count = ((end_seconds - start_seconds) * fps as f32).ceil().max(0.0) as u32;
+
+    global_monitor_values
+        .elapsed_time_from_start
+        .set_elapsed(Duration::from_secs_f32(start_seconds));
+    global_monitor_values.current_time_axis = global_settings
+        .tempo_map
+        .time_axis_from_seconds(start_seconds);
+
+    // フォントなどの読み込みが終わるまでは時間を止めておく
+    time.pause();
+
+    let encoder = match config.output {
+        OfflineRenderOutput::PngSequence => {
+            if let Err(e) = fs::create_dir_all(&config.output_directory) {
+                error!(
+                    "failed to create output directory {}: {}",
+                    config.output_directory, e
+                );
+                app_exit.send(AppExit::error());
+                return;
+            }
+            None
+        }
+        OfflineRenderOutput::Encoder => {
+            let args: Vec<String> = config
+                .encoder_command
+                .iter()
+                .map(|x| {
+                    x.replace("{width}", &width.to_string())
+                        .replace("{height}", &height.to_string())
+                        .replace("{fps}", &fps.to_string())
+                })
+                .collect();
+            let Some((program, args)) = args.split_first() else {
+                error!("encoder_command is empty");
+                app_exit.send(AppExit::error());
+                return;
+            };
+            match Command::new(program)
+                .args(args)
+                .stdin(Stdio::piped())
+                .spawn()
+            {
+                Ok(child) => Some(child),
+                Err(e) => {
+                    error!("failed to start encoder {}: {}", program, e);
+                    app_exit.send(AppExit::error());
+                    return;
+                }
+            }
+        }
+    };
+
+    info!(
+        "offline render: {} frames ({:.2}s - {:.2}s, {} fps, {}x{})",
+        frame_count, start_seconds, end_seconds, fps, width, height
+    );
+    if frame_count == 0 {
+        app_exit.send(AppExit::Success);
+    }
+
+    commands.insert_resource(OfflineRenderState {
+        image,
+        frame_count,
+        next_capture_frame: 0,
+        next_write_frame: 0,
+        pending_images: BTreeMap::new(),
+        encoder,
+    });
+}
+
+fn capture_frame(
+    mut commands: Commands,
+    mut offline_render_state: ResMut<OfflineRenderState>,
+    mut time: ResMut<Time<Virtual>>,
+    asset_server: Res<AssetServer>,
+    query_font: Query<&TextFont>,
+) {
+    if offline_render_state.next_capture_frame >= offline_render_state.frame_count {
+        return;
+    }
+
+    // フォントが揃ってから時間を進め始める。このフレームが先頭のフレームになる
+    if time.is_paused() {
+        if query_font
+            .iter()
+            .any(|x| asset_server.load_state(&x.font).is_loading())
+        {
+            return;
+        }
+        time.unpause();
+    }
+
+    commands
+        .spawn((
+            Screenshot::image(offline_render_state.image.clone()),
+            OfflineRenderFrame(offline_render_state.next_capture_frame),
+        ))
+        .observe(write_frame);
+    offline_render_state.next_capture_frame += 1;
+}
+
+fn write_frame(
+    trigger: Trigger<ScreenshotCaptured>,
+    query: Query<&OfflineRenderFrame>,
+    global_settings: Res<GlobalSettings>,
+    mut offline_render_state: ResMut<OfflineRenderState>,
+    mut app_exit: EventWriter<AppExit>,
+) {
+    let Ok(frame) = query.get(trigger.entity()) else {
+        return;
+    };
+    offline_render_state
+        .pending_images
+        .insert(frame.0, trigger.event().0.clone());
+
+    let config = &global_settings.config.offline_render;
+    let state = offline_render_state.as_mut();
+    while let Some(image) = state.pending_images.remove(&state.next_write_frame) {
+        let result = match state.encoder.as_mut() {
+            Some(encoder) => encoder
+                .stdin
+                .as_mut()
+                .unwrap()
+                .write_all(&image.data)
+                .map_err(|e| e.to_string()),
+            None => {
+                let path = PathBuf::from(&config.output_directory)
+                    .join(format!("{:06}.png", state.next_write_frame));
+                image
+                    .try_into_dynamic()
+                    .map_err(|e| e.to_string())
+                    .and_then(|x| x.to_rgb8().save(&path).map_err(|e| e.to_string()))
+            }
+        };
+        if let Err(e) = result {
+            error!("failed to write frame {}: {}", state.next_write_frame, e);
+            app_exit.send(AppExit::error());
+            return;
+        }
+
+        state.next_write_frame += 1;
+        if state.next_write_frame % config.fps.max(1) == 0 {
+            info!(
+                "offline render: {}/{} frames",
+                state.next_write_frame, state.frame_count
+            );
+        }
+    }
+
+    if state.next_write_frame >= state.frame_count {
+        // 標準入力を閉じて、エンコーダーの終了を待つ
+        if let Some(mut encoder) = state.encoder.take() {
+            drop(encoder.stdin.take());
+            if let Err(e) = encoder.wait() {
+                error!("failed to wait for encoder: {}", e);
+            }
+        }
+        info!("offline render: finished");
+        app_exit.send(AppExit::Success);
+    }
+}
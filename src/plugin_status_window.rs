@@ -1,11 +1,13 @@
 use bevy::{
     prelude::*,
-    render::{camera::RenderTarget, view::RenderLayers, Render},
+    render::{camera::RenderTarget, view::RenderLayers},
     window::{EnabledButtons, WindowRef, WindowResolution},
 };
 
-use crate::global_vars::{AppState, GlobalMonitorValues, GlobalSettings};
-use crate::util_color;
+use crate::global_vars::{
+    ActiveTheme, AppState, GlobalMonitorValues, GlobalSettings, LoopRegion, MidiLoadErrorMessage,
+    ThemeColor,
+};
 pub struct StatusWindowPlugin;
 
 impl Plugin for StatusWindowPlugin {
@@ -21,6 +23,7 @@ impl Plugin for StatusWindowPlugin {
                     print_status_midi_ppm,
                     print_status_midi_current_tempo,
                     print_status_midi_current_time_signature,
+                    print_status_midi_current_key_signature,
                     print_status_measure,
                     print_status_beat,
                     print_status_tick,
@@ -56,6 +59,9 @@ struct StatusMidiCurrentTempoText;
 #[derive(Component)]
 struct StatusMidiCurrentTimeSignatureText;
 
+#[derive(Component)]
+struct StatusMidiCurrentKeySignatureText;
+
 #[derive(Component)]
 struct StatusMeasureText;
 
@@ -71,7 +77,11 @@ struct StatusWindowResolution;
 #[derive(Component)]
 struct StatusWindowFPS;
 
-fn setup_status_window(mut commands: Commands, global_settings: Res<GlobalSettings>) {
+fn setup_status_window(
+    mut commands: Commands,
+    global_settings: Res<GlobalSettings>,
+    active_theme: Res<ActiveTheme>,
+) {
     // 2つ目のウィンドウを表示する
     let status_window = commands
         .spawn(Window {
@@ -86,23 +96,17 @@ fn setup_status_window(mut commands: Commands, global_settings: Res<GlobalSettin
             ..default()
         })
         .id();
-    let bg_color =
-        util_color::hex_to_srgb(&global_settings.config.theme[0].background_hex).unwrap();
-    let text_color =
-        util_color::hex_to_srgb(&global_settings.config.theme[0].main_base_hex).unwrap();
+    let bg_color = active_theme.color(&global_settings.config.theme, ThemeColor::Background);
 
     let status_window_camera = commands
         .spawn((
-            Camera2d::default(),
+            Camera2d,
             Camera {
                 target: RenderTarget::Window(WindowRef::Entity(status_window)),
-                clear_color: ClearColorConfig::Custom(Color::srgb(
-                    bg_color[0],
-                    bg_color[1],
-                    bg_color[2],
-                )),
+                clear_color: ClearColorConfig::Custom(bg_color),
                 ..default()
             },
+            ThemeColor::Background,
             RenderLayers::layer(1),
         ))
         .id();
@@ -264,6 +268,18 @@ fn setup_status_window(mut commands: Commands, global_settings: Res<GlobalSettin
                                     parent
                                         .spawn((Text::new(""), StatusMidiCurrentTimeSignatureText));
                                 });
+                            parent
+                                .spawn(Node {
+                                    width: Val::Percent(100.),
+                                    flex_direction: FlexDirection::Row,
+                                    align_items: AlignItems::FlexStart,
+                                    ..default()
+                                })
+                                .with_children(|parent| {
+                                    parent.spawn(Text::new("Current Key: "));
+                                    parent
+                                        .spawn((Text::new(""), StatusMidiCurrentKeySignatureText));
+                                });
                             parent
                                 .spawn(Node {
                                     width: Val::Percent(100.),
@@ -315,10 +331,29 @@ fn print_status_midi_path(
 fn print_status_status(
     mut query: Query<&mut Text, With<StatusStatusText>>,
     app_state: Res<State<AppState>>,
+    midi_load_error_message: Option<Res<MidiLoadErrorMessage>>,
+    loop_region: Option<Res<LoopRegion>>,
+    global_settings: Res<GlobalSettings>,
 ) {
     for mut text in &mut query {
         text.clear();
-        text.push_str(format!("{:?}", app_state.get()).as_str());
+        if let Some(midi_load_error_message) = &midi_load_error_message {
+            text.push_str(format!("Error ({})", midi_load_error_message.0).as_str());
+        } else {
+            text.push_str(format!("{:?}", app_state.get()).as_str());
+        }
+        // A-Bリピート中は、範囲を小節番号で表示する
+        if let Some((start_ticks, end_ticks)) = loop_region.as_ref().and_then(|x| x.range()) {
+            let tempo_map = &global_settings.tempo_map;
+            text.push_str(
+                format!(
+                    " (Loop: {} - {})",
+                    tempo_map.time_axis_from_ticks(start_ticks).measure,
+                    tempo_map.time_axis_from_ticks(end_ticks - 1).measure
+                )
+                .as_str(),
+            );
+        }
     }
 }
 
@@ -355,6 +390,9 @@ fn print_status_midi_ppm(
     for mut text in &mut query {
         text.clear();
         text.push_str(format!("{:?}", global_settings.ppm).as_str());
+        if let Some(fps) = global_settings.smpte_fps {
+            text.push_str(format!(" (SMPTE {:.2} fps)", fps.as_f32()).as_str());
+        }
     }
 }
 
@@ -415,7 +453,6 @@ fn print_status_midi_current_tempo(
 
 fn print_status_midi_current_time_signature(
     mut query: Query<&mut Text, With<StatusMidiCurrentTimeSignatureText>>,
-    global_settings: Res<GlobalSettings>,
     global_monitor_values: Res<GlobalMonitorValues>,
 ) {
     for mut text in &mut query {
@@ -430,7 +467,7 @@ fn print_status_midi_current_time_signature(
             )
             .as_str(),
         );
-        text.push_str("/");
+        text.push('/');
         text.push_str(
             format!(
                 "{:?}",
@@ -444,6 +481,21 @@ fn print_status_midi_current_time_signature(
     }
 }
 
+fn print_status_midi_current_key_signature(
+    mut query: Query<&mut Text, With<StatusMidiCurrentKeySignatureText>>,
+    global_monitor_values: Res<GlobalMonitorValues>,
+) {
+    for mut text in &mut query {
+        text.clear();
+        text.push_str(
+            global_monitor_values
+                .current_time_axis
+                .key_signature_name()
+                .as_str(),
+        );
+    }
+}
+
 fn print_status_measure(
     mut query: Query<&mut Text, With<StatusMeasureText>>,
     global_monitor_values: Res<GlobalMonitorValues>,
@@ -0,0 +1,120 @@
+use crate::global_vars::{
+    ActiveTheme, ConfigReloaded, GlobalMonitorValues, GlobalSettings, MidiReloaded, ThemeColor,
+};
+use bevy::prelude::*;
+
+/// Ch.16のノートに合わせて、曲の途中でテーマを切り替える
+///
+/// 各プラグインは、テーマの色を使うエンティティに`ThemeColor`を付けて生成する。
+/// 現在のテーマが変わると、このプラグインがそれらの色をまとめて塗り替える。
+pub struct ThemeSwitchPlugin;
+
+impl Plugin for ThemeSwitchPlugin {
+    fn build(&self, app: &mut App) {
+        app.init_resource::<ActiveTheme>().add_systems(
+            Update,
+            (
+                update_active_theme,
+                (
+                    apply_theme_to_materials,
+                    apply_theme_to_texts,
+                    apply_theme_to_cameras,
+                )
+                    .run_if(resource_changed::<ActiveTheme>),
+            )
+                .chain(),
+        );
+    }
+}
+
+/// 現在の再生位置から、テーマと切り替えの進み具合を求める
+fn update_active_theme(
+    global_settings: Res<GlobalSettings>,
+    global_monitor_values: Res<GlobalMonitorValues>,
+    mut active_theme: ResMut<ActiveTheme>,
+    mut config_reloaded: EventReader<ConfigReloaded>,
+    mut midi_reloaded: EventReader<MidiReloaded>,
+) {
+    let theme_count = global_settings.config.theme.len();
+    // 曲末で止まるTimeAxisではなく経過時間を使い、曲末の切り替えも最後まで移り変わるようにする
+    let current_seconds = global_monitor_values.elapsed_time_from_start.elapsed_secs();
+
+    // テーマの数を超える番号への切り替えは無視する
+    let mut from_index = 0;
+    let mut to_index = 0;
+    let mut changed_seconds = None;
+    for theme_change_event in global_settings
+        .theme_change_events
+        .iter()
+        .filter(|x| x.theme_index < theme_count)
+        .take_while(|x| x.time_axis.seconds_total <= current_seconds)
+    {
+        from_index = to_index;
+        to_index = theme_change_event.theme_index;
+        changed_seconds = Some(theme_change_event.time_axis.seconds_total);
+    }
+
+    let crossfade_seconds = global_settings.config.theme_switch.crossfade_seconds;
+    let ratio = match changed_seconds {
+        Some(changed_seconds) if crossfade_seconds > 0.0 => {
+            ((current_seconds - changed_seconds) / crossfade_seconds).min(1.0)
+        }
+        _ => 1.0,
+    };
+    // 切り替えが終わった後は、同じテーマとして扱う
+    let next_active_theme = match ratio >= 1.0 {
+        true => ActiveTheme {
+            from_index: to_index,
+            to_index,
+            ratio: 1.0,
+        },
+        false => ActiveTheme {
+            from_index,
+            to_index,
+            ratio,
+        },
+    };
+    active_theme.set_if_neq(next_active_theme);
+
+    // テーマの色そのものが変わった場合や、作り直した表示にも反映する
+    if !config_reloaded.is_empty() || !midi_reloaded.is_empty() {
+        config_reloaded.clear();
+        midi_reloaded.clear();
+        active_theme.set_changed();
+    }
+}
+
+fn apply_theme_to_materials(
+    global_settings: Res<GlobalSettings>,
+    active_theme: Res<ActiveTheme>,
+    mut materials: ResMut<Assets<ColorMaterial>>,
+    query: Query<(&ThemeColor, &MeshMaterial2d<ColorMaterial>)>,
+) {
+    for (theme_color, mesh_material) in query.iter() {
+        if let Some(material) = materials.get_mut(&mesh_material.0) {
+            material.color = active_theme.color(&global_settings.config.theme, *theme_color);
+        }
+    }
+}
+
+fn apply_theme_to_texts(
+    global_settings: Res<GlobalSettings>,
+    active_theme: Res<ActiveTheme>,
+    mut query: Query<(&ThemeColor, &mut TextColor)>,
+) {
+    for (theme_color, mut text_color) in query.iter_mut() {
+        text_color.0 = active_theme.color(&global_settings.config.theme, *theme_color);
+    }
+}
+
+fn apply_theme_to_cameras(
+    global_settings: Res<GlobalSettings>,
+    active_theme: Res<ActiveTheme>,
+    mut query: Query<(&ThemeColor, &mut Camera)>,
+) {
+    for (theme_color, mut camera) in query.iter_mut() {
+        camera.clear_color = ClearColorConfig::Custom(
+            active_theme.color(&global_settings.config.theme, *theme_color),
+        );
+    }
+}
@@ -0,0 +1,517 @@
+use crate::audio_clock::{AudioClock, AudioClockCounter};
+use crate::audio_file;
+use crate::global_vars::{
+    ActiveTheme, AppState, ConfigReloaded, GlobalMonitorValues, GlobalSettings, LoopRegion,
+    MainWindowCamera, MidiLoadErrorMessage, MidiReloaded, ThemeColor, TransportSeeked,
+};
+use crate::synth::Synth;
+use bevy::audio::{AddAudioSource, Decodable, PlaybackMode, Sample, Source};
+use bevy::input::InputSystem;
+use bevy::prelude::*;
+use bevy::window::PrimaryWindow;
+use std::time::Duration;
+
+/// キーボードとマウスで再生位置を操作する
+///
+/// 操作はすべて`TransportCommand`を経由して適用するので、
+/// 経過時間・現在のTimeAxis・オーディオ・ノートのアニメーションの状態が食い違わない。
+pub struct TransportControlPlugin;
+
+impl Plugin for TransportControlPlugin {
+    fn build(&self, app: &mut App) {
+        app.add_audio_source::<AudioFromPosition>()
+            .add_event::<TransportCommand>()
+            .init_resource::<LoopRegion>()
+            .add_systems(PostStartup, setup)
+            .add_systems(
+                Update,
+                (setup, restart_audio)
+                    .chain()
+                    .run_if(on_event::<ConfigReloaded>.or(on_event::<MidiReloaded>)),
+            )
+            .add_systems(
+                PreUpdate,
+                (
+                    read_keyboard,
+                    scrub_with_mouse,
+                    apply_loop_region,
+                    apply_transport_commands,
+                    sync_audio,
+                )
+                    .chain()
+                    .in_set(TransportControlSet)
+                    .after(InputSystem)
+                    .run_if(not(resource_exists::<MidiLoadErrorMessage>)),
+            );
+    }
+}
+
+/// 経過時間を進めるシステムは、このセットの後に動かす
+#[derive(SystemSet, Debug, Clone, PartialEq, Eq, Hash)]
+pub struct TransportControlSet;
+
+/// 再生位置の操作
+#[derive(Event, Debug, Clone, Copy)]
+pub enum TransportCommand {
+    TogglePlayOrStop, // 停止中は再生を始め、それ以外は停止して曲頭に戻る
+    TogglePause,
+    SeekSeconds(f32),
+    ScrubSeconds(f32), // ドラッグ中の移動。オーディオは離れた位置へ動いたときだけ作り直す
+    SeekMeasureAndBeat { measure: u32, beat: u32 }, // 小節は0始まり、拍は1始まり
+    SkipMeasures(i32),
+    SkipBeats(i32),
+    SetLoopStart,
+    SetLoopEnd,
+    ClearLoop,
+}
+
+#[derive(Component)]
+struct MainAudioComponent;
+
+/// 再生するオーディオ。ファイルを読み込めなかった場合はNoneで、音を鳴らさずに再生する
+#[derive(Resource)]
+struct MainAudioSource(Option<PlayableAudio>);
+
+/// 読み込みが終わり、鳴らせる状態のオーディオ
+#[derive(Clone)]
+enum PlayableAudio {
+    File(AudioSource),
+    Synth(Synth), // synth.enabledがtrueの場合
+}
+
+/// オーディオファイルを読み込めなかったときに表示するエラー
+#[derive(Component)]
+struct AudioLoadErrorText;
+
+/// 途中から再生するためのオーディオ
+///
+/// AudioSinkは再生位置を変えられないので、位置が飛ぶたびに開始位置を指定して作り直す。
+#[derive(Asset, TypePath)]
+struct AudioFromPosition {
+    audio: PlayableAudio,
+    start_position: Duration,
+    audio_clock: AudioClock,
+}
+
+impl Decodable for AudioFromPosition {
+    type DecoderItem = f32;
+    type Decoder = SkipToPosition<Box<dyn Source<Item = f32> + Send>>;
+
+    fn decoder(&self) -> Self::Decoder {
+        // シンセサイザーは開始位置から合成するので、読み飛ばす必要はない
+        let (source, position): (Box<dyn Source<Item = f32> + Send>, _) = match &self.audio {
+            PlayableAudio::File(audio_source) => (
+                Box::new(audio_source.decoder().convert_samples()),
+                Some(self.start_position),
+            ),
+            PlayableAudio::Synth(synth) => (Box::new(synth.source(self.start_position)), None),
+        };
+        SkipToPosition {
+            source,
+            position,
+            audio_clock_counter: self.audio_clock.counter(),
+        }
+    }
+}
+
+/// 最初のサンプルを読むときに開始位置まで進める。デコードはオーディオのスレッドで行われる
+struct SkipToPosition<S> {
+    source: S,
+    position: Option<Duration>,
+    audio_clock_counter: AudioClockCounter,
+}
+
+impl<S> Iterator for SkipToPosition<S>
+where
+    S: Source,
+    S::Item: Sample,
+{
+    type Item = S::Item;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if let Some(position) = self.position.take() {
+            // シークできない形式では、開始位置までのサンプルを読み飛ばす
+            if self.source.try_seek(position).is_err() {
+                let samples = (position.as_secs_f64() * self.source.sample_rate() as f64) as u64
+                    * self.source.channels() as u64;
+                for _ in 0..samples {
+                    if self.source.next().is_none() {
+                        self.audio_clock_counter.finish();
+                        return None;
+                    }
+                }
+            }
+        }
+        let Some(sample) = self.source.next() else {
+            self.audio_clock_counter.finish();
+            return None;
+        };
+        self.audio_clock_counter
+            .count(self.source.sample_rate(), self.source.channels());
+        Some(sample)
+    }
+}
+
+impl<S> Source for SkipToPosition<S>
+where
+    S: Source,
+    S::Item: Sample,
+{
+    fn current_frame_len(&self) -> Option<usize> {
+        self.source.current_frame_len()
+    }
+
+    fn channels(&self) -> u16 {
+        self.source.channels()
+    }
+
+    fn sample_rate(&self) -> u32 {
+        self.source.sample_rate()
+    }
+
+    fn total_duration(&self) -> Option<Duration> {
+        self.source.total_duration()
+    }
+}
+
+fn setup(
+    mut commands: Commands,
+    global_settings: Res<GlobalSettings>,
+    active_theme: Res<ActiveTheme>,
+    query_error: Query<Entity, With<AudioLoadErrorText>>,
+    query_camera: Query<Entity, With<MainWindowCamera>>,
+) {
+    for entity in query_error.iter() {
+        commands.entity(entity).despawn_recursive();
+    }
+
+    let config = &global_settings.config;
+    if config.synth.enabled {
+        // 小節ごとに分割したノートでは、小節線のたびに音が鳴り直してしまう
+        let synth = Synth::new(&global_settings.unsplit_midi_notes_vec, &config.synth);
+        commands.insert_resource(MainAudioSource(Some(PlayableAudio::Synth(synth))));
+        return;
+    }
+
+    match audio_file::load_audio_file(&config.main_config.wave_file_path) {
+        Ok((audio_source, format)) => {
+            info!(
+                "loaded {} audio file {}",
+                format, config.main_config.wave_file_path
+            );
+            commands.insert_resource(MainAudioSource(Some(PlayableAudio::File(audio_source))));
+        }
+        Err(e) => {
+            // 無音のまま気づかずに再生しないように、エラーを画面に重ねて表示する
+            error!("{}", e);
+            let text_color = active_theme.color(&config.theme, ThemeColor::Accent);
+            commands.spawn((
+                AudioLoadErrorText,
+                ThemeColor::Accent,
+                Node {
+                    position_type: PositionType::Absolute,
+                    left: Val::Px(20.0),
+                    top: Val::Px(20.0),
+                    ..default()
+                },
+                Text::new(format!("Failed to load audio file\n{}", e)),
+                TextColor(text_color),
+                TargetCamera(query_camera.single()),
+            ));
+            commands.insert_resource(MainAudioSource(None));
+        }
+    }
+}
+
+/// 設定やMIDIが変わったときは、作り直したオーディオを現在の位置から鳴らし直す
+fn restart_audio(mut commands: Commands, query_audio: Query<Entity, With<MainAudioComponent>>) {
+    for entity in query_audio.iter() {
+        commands.entity(entity).despawn_recursive();
+    }
+    commands.remove_resource::<AudioClock>();
+}
+
+fn read_keyboard(keys: Res<ButtonInput<KeyCode>>, mut commands: EventWriter<TransportCommand>) {
+    let is_shift_pressed = keys.any_pressed([KeyCode::ShiftLeft, KeyCode::ShiftRight]);
+    for key in keys.get_just_pressed() {
+        let command = match key {
+            KeyCode::Space => TransportCommand::TogglePlayOrStop,
+            KeyCode::KeyP => TransportCommand::TogglePause,
+            KeyCode::Home => TransportCommand::SeekMeasureAndBeat {
+                measure: 0,
+                beat: 1,
+            },
+            KeyCode::ArrowLeft if is_shift_pressed => TransportCommand::SkipBeats(-1),
+            KeyCode::ArrowRight if is_shift_pressed => TransportCommand::SkipBeats(1),
+            KeyCode::ArrowLeft => TransportCommand::SkipMeasures(-1),
+            KeyCode::ArrowRight => TransportCommand::SkipMeasures(1),
+            KeyCode::BracketLeft => TransportCommand::SetLoopStart,
+            KeyCode::BracketRight => TransportCommand::SetLoopEnd,
+            KeyCode::Backslash => TransportCommand::ClearLoop,
+            _ => continue,
+        };
+        commands.send(command);
+    }
+}
+
+/// ドラッグ中に、この秒数より離れた位置へ動いたときだけオーディオを作り直す
+const SCRUB_AUDIO_RESTART_SECONDS: f32 = 0.5;
+
+/// トランスポートパネルの上をドラッグすると、横位置に応じて曲全体の中の位置へ移動する
+///
+/// ドラッグ中は毎フレームオーディオを作り直さないように`ScrubSeconds`を送り、
+/// 離したときに最後の位置へ`SeekSeconds`を送って鳴らし直す。
+fn scrub_with_mouse(
+    mouse_buttons: Res<ButtonInput<MouseButton>>,
+    windows: Query<&Window, With<PrimaryWindow>>,
+    global_settings: Res<GlobalSettings>,
+    mut commands: EventWriter<TransportCommand>,
+    mut scrub_seconds: Local<Option<f32>>, // ドラッグ中の最後の移動先
+) {
+    if !mouse_buttons.pressed(MouseButton::Left) {
+        if let Some(seconds) = scrub_seconds.take() {
+            commands.send(TransportCommand::SeekSeconds(seconds));
+        }
+        return;
+    }
+    let Ok(window) = windows.get_single() else {
+        return;
+    };
+    let Some(cursor_position) = window.cursor_position() else {
+        return;
+    };
+    let config = &global_settings.config;
+    let Some(rect) = config.feature_and_layout.transport_panel.calculate_rect(
+        config.main_config.window_width,
+        config.main_config.window_height,
+    ) else {
+        return;
+    };
+
+    // 左上原点のカーソル位置を、中心を原点とする座標系に変換
+    let x = cursor_position.x - window.width() / 2.0;
+    let y = window.height() / 2.0 - cursor_position.y;
+    if x < rect.left_bottom_abs_pixel.0
+        || x > rect.right_top_abs_pixel.0
+        || y < rect.left_bottom_abs_pixel.1
+        || y > rect.right_top_abs_pixel.1
+    {
+        return;
+    }
+
+    let ratio = (x - rect.left_bottom_abs_pixel.0) / rect.width_pixel;
+    let seconds = ratio * global_settings.tempo_map.last_time_axis().seconds_total;
+    if *scrub_seconds != Some(seconds) {
+        *scrub_seconds = Some(seconds);
+        commands.send(TransportCommand::ScrubSeconds(seconds));
+    }
+}
+
+/// ループの終点、または曲末に達したときの処理
+fn apply_loop_region(
+    app_state: Res<State<AppState>>,
+    global_settings: Res<GlobalSettings>,
+    global_monitor_values: Res<GlobalMonitorValues>,
+    loop_region: Res<LoopRegion>,
+    mut commands: EventWriter<TransportCommand>,
+) {
+    if app_state.get() != &AppState::Playing {
+        return;
+    }
+    let tempo_map = &global_settings.tempo_map;
+    let elapsed_seconds = global_monitor_values.elapsed_time_from_start.elapsed_secs();
+
+    if let Some((start_ticks, end_ticks)) = loop_region.range() {
+        if elapsed_seconds >= tempo_map.time_axis_from_ticks(end_ticks).seconds_total {
+            commands.send(TransportCommand::SeekSeconds(
+                tempo_map.time_axis_from_ticks(start_ticks).seconds_total,
+            ));
+            return;
+        }
+    }
+    if elapsed_seconds >= tempo_map.last_time_axis().seconds_total {
+        commands.send(TransportCommand::TogglePlayOrStop);
+    }
+}
+
+#[allow(clippy::too_many_arguments)]
+fn apply_transport_commands(
+    mut commands: Commands,
+    mut transport_commands: EventReader<TransportCommand>,
+    app_state: Res<State<AppState>>,
+    mut next_app_state: ResMut<NextState<AppState>>,
+    global_settings: Res<GlobalSettings>,
+    mut global_monitor_values: ResMut<GlobalMonitorValues>,
+    mut loop_region: ResMut<LoopRegion>,
+    mut transport_seeked: EventWriter<TransportSeeked>,
+    query_audio: Query<(Entity, Option<&AudioSink>), With<MainAudioComponent>>,
+    mut scrub_audio_seconds: Local<Option<f32>>, // ドラッグ中に最後にオーディオを作り直した位置
+) {
+    let tempo_map = &global_settings.tempo_map;
+    // 同じフレームの中で続けて操作しても、直前の操作の結果から計算する
+    let mut state = *app_state.get();
+    let mut current_time_axis = global_monitor_values.current_time_axis;
+
+    for transport_command in transport_commands.read() {
+        let seek_ticks = match *transport_command {
+            TransportCommand::TogglePlayOrStop => {
+                if state == AppState::Stop {
+                    state = AppState::Playing;
+                    let start_seconds = global_settings.config.main_config.start_seconds;
+                    Some(tempo_map.ticks_from_seconds(start_seconds))
+                } else {
+                    state = AppState::Stop;
+                    Some(0)
+                }
+            }
+            TransportCommand::TogglePause => {
+                match state {
+                    AppState::Playing => state = AppState::Paused,
+                    AppState::Paused => state = AppState::Playing,
+                    AppState::Stop => {}
+                }
+                None
+            }
+            TransportCommand::SeekSeconds(seconds) | TransportCommand::ScrubSeconds(seconds) => {
+                Some(tempo_map.ticks_from_seconds(seconds))
+            }
+            TransportCommand::SeekMeasureAndBeat { measure, beat } => {
+                Some(tempo_map.ticks_from_measure_and_beat(measure, beat))
+            }
+            TransportCommand::SkipMeasures(count) => Some(tempo_map.ticks_from_measure_and_beat(
+                current_time_axis.measure.saturating_add_signed(count),
+                current_time_axis.beat,
+            )),
+            TransportCommand::SkipBeats(count) => {
+                // 拍の途中にいる場合、前に戻る操作はまずその拍の先頭に戻る
+                let count = match count < 0 && current_time_axis.ticks_reset_by_beat > 0 {
+                    true => count + 1,
+                    false => count,
+                };
+                let beat_start_ticks =
+                    current_time_axis.ticks_total - current_time_axis.ticks_reset_by_beat;
+                Some(
+                    beat_start_ticks
+                        .saturating_add_signed(count * current_time_axis.beat_length_ticks as i32),
+                )
+            }
+            TransportCommand::SetLoopStart => {
+                loop_region.start_ticks =
+                    Some(tempo_map.ticks_from_measure(current_time_axis.measure));
+                None
+            }
+            TransportCommand::SetLoopEnd => {
+                loop_region.end_ticks =
+                    Some(tempo_map.ticks_from_measure(current_time_axis.measure + 1));
+                None
+            }
+            TransportCommand::ClearLoop => {
+                *loop_region = LoopRegion::default();
+                None
+            }
+        };
+
+        if let Some(seek_ticks) = seek_ticks {
+            // 停止中に位置を動かした場合は、その位置で一時停止する
+            if state == AppState::Stop && seek_ticks > 0 {
+                state = AppState::Paused;
+            }
+            current_time_axis = tempo_map
+                .time_axis_from_ticks(seek_ticks.min(tempo_map.last_time_axis().ticks_total));
+            global_monitor_values
+                .elapsed_time_from_start
+                .set_elapsed(Duration::from_secs_f32(current_time_axis.seconds_total));
+            global_monitor_values.current_time_axis = current_time_axis;
+
+            // オーディオは新しい位置で作り直す。ドラッグ中は近くへの移動なら鳴らし続け、
+            // 経過時間を古いオーディオの時計に戻されないように時計だけ外す
+            let restart_audio = match *transport_command {
+                TransportCommand::ScrubSeconds(seconds) => match *scrub_audio_seconds {
+                    Some(audio_seconds)
+                        if (seconds - audio_seconds).abs() <= SCRUB_AUDIO_RESTART_SECONDS =>
+                    {
+                        false
+                    }
+                    _ => {
+                        *scrub_audio_seconds = Some(seconds);
+                        true
+                    }
+                },
+                _ => {
+                    *scrub_audio_seconds = None;
+                    true
+                }
+            };
+            if restart_audio {
+                for (entity, _) in query_audio.iter() {
+                    commands.entity(entity).despawn_recursive();
+                }
+            }
+            commands.remove_resource::<AudioClock>();
+            transport_seeked.send(TransportSeeked);
+        }
+    }
+
+    if &state != app_state.get() {
+        next_app_state.set(state);
+        for (_, audio_sink) in query_audio.iter() {
+            match (state, audio_sink) {
+                (AppState::Playing, Some(audio_sink)) => audio_sink.play(),
+                (AppState::Paused, Some(audio_sink)) => audio_sink.pause(),
+                _ => {}
+            }
+        }
+        if state == AppState::Stop {
+            for (entity, _) in query_audio.iter() {
+                commands.entity(entity).despawn_recursive();
+            }
+            commands.remove_resource::<AudioClock>();
+        }
+    }
+}
+
+/// 再生中にオーディオが無ければ、現在の位置から鳴らし始める
+#[allow(clippy::too_many_arguments)]
+fn sync_audio(
+    mut commands: Commands,
+    app_state: Res<State<AppState>>,
+    next_app_state: Res<NextState<AppState>>,
+    global_settings: Res<GlobalSettings>,
+    global_monitor_values: Res<GlobalMonitorValues>,
+    main_audio_source: Res<MainAudioSource>,
+    mut audio_from_positions: ResMut<Assets<AudioFromPosition>>,
+    query_audio: Query<(), With<MainAudioComponent>>,
+) {
+    // 状態の変更はこのフレームの終わりに反映されるので、変更先の状態で判断する
+    let state = match next_app_state.as_ref() {
+        NextState::Pending(state) => state,
+        NextState::Unchanged => app_state.get(),
+    };
+    if state != &AppState::Playing || !query_audio.is_empty() {
+        return;
+    }
+    let Some(audio) = main_audio_source.0.clone() else {
+        return;
+    };
+
+    // 経過時間は、鳴らし始めてからはこの時計からaudio_offset_ms引いた位置に合わせるので、
+    // その分だけ先から鳴らし始めて、最初の同期で映像が飛ばないようにする
+    let start_seconds = global_monitor_values.elapsed_time_from_start.elapsed_secs()
+        + global_settings.config.main_config.audio_offset_ms / 1000.0;
+    let start_position = Duration::from_secs_f32(start_seconds.max(0.0));
+    let audio_clock = AudioClock::new(start_position);
+    let audio_from_position = audio_from_positions.add(AudioFromPosition {
+        audio,
+        start_position,
+        audio_clock: audio_clock.clone(),
+    });
+    commands.insert_resource(audio_clock);
+    commands.spawn((
+        AudioPlayer(audio_from_position),
+        MainAudioComponent,
+        PlaybackSettings {
+            mode: PlaybackMode::Once,
+            ..default()
+        },
+    ));
+}
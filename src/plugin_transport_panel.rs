@@ -1,6 +1,7 @@
-use crate::global_vars::{GlobalMonitorValues, GlobalSettings, MainWindowCamera, MidiNote};
-use crate::util_color;
-use bevy::log::tracing_subscriber::fmt::time;
+use crate::global_vars::{
+    ActiveTheme, ConfigReloaded, GlobalMonitorValues, GlobalSettings, ThemeColor,
+};
+use crate::plugin_config_hot_reload::despawn_with;
 use bevy::prelude::*;
 use bevy::render::view::RenderLayers;
 use bevy::sprite::Anchor;
@@ -9,19 +10,26 @@ pub struct TransportPanelPlugin;
 
 impl Plugin for TransportPanelPlugin {
     fn build(&self, app: &mut App) {
-        app.add_systems(PostStartup, setup).add_systems(
-            Update,
-            (
-                update_elapsed_time_minutes_text,
-                update_elapsed_time_seconds_text,
-                update_elapsed_time_millis_text,
-                update_measure_text,
-                update_beat_text,
-                update_tick_reset_by_beat_text,
-                update_beat_bar,
-                update_measure_bar,
-            ),
-        );
+        app.add_systems(PostStartup, setup)
+            .add_systems(
+                Update,
+                (
+                    update_elapsed_time_minutes_text,
+                    update_elapsed_time_seconds_text,
+                    update_elapsed_time_millis_text,
+                    update_measure_text,
+                    update_beat_text,
+                    update_tick_reset_by_beat_text,
+                    update_beat_bar,
+                    update_measure_bar,
+                ),
+            )
+            .add_systems(
+                Update,
+                (despawn_with::<TransportPanelRoot>, setup)
+                    .chain()
+                    .run_if(on_event::<ConfigReloaded>),
+            );
     }
 }
 
@@ -64,27 +72,18 @@ struct MeasureBar;
 
 fn setup(
     mut commands: Commands,
-    query: Query<Entity, With<MainWindowCamera>>,
     global_settings: Res<GlobalSettings>,
+    active_theme: Res<ActiveTheme>,
     asset_server: Res<AssetServer>,
     mut meshes: ResMut<Assets<Mesh>>,
     mut materials: ResMut<Assets<ColorMaterial>>,
 ) {
-    let main_window_camera = commands.entity(query.single()).id();
     let font: Handle<Font> = asset_server.load("fonts\\NotoSansJP-Thin.ttf");
     let color_levels: Vec<_> = (0..=4)
-        .map(|level| {
-            util_color::adjust_color(
-                &global_settings.config.theme[0].main_base_hex,
-                &global_settings.config.theme[0].background_hex,
-                level,
-                4,
-            )
-            .unwrap()
-        })
+        .map(|level| active_theme.color(&global_settings.config.theme, ThemeColor::Main(level)))
         .collect();
 
-    let transport_panel_layout = global_settings
+    let Some(transport_panel_layout) = global_settings
         .config
         .feature_and_layout
         .transport_panel
@@ -92,7 +91,9 @@ fn setup(
             global_settings.config.main_config.window_width,
             global_settings.config.main_config.window_height,
         )
-        .unwrap();
+    else {
+        return;
+    };
 
     let transport_panel_root_entity = commands
         .spawn((
@@ -112,7 +113,7 @@ fn setup(
     let time_text_font_size = 40.0;
     let measure_beat_ticks_text_y = 0.0;
     let measure_beat_ticks_font_size = 40.0;
-    let column_start_vec = vec![0.0, 90.0, 160.0];
+    let column_start_vec = [0.0, 90.0, 160.0];
 
     commands
         .entity(transport_panel_root_entity)
@@ -127,11 +128,8 @@ fn setup(
                     font_size: time_text_font_size,
                     ..default()
                 },
-                TextColor(Color::srgb(
-                    color_levels[0][0],
-                    color_levels[0][1],
-                    color_levels[0][2],
-                )),
+                TextColor(color_levels[0]),
+                ThemeColor::Main(0),
                 Anchor::BottomLeft,
             ));
             parent.spawn((
@@ -144,11 +142,8 @@ fn setup(
                     font_size: time_text_font_size,
                     ..default()
                 },
-                TextColor(Color::srgb(
-                    color_levels[0][0],
-                    color_levels[0][1],
-                    color_levels[0][2],
-                )),
+                TextColor(color_levels[0]),
+                ThemeColor::Main(0),
                 Anchor::BottomLeft,
             ));
             parent
@@ -162,11 +157,8 @@ fn setup(
                         font_size: time_text_font_size,
                         ..default()
                     },
-                    TextColor(Color::srgb(
-                        color_levels[0][0],
-                        color_levels[0][1],
-                        color_levels[0][2],
-                    )),
+                    TextColor(color_levels[0]),
+                    ThemeColor::Main(0),
                     Anchor::BottomLeft,
                 ))
                 .with_children(|parent| {
@@ -179,11 +171,8 @@ fn setup(
                             font_size: 20.0,
                             ..default()
                         },
-                        TextColor(Color::srgb(
-                            color_levels[1][0],
-                            color_levels[1][1],
-                            color_levels[1][2],
-                        )),
+                        TextColor(color_levels[1]),
+                        ThemeColor::Main(1),
                         Anchor::BottomLeft,
                     ));
                 });
@@ -198,11 +187,8 @@ fn setup(
                     font_size: measure_beat_ticks_font_size,
                     ..default()
                 },
-                TextColor(Color::srgb(
-                    color_levels[0][0],
-                    color_levels[0][1],
-                    color_levels[0][2],
-                )),
+                TextColor(color_levels[0]),
+                ThemeColor::Main(0),
                 Anchor::BottomLeft,
             ));
             parent
@@ -216,11 +202,8 @@ fn setup(
                         font_size: measure_beat_ticks_font_size,
                         ..default()
                     },
-                    TextColor(Color::srgb(
-                        color_levels[0][0],
-                        color_levels[0][1],
-                        color_levels[0][2],
-                    )),
+                    TextColor(color_levels[0]),
+                    ThemeColor::Main(0),
                     Anchor::BottomLeft,
                 ))
                 .with_children(|parent| {
@@ -230,11 +213,8 @@ fn setup(
                         Transform::from_xyz(0.0, 0.0, 0.0).with_scale(Vec3::new(0.0, 1.0, 1.0)),
                         GlobalTransform::default(),
                         Mesh2d(meshes.add(Rectangle::new(40.0, 1.0))),
-                        MeshMaterial2d(materials.add(Color::srgb(
-                            color_levels[0][0],
-                            color_levels[0][1],
-                            color_levels[0][2],
-                        ))),
+                        MeshMaterial2d(materials.add(color_levels[0])),
+                        ThemeColor::Main(0),
                         Anchor::BottomLeft,
                     ));
                 });
@@ -249,11 +229,8 @@ fn setup(
                         font_size: measure_beat_ticks_font_size,
                         ..default()
                     },
-                    TextColor(Color::srgb(
-                        color_levels[0][0],
-                        color_levels[0][1],
-                        color_levels[0][2],
-                    )),
+                    TextColor(color_levels[0]),
+                    ThemeColor::Main(0),
                     Anchor::BottomLeft,
                 ))
                 .with_children(|parent| {
@@ -266,11 +243,8 @@ fn setup(
                             font_size: 20.0,
                             ..default()
                         },
-                        TextColor(Color::srgb(
-                            color_levels[1][0],
-                            color_levels[1][1],
-                            color_levels[1][2],
-                        )),
+                        TextColor(color_levels[1]),
+                        ThemeColor::Main(1),
                         Anchor::BottomLeft,
                     ));
                     parent.spawn((
@@ -279,11 +253,8 @@ fn setup(
                         Transform::from_xyz(0.0, 0.0, 0.0).with_scale(Vec3::new(0.0, 1.0, 1.0)),
                         GlobalTransform::default(),
                         Mesh2d(meshes.add(Rectangle::new(62.0, 1.0))),
-                        MeshMaterial2d(materials.add(Color::srgb(
-                            color_levels[0][0],
-                            color_levels[0][1],
-                            color_levels[0][2],
-                        ))),
+                        MeshMaterial2d(materials.add(color_levels[0])),
+                        ThemeColor::Main(0),
                         Anchor::BottomLeft,
                     ));
                 });
@@ -292,7 +263,6 @@ fn setup(
 
 fn update_elapsed_time_minutes_text(
     global_monitor_values: Res<GlobalMonitorValues>,
-    global_settings: Res<GlobalSettings>,
     mut query: Query<&mut Text2d, With<ElapsedTimeMinutesText>>,
 ) {
     let time_axis = global_monitor_values.current_time_axis;
@@ -308,7 +278,6 @@ fn update_elapsed_time_minutes_text(
 
 fn update_elapsed_time_seconds_text(
     global_monitor_values: Res<GlobalMonitorValues>,
-    global_settings: Res<GlobalSettings>,
     mut query: Query<&mut Text2d, With<ElapsedTimeSecondsText>>,
 ) {
     let time_axis = global_monitor_values.current_time_axis;
@@ -331,16 +300,23 @@ fn update_elapsed_time_millis_text(
 
     for mut text in &mut query {
         text.clear();
-        text.push_str(&format!(
-            ".{:03}",
-            (time_axis.seconds_total.fract() * 1000.0).floor() as u32
-        ));
+        if let Some(fps) = global_settings.smpte_fps {
+            // タイムコード形式のMIDIでは、ミリ秒の代わりにフレームを表示する
+            text.push_str(&format!(
+                ":{:02}",
+                (time_axis.seconds_total.fract() * fps.as_f32()).floor() as u32
+            ));
+        } else {
+            text.push_str(&format!(
+                ".{:03}",
+                (time_axis.seconds_total.fract() * 1000.0).floor() as u32
+            ));
+        }
     }
 }
 
 fn update_measure_text(
     global_monitor_values: Res<GlobalMonitorValues>,
-    global_settings: Res<GlobalSettings>,
     mut query: Query<&mut Text2d, With<MeasureText>>,
 ) {
     let time_axis = global_monitor_values.current_time_axis;
@@ -353,7 +329,6 @@ fn update_measure_text(
 
 fn update_beat_text(
     global_monitor_values: Res<GlobalMonitorValues>,
-    global_settings: Res<GlobalSettings>,
     mut query: Query<&mut Text2d, With<BeatText>>,
 ) {
     let time_axis = global_monitor_values.current_time_axis;
@@ -366,7 +341,6 @@ fn update_beat_text(
 
 fn update_tick_reset_by_beat_text(
     global_monitor_values: Res<GlobalMonitorValues>,
-    global_settings: Res<GlobalSettings>,
     mut query: Query<&mut Text2d, With<TickResetByBeatText>>,
 ) {
     let time_axis = global_monitor_values.current_time_axis;
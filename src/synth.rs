@@ -0,0 +1,161 @@
+use crate::global_vars::{MidiNote, SynthConfig, SynthWaveform};
+use bevy::audio::Source;
+use std::f32::consts::TAU;
+use std::sync::Arc;
+use std::time::Duration;
+
+/// ドラム用のチャンネル(ch.10)。音程を持たないので鳴らさない
+const DRUM_CHANNEL: u32 = 9;
+
+/// シンセサイザーで鳴らす1音
+#[derive(Clone, Copy, Debug)]
+struct SynthNote {
+    start_seconds: f32,
+    end_seconds: f32, // 離鍵、またはペダルによる余韻の終端
+    frequency: f32,
+    amplitude: f32,
+}
+
+/// 読み込んだMIDIのノートを、オシレーターで鳴らす内蔵のシンセサイザー
+///
+/// 事前に書き出さず、オーディオのスレッドが読み出すたびにその時刻の音を合成するので、
+/// どの位置からでもすぐに鳴らし始められる。
+#[derive(Clone)]
+pub struct Synth {
+    notes: Arc<Vec<SynthNote>>, // 開始時刻の昇順
+    config: SynthConfig,
+}
+
+impl Synth {
+    pub fn new(midi_notes_vec: &[Vec<MidiNote>], config: &SynthConfig) -> Self {
+        let mut notes: Vec<SynthNote> = midi_notes_vec
+            .iter()
+            .flatten()
+            .filter(|x| x.channel != DRUM_CHANNEL)
+            .filter_map(|x| {
+                let end_time_axis = x.sustain_off_time_axis.or(x.note_off_time_axis)?;
+                Some(SynthNote {
+                    start_seconds: x.note_on_time_axis.seconds_total,
+                    end_seconds: end_time_axis.seconds_total,
+                    frequency: 440.0 * 2.0_f32.powf((x.key as f32 - 69.0) / 12.0),
+                    amplitude: x.velocity as f32 / 127.0,
+                })
+            })
+            .collect();
+        notes.sort_by(|a, b| a.start_seconds.total_cmp(&b.start_seconds));
+
+        Self {
+            notes: Arc::new(notes),
+            config: config.clone(),
+        }
+    }
+
+    /// 指定した位置から鳴らすSource
+    pub fn source(&self, start_position: Duration) -> SynthSource {
+        let sample_rate = self.config.sample_rate.max(1);
+        SynthSource {
+            synth: self.clone(),
+            sample_rate,
+            sample_index: (start_position.as_secs_f64() * sample_rate as f64) as u64,
+            next_note_index: 0,
+            active_note_indices: Vec::new(),
+        }
+    }
+}
+
+/// モノラルで合成したサンプルを返す
+pub struct SynthSource {
+    synth: Synth,
+    sample_rate: u32,
+    sample_index: u64,
+    next_note_index: usize,          // まだ鳴らし始めていない最初のノート
+    active_note_indices: Vec<usize>, // 鳴っているノート
+}
+
+impl SynthSource {
+    fn oscillate(&self, phase: f32) -> f32 {
+        match self.synth.config.waveform {
+            SynthWaveform::Sine => (phase * TAU).sin(),
+            SynthWaveform::Triangle => 1.0 - 4.0 * (phase - 0.5).abs(),
+            SynthWaveform::Square => match phase < 0.5 {
+                true => 1.0,
+                false => -1.0,
+            },
+            SynthWaveform::Sawtooth => 2.0 * phase - 1.0,
+        }
+    }
+
+    /// 立ち上がりと余韻の音量。0.0～1.0
+    fn envelope(&self, note: &SynthNote, seconds: f32) -> f32 {
+        let config = &self.synth.config;
+        let attack = match config.attack_seconds > 0.0 {
+            true => ((seconds - note.start_seconds) / config.attack_seconds).min(1.0),
+            false => 1.0,
+        };
+        let release = match seconds > note.end_seconds {
+            true if config.release_seconds > 0.0 => {
+                1.0 - (seconds - note.end_seconds) / config.release_seconds
+            }
+            true => 0.0,
+            false => 1.0,
+        };
+        (attack * release).max(0.0)
+    }
+}
+
+impl Iterator for SynthSource {
+    type Item = f32;
+
+    fn next(&mut self) -> Option<f32> {
+        let notes = self.synth.notes.clone();
+        // 長い曲でも位相がずれないように、時刻はf64で計算する
+        let seconds = self.sample_index as f64 / self.sample_rate as f64;
+        let release_seconds = self.synth.config.release_seconds.max(0.0) as f64;
+
+        // 鳴らし始めるノートを加え、余韻まで終わったノートを外す
+        while let Some(note) = notes.get(self.next_note_index) {
+            if note.start_seconds as f64 > seconds {
+                break;
+            }
+            if note.end_seconds as f64 + release_seconds > seconds {
+                self.active_note_indices.push(self.next_note_index);
+            }
+            self.next_note_index += 1;
+        }
+        self.active_note_indices
+            .retain(|&i| notes[i].end_seconds as f64 + release_seconds > seconds);
+        if self.active_note_indices.is_empty() && self.next_note_index >= notes.len() {
+            return None;
+        }
+
+        let mut value = 0.0;
+        for &i in &self.active_note_indices {
+            let note = &notes[i];
+            let phase = ((seconds - note.start_seconds as f64) * note.frequency as f64).fract();
+            value +=
+                self.oscillate(phase as f32) * note.amplitude * self.envelope(note, seconds as f32);
+        }
+        self.sample_index += 1;
+
+        // 和音で音が割れないように、大きな値をなだらかに抑える
+        Some((value * self.synth.config.volume).tanh())
+    }
+}
+
+impl Source for SynthSource {
+    fn current_frame_len(&self) -> Option<usize> {
+        None
+    }
+
+    fn channels(&self) -> u16 {
+        1
+    }
+
+    fn sample_rate(&self) -> u32 {
+        self.sample_rate
+    }
+
+    fn total_duration(&self) -> Option<Duration> {
+        None
+    }
+}
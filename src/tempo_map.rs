@@ -0,0 +1,485 @@
+use crate::global_vars::TimeAxis;
+
+pub struct TempoChangeEvent {
+    pub tempo: f32,            // how many beats per minute
+    pub total_ticks: u32,      // how many ticks before the tempo change
+    pub seconds_per_tick: f64, // how many seconds per tick after the tempo change
+}
+
+pub struct TimeSignatureChangeEvent {
+    pub numerator: u8,
+    pub denominator: u8,
+    pub midi_clocks_per_metronome_click: u8,
+    pub thirty_seconds_notes_per_quarter_note: u8,
+    pub total_ticks: u32, // how many ticks before the time signature change
+}
+
+pub struct KeySignatureChangeEvent {
+    pub sharps: i8,       // シャープの数。負の値はフラットの数
+    pub minor: bool,      // 短調かどうか
+    pub total_ticks: u32, // how many ticks before the key signature change
+}
+
+/// テンポ・拍子・調が一定の区間
+#[derive(Clone, Copy, Debug)]
+struct TempoSegment {
+    ticks_start: u32,
+    seconds_start: f64,
+    seconds_per_tick: f64,
+    measure_start: u32,                // 区間開始時点の小節数
+    ticks_reset_by_measure_start: u32, // 区間開始時点の小節内tick
+    tempo: f32,
+    time_signature_numerator: u8,
+    time_signature_denominator: u8,
+    time_signature_midi_clocks_per_metronome_click: u8,
+    time_signature_thirty_seconds_notes_per_quarter_note: u8,
+    key_signature_sharps: i8,
+    key_signature_minor: bool,
+}
+
+impl TempoSegment {
+    fn measure_length_ticks(&self, ppm: u16) -> u32 {
+        ppm as u32 * self.time_signature_numerator as u32 * 4
+            / self.time_signature_denominator as u32
+    }
+
+    fn beat_length_ticks(&self, ppm: u16) -> u32 {
+        ppm as u32 * 4 / self.time_signature_denominator as u32
+    }
+
+    /// 区間内のtickから(小節数, 小節内tick)を計算する
+    fn measure_and_ticks_reset_by_measure(&self, ppm: u16, ticks: u32) -> (u32, u32) {
+        let measure_length_ticks = self.measure_length_ticks(ppm);
+        let offset = self.ticks_reset_by_measure_start + (ticks - self.ticks_start);
+        (
+            self.measure_start + offset / measure_length_ticks,
+            offset % measure_length_ticks,
+        )
+    }
+}
+
+/// テンポ・拍子・調の変化点だけを保持するマップ
+///
+/// tickごとにTimeAxisを持つ代わりに区間の列を保持し、
+/// tick・秒・小節の相互変換を二分探索で行う。
+#[derive(Clone, Debug)]
+pub struct TempoMap {
+    ppm: u16,
+    end_ticks: u32,
+    segments: Vec<TempoSegment>, // ticks_startの昇順。先頭は必ずtick 0
+}
+
+impl TempoMap {
+    pub fn new(
+        ppm: u16,
+        end_ticks: u32,
+        mut tempo_change_events: Vec<TempoChangeEvent>,
+        mut time_signature_change_events: Vec<TimeSignatureChangeEvent>,
+        mut key_signature_change_events: Vec<KeySignatureChangeEvent>,
+    ) -> Self {
+        // 同じtickのイベントは後に現れたものを優先するため、安定ソートを使う
+        tempo_change_events.sort_by_key(|x| x.total_ticks);
+        time_signature_change_events.sort_by_key(|x| x.total_ticks);
+        key_signature_change_events.sort_by_key(|x| x.total_ticks);
+
+        let mut segments = vec![TempoSegment {
+            ticks_start: 0,
+            seconds_start: 0.0,
+            seconds_per_tick: 60.0 / 120.0 / ppm as f64,
+            measure_start: 0,
+            ticks_reset_by_measure_start: 0,
+            tempo: 120.0,
+            time_signature_numerator: 4,
+            time_signature_denominator: 4,
+            time_signature_midi_clocks_per_metronome_click: 24,
+            time_signature_thirty_seconds_notes_per_quarter_note: 8,
+            key_signature_sharps: 0,
+            key_signature_minor: false,
+        }];
+
+        let mut tempo_iter = tempo_change_events.iter().peekable();
+        let mut time_signature_iter = time_signature_change_events.iter().peekable();
+        let mut key_signature_iter = key_signature_change_events.iter().peekable();
+
+        // 次に変化が起こるtick
+        while let Some(next_ticks) = [
+            tempo_iter.peek().map(|x| x.total_ticks),
+            time_signature_iter.peek().map(|x| x.total_ticks),
+            key_signature_iter.peek().map(|x| x.total_ticks),
+        ]
+        .into_iter()
+        .flatten()
+        .min()
+        {
+            let previous = *segments.last().unwrap();
+            let (measure_start, ticks_reset_by_measure_start) =
+                previous.measure_and_ticks_reset_by_measure(ppm, next_ticks);
+            let mut segment = TempoSegment {
+                ticks_start: next_ticks,
+                seconds_start: previous.seconds_start
+                    + (next_ticks - previous.ticks_start) as f64 * previous.seconds_per_tick,
+                measure_start,
+                ticks_reset_by_measure_start,
+                ..previous
+            };
+
+            while let Some(event) = tempo_iter.next_if(|x| x.total_ticks == next_ticks) {
+                segment.tempo = event.tempo;
+                segment.seconds_per_tick = event.seconds_per_tick;
+            }
+            while let Some(event) = time_signature_iter.next_if(|x| x.total_ticks == next_ticks) {
+                segment.time_signature_numerator = event.numerator;
+                segment.time_signature_denominator = event.denominator;
+                segment.time_signature_midi_clocks_per_metronome_click =
+                    event.midi_clocks_per_metronome_click;
+                segment.time_signature_thirty_seconds_notes_per_quarter_note =
+                    event.thirty_seconds_notes_per_quarter_note;
+            }
+            while let Some(event) = key_signature_iter.next_if(|x| x.total_ticks == next_ticks) {
+                segment.key_signature_sharps = event.sharps;
+                segment.key_signature_minor = event.minor;
+            }
+
+            // 拍子が小節の途中で変わった場合は、その時点から新しい小節とする
+            if segment.ticks_reset_by_measure_start >= segment.measure_length_ticks(ppm) {
+                segment.measure_start += 1;
+                segment.ticks_reset_by_measure_start = 0;
+            }
+
+            if previous.ticks_start == next_ticks {
+                *segments.last_mut().unwrap() = segment;
+            } else {
+                segments.push(segment);
+            }
+        }
+
+        Self {
+            ppm,
+            end_ticks,
+            segments,
+        }
+    }
+
+    fn segment_at_ticks(&self, ticks: u32) -> &TempoSegment {
+        let index = self.segments.partition_point(|x| x.ticks_start <= ticks);
+        &self.segments[index - 1]
+    }
+
+    /// tickからTimeAxisを生成する
+    pub fn time_axis_from_ticks(&self, ticks: u32) -> TimeAxis {
+        let segment = self.segment_at_ticks(ticks);
+        let (measure, ticks_reset_by_measure) =
+            segment.measure_and_ticks_reset_by_measure(self.ppm, ticks);
+        let beat_length_ticks = segment.beat_length_ticks(self.ppm);
+
+        TimeAxis {
+            ticks_total: ticks,
+            seconds_total: (segment.seconds_start
+                + (ticks - segment.ticks_start) as f64 * segment.seconds_per_tick)
+                as f32,
+            measure,
+            ticks_reset_by_measure,
+            beat: ticks_reset_by_measure / beat_length_ticks
+                % segment.time_signature_numerator as u32
+                + 1,
+            ticks_reset_by_beat: ticks_reset_by_measure % beat_length_ticks,
+            measure_length_ticks: segment.measure_length_ticks(self.ppm),
+            beat_length_ticks,
+            tempo: segment.tempo,
+            time_signature_numerator: segment.time_signature_numerator,
+            time_signature_denominator: segment.time_signature_denominator,
+            time_signature_midi_clocks_per_metronome_click: segment
+                .time_signature_midi_clocks_per_metronome_click,
+            time_signature_thirty_seconds_notes_per_quarter_note: segment
+                .time_signature_thirty_seconds_notes_per_quarter_note,
+            key_signature_sharps: segment.key_signature_sharps,
+            key_signature_minor: segment.key_signature_minor,
+        }
+    }
+
+    /// 経過秒数から、その時点以前で最後のtickを求める。範囲外は曲頭・曲末に丸める
+    pub fn ticks_from_seconds(&self, seconds: f32) -> u32 {
+        self.exact_ticks_from_seconds(seconds).floor() as u32
+    }
+
+    /// 経過秒数から、tickの間の位置も含めたtickを求める。範囲外は曲頭・曲末に丸める
+    pub fn exact_ticks_from_seconds(&self, seconds: f32) -> f64 {
+        let seconds = seconds.max(0.0) as f64;
+        let index = self
+            .segments
+            .partition_point(|x| x.seconds_start <= seconds);
+        let segment = &self.segments[index.max(1) - 1];
+        let ticks = segment.ticks_start as f64
+            + (seconds - segment.seconds_start) / segment.seconds_per_tick;
+        ticks.min(self.end_ticks as f64)
+    }
+
+    /// 経過秒数からTimeAxisを生成する
+    pub fn time_axis_from_seconds(&self, seconds: f32) -> TimeAxis {
+        self.time_axis_from_ticks(self.ticks_from_seconds(seconds))
+    }
+
+    /// 小節の先頭のtickを求める
+    pub fn ticks_from_measure(&self, measure: u32) -> u32 {
+        let index = self.segments.partition_point(|x| {
+            x.measure_start < measure
+                || (x.measure_start == measure && x.ticks_reset_by_measure_start == 0)
+        });
+        let segment = &self.segments[index.max(1) - 1];
+        segment.ticks_start
+            + (measure.saturating_sub(segment.measure_start))
+                * segment.measure_length_ticks(self.ppm)
+            - segment.ticks_reset_by_measure_start
+    }
+
+    /// 小節と拍(1始まり)の先頭のtickを求める。拍が小節の拍数を超える場合は小節の最後の拍、曲末を超える場合は曲末に丸める
+    pub fn ticks_from_measure_and_beat(&self, measure: u32, beat: u32) -> u32 {
+        let measure_start_ticks = self.ticks_from_measure(measure).min(self.end_ticks);
+        let segment = self.segment_at_ticks(measure_start_ticks);
+        let beat = beat.clamp(1, segment.time_signature_numerator.max(1) as u32);
+        (measure_start_ticks + (beat - 1) * segment.beat_length_ticks(self.ppm)).min(self.end_ticks)
+    }
+
+    /// テンポ・拍子・調が変化する各点のTimeAxis。先頭は必ずtick 0
+    pub fn change_time_axes(&self) -> Vec<TimeAxis> {
+        self.segments
+            .iter()
+            .map(|x| self.time_axis_from_ticks(x.ticks_start))
+            .collect()
+    }
+
+    pub fn first_time_axis(&self) -> TimeAxis {
+        self.time_axis_from_ticks(0)
+    }
+
+    pub fn last_time_axis(&self) -> TimeAxis {
+        self.time_axis_from_ticks(self.end_ticks)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const PPM: u16 = 480;
+
+    fn tempo(total_ticks: u32, tempo: f32) -> TempoChangeEvent {
+        TempoChangeEvent {
+            tempo,
+            total_ticks,
+            seconds_per_tick: 60.0 / tempo as f64 / PPM as f64,
+        }
+    }
+
+    fn time_signature(
+        total_ticks: u32,
+        numerator: u8,
+        denominator: u8,
+    ) -> TimeSignatureChangeEvent {
+        TimeSignatureChangeEvent {
+            numerator,
+            denominator,
+            midi_clocks_per_metronome_click: 24,
+            thirty_seconds_notes_per_quarter_note: 8,
+            total_ticks,
+        }
+    }
+
+    fn tempo_map(
+        end_ticks: u32,
+        tempo_change_events: Vec<TempoChangeEvent>,
+        time_signature_change_events: Vec<TimeSignatureChangeEvent>,
+    ) -> TempoMap {
+        TempoMap::new(
+            PPM,
+            end_ticks,
+            tempo_change_events,
+            time_signature_change_events,
+            vec![],
+        )
+    }
+
+    fn assert_seconds(actual: f32, expected: f32) {
+        assert!(
+            (actual - expected).abs() < 1e-4,
+            "expected {expected} seconds, got {actual}"
+        );
+    }
+
+    #[test]
+    fn default_tempo_and_time_signature() {
+        let tempo_map = tempo_map(PPM as u32 * 16, vec![], vec![]);
+
+        let time_axis = tempo_map.time_axis_from_ticks(1920 + 480 + 10);
+        assert_eq!(time_axis.measure, 1);
+        assert_eq!(time_axis.beat, 2);
+        assert_eq!(time_axis.ticks_reset_by_measure, 490);
+        assert_eq!(time_axis.ticks_reset_by_beat, 10);
+        assert_eq!(time_axis.measure_length_ticks, 1920);
+        assert_eq!(time_axis.beat_length_ticks, 480);
+        assert_eq!(time_axis.tempo, 120.0);
+
+        assert_seconds(tempo_map.time_axis_from_ticks(480).seconds_total, 0.5);
+        assert_eq!(tempo_map.ticks_from_seconds(0.5), 480);
+        assert_eq!(tempo_map.ticks_from_measure(0), 0);
+        assert_eq!(tempo_map.ticks_from_measure(3), 1920 * 3);
+    }
+
+    #[test]
+    fn tempo_change() {
+        let tempo_map = tempo_map(1920 * 4, vec![tempo(1920, 60.0)], vec![]);
+
+        // 変化点の直前までは前のテンポ、変化点からは新しいテンポ
+        assert_eq!(tempo_map.time_axis_from_ticks(1919).tempo, 120.0);
+        assert_eq!(tempo_map.time_axis_from_ticks(1920).tempo, 60.0);
+        assert_seconds(tempo_map.time_axis_from_ticks(1920).seconds_total, 2.0);
+        assert_seconds(tempo_map.time_axis_from_ticks(2400).seconds_total, 3.0);
+
+        assert_eq!(tempo_map.ticks_from_seconds(1.999), 1919);
+        assert_eq!(tempo_map.ticks_from_seconds(2.0), 1920);
+        assert_eq!(tempo_map.ticks_from_seconds(2.5), 2160);
+
+        // テンポが変わっても小節の位置は変わらない
+        assert_eq!(tempo_map.ticks_from_measure(2), 3840);
+    }
+
+    #[test]
+    fn time_signature_change_at_measure_start() {
+        let tempo_map = tempo_map(1920 * 4, vec![], vec![time_signature(1920, 3, 4)]);
+
+        assert_eq!(tempo_map.time_axis_from_ticks(1919).measure, 0);
+        let time_axis = tempo_map.time_axis_from_ticks(1920);
+        assert_eq!(time_axis.measure, 1);
+        assert_eq!(time_axis.ticks_reset_by_measure, 0);
+        assert_eq!(time_axis.measure_length_ticks, 1440);
+        assert_eq!(time_axis.time_signature_numerator, 3);
+
+        assert_eq!(tempo_map.ticks_from_measure(1), 1920);
+        assert_eq!(tempo_map.ticks_from_measure(2), 1920 + 1440);
+        assert_eq!(tempo_map.time_axis_from_ticks(1920 + 1439).measure, 1);
+        assert_eq!(tempo_map.time_axis_from_ticks(1920 + 1440).measure, 2);
+    }
+
+    #[test]
+    fn time_signature_change_mid_measure_shorter_than_new_measure() {
+        // 4/4の2拍目の終わりで3/4に変わる。経過した960tickは3/4の1小節(1440tick)に満たないので小節は続く
+        let tempo_map = tempo_map(1920 * 4, vec![], vec![time_signature(960, 3, 4)]);
+
+        let time_axis = tempo_map.time_axis_from_ticks(960);
+        assert_eq!(time_axis.measure, 0);
+        assert_eq!(time_axis.ticks_reset_by_measure, 960);
+        assert_eq!(time_axis.beat, 3);
+
+        assert_eq!(tempo_map.time_axis_from_ticks(1439).measure, 0);
+        assert_eq!(tempo_map.time_axis_from_ticks(1440).measure, 1);
+        assert_eq!(tempo_map.ticks_from_measure(0), 0);
+        assert_eq!(tempo_map.ticks_from_measure(1), 1440);
+        assert_eq!(tempo_map.ticks_from_measure(2), 1440 + 1440);
+    }
+
+    #[test]
+    fn time_signature_change_mid_measure_longer_than_new_measure() {
+        // 4/4の3拍目の終わりで2/4に変わる。経過した1440tickは2/4の1小節(960tick)以上なので新しい小節になる
+        let tempo_map = tempo_map(1920 * 4, vec![], vec![time_signature(1440, 2, 4)]);
+
+        assert_eq!(tempo_map.time_axis_from_ticks(1439).measure, 0);
+        let time_axis = tempo_map.time_axis_from_ticks(1440);
+        assert_eq!(time_axis.measure, 1);
+        assert_eq!(time_axis.ticks_reset_by_measure, 0);
+        assert_eq!(time_axis.beat, 1);
+
+        assert_eq!(tempo_map.ticks_from_measure(1), 1440);
+        assert_eq!(tempo_map.ticks_from_measure(2), 1440 + 960);
+    }
+
+    #[test]
+    fn time_signature_change_mid_measure_equal_to_new_measure() {
+        // 経過したtickが新しい小節の長さとちょうど同じ場合も新しい小節になる
+        let tempo_map = tempo_map(1920 * 4, vec![], vec![time_signature(960, 2, 4)]);
+
+        assert_eq!(tempo_map.time_axis_from_ticks(959).measure, 0);
+        assert_eq!(tempo_map.time_axis_from_ticks(960).measure, 1);
+        assert_eq!(tempo_map.ticks_from_measure(1), 960);
+        assert_eq!(tempo_map.ticks_from_measure(2), 1920);
+    }
+
+    #[test]
+    fn compound_time_signature() {
+        let tempo_map = tempo_map(1440 * 4, vec![], vec![time_signature(0, 6, 8)]);
+
+        let time_axis = tempo_map.time_axis_from_ticks(1440 + 240 * 5 + 1);
+        assert_eq!(time_axis.measure, 1);
+        assert_eq!(time_axis.beat, 6);
+        assert_eq!(time_axis.ticks_reset_by_beat, 1);
+        assert_eq!(time_axis.measure_length_ticks, 1440);
+        assert_eq!(time_axis.beat_length_ticks, 240);
+    }
+
+    #[test]
+    fn seconds_out_of_range() {
+        let tempo_map = tempo_map(1920, vec![], vec![]);
+
+        // 範囲外は曲頭・曲末に丸める
+        assert_eq!(tempo_map.ticks_from_seconds(-1.0), 0);
+        assert_eq!(tempo_map.ticks_from_seconds(100.0), 1920);
+        assert_eq!(tempo_map.first_time_axis().ticks_total, 0);
+        assert_eq!(tempo_map.last_time_axis().ticks_total, 1920);
+    }
+
+    #[test]
+    fn change_time_axes_merge_events_at_same_ticks() {
+        let tempo_map = tempo_map(
+            1920 * 4,
+            vec![tempo(0, 90.0), tempo(1920, 100.0), tempo(1920, 140.0)],
+            vec![time_signature(1920, 3, 4)],
+        );
+
+        let change_time_axes = tempo_map.change_time_axes();
+        assert_eq!(change_time_axes.len(), 2);
+        assert_eq!(change_time_axes[0].ticks_total, 0);
+        assert_eq!(change_time_axes[0].tempo, 90.0);
+        assert_eq!(change_time_axes[1].ticks_total, 1920);
+        // 同じtickのイベントは後に現れたものを優先する
+        assert_eq!(change_time_axes[1].tempo, 140.0);
+        assert_eq!(change_time_axes[1].time_signature_numerator, 3);
+    }
+
+    #[test]
+    fn ticks_from_measure_and_beat() {
+        let tempo_map = tempo_map(1920 * 2 + 1440, vec![], vec![time_signature(1920, 3, 4)]);
+
+        assert_eq!(tempo_map.ticks_from_measure_and_beat(0, 1), 0);
+        assert_eq!(tempo_map.ticks_from_measure_and_beat(0, 4), 1440);
+        assert_eq!(tempo_map.ticks_from_measure_and_beat(1, 3), 1920 + 960);
+
+        // 拍は1から小節の拍数までに丸める
+        assert_eq!(tempo_map.ticks_from_measure_and_beat(1, 0), 1920);
+        assert_eq!(tempo_map.ticks_from_measure_and_beat(1, 4), 1920 + 960);
+        assert_eq!(
+            tempo_map.ticks_from_measure_and_beat(2, 3),
+            1920 + 1440 + 960
+        );
+
+        // 曲末を超える場合は曲末に丸める
+        assert_eq!(tempo_map.ticks_from_measure_and_beat(3, 2), 1920 * 2 + 1440);
+        assert_eq!(
+            tempo_map.ticks_from_measure_and_beat(10, 2),
+            1920 * 2 + 1440
+        );
+    }
+
+    #[test]
+    fn exact_ticks_from_seconds() {
+        let tempo_map = tempo_map(1920, vec![tempo(960, 60.0)], vec![]);
+
+        let ticks = tempo_map.exact_ticks_from_seconds(0.5005);
+        assert!((ticks - 480.48).abs() < 1e-3, "got {ticks}");
+        let ticks = tempo_map.exact_ticks_from_seconds(1.25);
+        assert!((ticks - 1080.0).abs() < 1e-3, "got {ticks}");
+
+        // 範囲外は曲頭・曲末に丸める
+        assert_eq!(tempo_map.exact_ticks_from_seconds(-1.0), 0.0);
+        assert_eq!(tempo_map.exact_ticks_from_seconds(100.0), 1920.0);
+        assert_eq!(tempo_map.ticks_from_seconds(100.0), 1920);
+    }
+}
@@ -4,6 +4,9 @@ use std::error::Error;
 pub fn hex_to_srgb(hex: &str) -> Result<Vec<f32>, Box<dyn Error>> {
     // HEXコードの先頭に '#' があれば取り除く
     let hex = hex.trim_start_matches('#');
+    if let Some(c) = hex.chars().find(|c| !c.is_ascii_hexdigit()) {
+        return Err(format!("invalid hex digit {:?}", c).into());
+    }
 
     // HEXコードの長さに応じて処理
     match hex.len() {
@@ -22,10 +25,19 @@ pub fn hex_to_srgb(hex: &str) -> Result<Vec<f32>, Box<dyn Error>> {
             let a = u8::from_str_radix(&hex[6..8], 16)? as f32 / 255.0;
             Ok(vec![r, g, b, a])
         }
-        _ => Err("Invalid HEX color length".into()),
+        len => Err(format!("invalid length {}", len).into()),
     }
 }
 
+/// 2つのSRGB値を混ぜる関数。ratio = 0.0でbase、1.0でtargetになる
+pub fn mix_color(base_color: &[f32], target_color: &[f32], ratio: f32) -> Vec<f32> {
+    base_color
+        .iter()
+        .zip(target_color.iter())
+        .map(|(base, target)| base + (target - base) * ratio)
+        .collect()
+}
+
 pub fn adjust_color(
     base_color: &str,
     target_color: &str,